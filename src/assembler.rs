@@ -1,558 +1,959 @@
 use std::collections::HashMap;
-
-union split16 {
-    u: u16,
-    b: (u8, u8),
+use std::path::PathBuf;
+
+use crate::isa;
+
+mod diagnostic;
+pub mod disassembler;
+mod lexer;
+mod parser;
+
+pub use crate::isa::Instruction;
+pub use diagnostic::{AssemblyError, Diagnostic, ErrorCode, SourceMap, Span};
+pub use lexer::Lexeme;
+pub use parser::register;
+
+enum Token {
+    Instruction(Instruction, Span),
+    Label(String, Span),
+    Jump(JumpCond, JumpKind, Expr, Span), // jump to an address that depends on labels
+    Pending(Box<parser::Statement>),      // needs label addresses before it can be lowered
+    Data(u32, Vec<(Expr, Span)>),         // .byte/.word/.dword values, each this many bytes wide
+    Bytes(Vec<u8>, Span),
+    Fill(Vec<u8>, u32, Span), // .fill, the value's bytes and how many times
+    Org(u32, Span),
+    Align(u32, Span),
 }
-union split32 {
-    u: u32,
-    b: (u8, u8, u8, u8),
+impl Token {
+    /// Where the token came from, for diagnostics about all of it.
+    fn span(&self) -> Option<Span> {
+        match self {
+            Token::Instruction(_, span)
+            | Token::Label(_, span)
+            | Token::Jump(_, _, _, span)
+            | Token::Bytes(_, span)
+            | Token::Fill(_, _, span)
+            | Token::Org(_, span)
+            | Token::Align(_, span) => Some(*span),
+            Token::Pending(s) => Some(s.span()),
+            Token::Data(_, values) => values.first().map(|(_, span)| *span),
+        }
+    }
+    /// Bytes of padding an `.org` or `.align` at `addr` adds.
+    fn padding(&self, addr: u32) -> u32 {
+        match self {
+            Token::Org(target, _) => target.saturating_sub(addr),
+            Token::Align(n, _) => (n - addr % n) % n,
+            _ => 0,
+        }
+    }
 }
 
-//#[derive(Error)]
-pub enum AssemblyError {
-    File(std::io::Error),
-    UndefinedVariable(String, usize),
-}
+/// The largest image the assembler builds, 16 MiB. Addresses are 32 bits, but
+/// the image is allocated in full, so a `.fill` or `.org` far beyond any real
+/// program is an error rather than gigabytes of zeros.
+const MAX_IMAGE: u32 = 0x100_0000;
+
+/// Values of every symbol an expression can refer to, by name.
+type Symbols = HashMap<String, i64>;
 
-enum Token {}
+/// Every label of an assembled program, and its address.
+pub type Labels = HashMap<String, u32>;
+
+/// An operand expression, folded to a constant at assemble time. Values are
+/// signed 64 bit while folding and only range checked against the field
+/// they end up in.
 enum Expr {
-    Const(VarType),
+    Const(i64),
+    Label(String, Span),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
     Add(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
     Mul(Box<Expr>, Box<Expr>),
     Div(Box<Expr>, Box<Expr>),
-    TypeCast(VarType),
+    Rem(Box<Expr>, Box<Expr>),
+    Shl(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Xor(Box<Expr>, Box<Expr>),
+    // comparisons and logic give 1 for true and 0 for false
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+    Lt(Box<Expr>, Box<Expr>),
+    Le(Box<Expr>, Box<Expr>),
+    Gt(Box<Expr>, Box<Expr>),
+    Ge(Box<Expr>, Box<Expr>),
+    LogicAnd(Box<Expr>, Box<Expr>),
+    LogicOr(Box<Expr>, Box<Expr>),
+    LogicNot(Box<Expr>),
 }
 impl Expr {
-    fn get_type(&self) -> Result<VarType, AssemblyError> {
-        todo!();
-        // match self {
-        // 	Expr::Const(t) => Ok(t.clone()),
-        // 	Expr::Add(a, b) => {
-        // 		let a = a.get_type()?;
-        // 		let b = b.get_type()?;
-        // 		if a == b {return a}
-        // 		return Err(AssemblyError::File);
-        // 	},
-        // 	_ => panic!("expr get_type not finished!"),
-        // }
+    /// Folds the expression down to a single value. `span` covers the whole
+    /// expression and is where arithmetic errors are reported.
+    fn eval(&self, symbols: &Symbols, span: Span) -> Result<i64, Diagnostic> {
+        let overflow =
+            || Diagnostic::error(ErrorCode::InvalidExpression, "expression overflows", span);
+        let bin = |a: &Expr, b: &Expr| -> Result<(i64, i64), Diagnostic> {
+            Ok((a.eval(symbols, span)?, b.eval(symbols, span)?))
+        };
+        match self {
+            Expr::Const(n) => Ok(*n),
+            Expr::Label(name, ls) => symbols.get(name).copied().ok_or_else(|| {
+                Diagnostic::error(
+                    ErrorCode::UndefinedLabel,
+                    format!("undefined label `{}`", name),
+                    *ls,
+                )
+            }),
+            Expr::Neg(a) => a.eval(symbols, span)?.checked_neg().ok_or_else(overflow),
+            Expr::Not(a) => Ok(!a.eval(symbols, span)?),
+            Expr::Add(a, b) => {
+                let (a, b) = bin(a, b)?;
+                a.checked_add(b).ok_or_else(overflow)
+            }
+            Expr::Sub(a, b) => {
+                let (a, b) = bin(a, b)?;
+                a.checked_sub(b).ok_or_else(overflow)
+            }
+            Expr::Mul(a, b) => {
+                let (a, b) = bin(a, b)?;
+                a.checked_mul(b).ok_or_else(overflow)
+            }
+            Expr::Div(a, b) | Expr::Rem(a, b) => {
+                let (a, b) = bin(a, b)?;
+                if b == 0 {
+                    return Err(Diagnostic::error(
+                        ErrorCode::InvalidExpression,
+                        "division by zero",
+                        span,
+                    ));
+                }
+                let n = match self {
+                    Expr::Div(_, _) => a.checked_div(b),
+                    _ => a.checked_rem(b),
+                };
+                n.ok_or_else(overflow)
+            }
+            Expr::Shl(a, b) | Expr::Shr(a, b) => {
+                let (a, b) = bin(a, b)?;
+                if !(0..64).contains(&b) {
+                    return Err(Diagnostic::error(
+                        ErrorCode::InvalidExpression,
+                        format!("can't shift by {}", b),
+                        span,
+                    ));
+                }
+                match self {
+                    Expr::Shl(_, _) => Ok(a << b),
+                    _ => Ok(a >> b),
+                }
+            }
+            Expr::And(a, b) => bin(a, b).map(|(a, b)| a & b),
+            Expr::Or(a, b) => bin(a, b).map(|(a, b)| a | b),
+            Expr::Xor(a, b) => bin(a, b).map(|(a, b)| a ^ b),
+            Expr::Eq(a, b) => bin(a, b).map(|(a, b)| (a == b) as i64),
+            Expr::Ne(a, b) => bin(a, b).map(|(a, b)| (a != b) as i64),
+            Expr::Lt(a, b) => bin(a, b).map(|(a, b)| (a < b) as i64),
+            Expr::Le(a, b) => bin(a, b).map(|(a, b)| (a <= b) as i64),
+            Expr::Gt(a, b) => bin(a, b).map(|(a, b)| (a > b) as i64),
+            Expr::Ge(a, b) => bin(a, b).map(|(a, b)| (a >= b) as i64),
+            Expr::LogicAnd(a, b) => bin(a, b).map(|(a, b)| (a != 0 && b != 0) as i64),
+            Expr::LogicOr(a, b) => bin(a, b).map(|(a, b)| (a != 0 || b != 0) as i64),
+            Expr::LogicNot(a) => Ok((a.eval(symbols, span)? == 0) as i64),
+        }
+    }
+    /// Evaluates the expression and checks it fits a field whose largest
+    /// value is `max`.
+    fn value(&self, symbols: &Symbols, span: Span, max: u64) -> Result<u64, Diagnostic> {
+        fit(self.eval(symbols, span)?, max, span)
+    }
+    /// Every label the expression refers to.
+    fn labels<'a>(&'a self, out: &mut Vec<(&'a String, Span)>) {
+        match self {
+            Expr::Const(_) => {}
+            Expr::Label(name, span) => out.push((name, *span)),
+            Expr::Neg(a) | Expr::Not(a) | Expr::LogicNot(a) => a.labels(out),
+            Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Rem(a, b)
+            | Expr::Shl(a, b)
+            | Expr::Shr(a, b)
+            | Expr::And(a, b)
+            | Expr::Or(a, b)
+            | Expr::Xor(a, b)
+            | Expr::Eq(a, b)
+            | Expr::Ne(a, b)
+            | Expr::Lt(a, b)
+            | Expr::Le(a, b)
+            | Expr::Gt(a, b)
+            | Expr::Ge(a, b)
+            | Expr::LogicAnd(a, b)
+            | Expr::LogicOr(a, b) => {
+                a.labels(out);
+                b.labels(out);
+            }
+        }
+    }
+    fn is_const(&self) -> bool {
+        let mut labels = vec![];
+        self.labels(&mut labels);
+        labels.is_empty()
     }
 }
-enum VarType {
-    U8,
-    U16,
-    U32,
-    U64,
-    I8,
-    I16,
-    I32,
-    I64,
-    SlimPtr,           // [ptr:4]
-    Ptr(Box<VarType>), // [ptr:4][type:4]
-    Struct(Vec<VarType>),
-    Array(Box<VarType>, usize),
-    Tuple(Vec<VarType>),
-    Func(Vec<VarType>, Box<VarType>),
+
+/// An expression over registers, flags and labels, like `r0 == 0 && zf`,
+/// for breakpoint conditions. It's an ordinary expression except that
+/// registers and zero page slots, `zp[n]`, can be used and stand for their
+/// values.
+pub struct Condition {
+    expr: Expr,
+    span: Span,
 }
-impl VarType {
-    fn size(&self) -> u32 {
-        match self {
-            VarType::U8 => 1,
-            VarType::U16 => 2,
-            VarType::U32 => 4,
-            VarType::U64 => 8,
-            VarType::I8 => 1,
-            VarType::I16 => 2,
-            VarType::I32 => 4,
-            VarType::I64 => 8,
-            VarType::SlimPtr => 4,
-            VarType::Ptr(_) => 8,
-            VarType::Struct(n) => n.iter().map(|t| t.size()).sum::<u32>(),
-            VarType::Array(t, s) => t.size() * (*s as u32),
-            VarType::Tuple(n) => n.iter().map(|t| t.size()).sum::<u32>(),
-            VarType::Func(_, _) => 4,
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, AssemblyError> {
+        let mut sources = SourceMap::new();
+        let file = sources.add("<condition>", text);
+        let mut diags = vec![];
+        let lexemes = lexer::lex(text, file, &mut diags);
+        if diags.is_empty() {
+            match parser::Parser::new(lexemes, &mut sources, &[], &[], file).register_expr() {
+                Ok((expr, span)) => return Ok(Condition { expr, span }),
+                Err(d) => diags.push(d),
+            }
         }
+        Err(AssemblyError {
+            diagnostics: diags,
+            sources,
+        })
+    }
+    /// Every name the condition uses, once each.
+    pub fn names(&self) -> Vec<&String> {
+        let mut labels = vec![];
+        self.expr.labels(&mut labels);
+        let mut names: Vec<&String> = labels.into_iter().map(|(name, _)| name).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+    /// Works the condition out, given the value of every name it uses.
+    /// Registers go by their plain names, `r0` or `sp`, and zero page slots
+    /// as `zp[n]`.
+    pub fn eval(&self, values: &HashMap<String, i64>) -> Result<i64, String> {
+        self.expr.eval(values, self.span).map_err(|d| d.message)
     }
 }
+
+/// Splits one line of text into assembly tokens, for tools that read bits of
+/// assembly syntax outside a program. `None` if any of it doesn't lex.
+pub fn tokens(line: &str) -> Option<Vec<Lexeme>> {
+    let mut diags = vec![];
+    let mut out: Vec<Lexeme> = lexer::lex(line, 0, &mut diags)
+        .into_iter()
+        .map(|l| l.lexeme)
+        .collect();
+    out.retain(|l| *l != Lexeme::Newline);
+    if diags.is_empty() {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// Range checks `n` against a field whose largest value is `max`. Negative
+/// values are accepted as long as they fit as two's complement, and come
+/// back already wrapped to the field width.
+fn fit(n: i64, max: u64, span: Span) -> Result<u64, Diagnostic> {
+    let min = -(((max >> 1) + 1) as i64);
+    if n > max as i64 || n < min {
+        let bits = 64 - max.leading_zeros();
+        let shown = if n < 0 {
+            format!("-0x{:x}", n.unsigned_abs())
+        } else {
+            format!("0x{:x}", n)
+        };
+        return Err(Diagnostic::error(
+            ErrorCode::ValueOutOfRange,
+            format!("value {} does not fit in {} bits", shown, bits),
+            span,
+        )
+        .note(format!(
+            "this operand holds 0x0 to 0x{:x}, or -0x{:x} to -0x1 as two's complement",
+            max,
+            min.unsigned_abs()
+        )));
+    }
+    Ok(n as u64 & max)
+}
+
+/// Where a variable in a `Scope` lives.
 enum VarStore {
-    Reg,
-    ZeroPage,
-    Stack,
+    Reg(u8),
 }
 struct Scope {
     name: String,
     code: Vec<Token>,
-    vars: HashMap<String, VarType, VarStore>,
+    vars: HashMap<String, VarStore>,
 }
-pub enum Instruction {
-    Nop,
-
-    JmpConst24(u32),
-    JmpConst32(u32),
-    JmpOffsetA8(u8),
-    JmpOffsetS8(u8),
-    JmpOffsetA16(u16),
-    JmpOffsetS16(u16),
-    JmpOffsetA32(u32),
-    JmpOffsetS32(u32),
-
-    JCConst24(u32),
-    JCConst32(u32),
-    JCOffsetA8(u8),
-    JCOffsetS8(u8),
-    JCOffsetA16(u16),
-    JCOffsetS16(u16),
-    JCOffsetA32(u32),
-    JCOffsetS32(u32),
-
-    JNCConst24(u32),
-    JNCConst32(u32),
-    JNCOffsetA8(u8),
-    JNCOffsetS8(u8),
-    JNCOffsetA16(u16),
-    JNCOffsetS16(u16),
-    JNCOffsetA32(u32),
-    JNCOffsetS32(u32),
-
-    JZConst24(u32),
-    JZConst32(u32),
-    JZOffsetA8(u8),
-    JZOffsetS8(u8),
-    JZOffsetA16(u16),
-    JZOffsetS16(u16),
-    JZOffsetA32(u32),
-    JZOffsetS32(u32),
-
-    JNZConst24(u32),
-    JNZConst32(u32),
-    JNZOffsetA8(u8),
-    JNZOffsetS8(u8),
-    JNZOffsetA16(u16),
-    JNZOffsetS16(u16),
-    JNZOffsetA32(u32),
-    JNZOffsetS32(u32),
-
-    AddRRR(u8, u8, u8),
-    AddZRR(u8, u8, u8),
-    IncR1(u8),
-    AddRRZ(u8, u8, u8),
-    AddRZZ(u8, u8, u8),
-    AddZZZ(u8, u8, u8),
-
-    AddcRRR(u8, u8, u8),
-    AddcZRR(u8, u8, u8),
-    IncR4(u8),
-    AddcRRZ(u8, u8, u8),
-    AddcRZZ(u8, u8, u8),
-    AddcZZZ(u8, u8, u8),
-
-    SubRRR(u8, u8, u8),
-    SubZRR(u8, u8, u8),
-    SubRZR(u8, u8, u8),
-    SubRRZ(u8, u8, u8),
-    SubRZZ(u8, u8, u8),
-    SubZZZ(u8, u8, u8),
-
-    SubbRRR(u8, u8, u8),
-    SubbZRR(u8, u8, u8),
-    SubbRZR(u8, u8, u8),
-    SubbRRZ(u8, u8, u8),
-    SubbRZZ(u8, u8, u8),
-    SubbZZZ(u8, u8, u8),
-
-    MulRRR(u8, u8, u8),
-    MulZRR(u8, u8, u8),
-    MulRRZ(u8, u8, u8),
-    MulRZZ(u8, u8, u8),
-    MulZZZ(u8, u8, u8),
-
-    DivRRR(u8, u8, u8),
-    DivZRR(u8, u8, u8),
-    DivRZR(u8, u8, u8),
-    DivRRZ(u8, u8, u8),
-    DivRZZ(u8, u8, u8),
-    DivZZZ(u8, u8, u8),
-
-    AndRRR(u8, u8, u8),
-    AndZRR(u8, u8, u8),
-    AndRRZ(u8, u8, u8),
-    AndRZZ(u8, u8, u8),
-    AndZZZ(u8, u8, u8),
-
-    OrRRR(u8, u8, u8),
-    OrZRR(u8, u8, u8),
-    OrRRZ(u8, u8, u8),
-    OrRZZ(u8, u8, u8),
-    OrZZZ(u8, u8, u8),
-
-    XorRRR(u8, u8, u8),
-    XorZRR(u8, u8, u8),
-    XorRRZ(u8, u8, u8),
-    XorRZZ(u8, u8, u8),
-    XorZZZ(u8, u8, u8),
-
-    NotRR(u8, u8),
-    NotZR(u8, u8),
-    NotRZ(u8, u8),
-    NotZZ(u8, u8),
-
-    DecR1(u8),
-    DecR4(u8),
-
-    RemRRR(u8, u8, u8),
-    RemZRR(u8, u8, u8),
-    RemRZR(u8, u8, u8),
-    RemRRZ(u8, u8, u8),
-    RemRZZ(u8, u8, u8),
-    RemZZZ(u8, u8, u8),
-
-    ShlRRR(u8, u8, u8),
-    ShlZRR(u8, u8, u8),
-    ShlRCR(u8, u8, u8),
-    ShlZCR(u8, u8, u8),
-    ShlRCZ(u8, u8, u8),
-    ShlZCZ(u8, u8, u8),
-
-    ShrRRR(u8, u8, u8),
-    ShrZRR(u8, u8, u8),
-    ShrRCR(u8, u8, u8),
-    ShrZCR(u8, u8, u8),
-    ShrRCZ(u8, u8, u8),
-    ShrZCZ(u8, u8, u8),
-
-    PrintChar_R(u8),
-    PrintChar_C8(u8),
-
-    Read8_Cptr_R(u32, u8),
-    Read16_Cptr_R(u32, u8),
-    Read32_Cptr_R(u32, u8),
-    Read8_Rptr_R(u8, u8),
-    Read16_Rptr_R(u8, u8),
-    Read32_Rptr_R(u8, u8),
-    Write8_R_Cptr(u8, u32),
-    Write16_R_Cptr(u8, u32),
-    Write32_R_Cptr(u8, u32),
-    Write8_R_Rptr(u8, u8),
-    Write16_R_Rptr(u8, u8),
-    Write32_R_Rptr(u8, u8),
-    Write8_C8_Cptr(u8, u32),
-    Write8_C8_Rptr(u16, u8),
-    Write16_C16_Cptr(u16, u32),
-    Write16_C16_Rptr(u16, u8),
-    Write32_C32_Cptr(u32, u32),
-    Write32_C32_Rptr(u32, u8),
-
-    MovRR(u8, u8),
-    MovR4R4(u8, u8, u8),
-    Mov4R4R(u8, u8, u8),
-    Mov44(u8),
-    ConstRegU8(u8, u8),
-    ConstRegU16(u8, u16),
-    ConstRegU32(u8, u32),
-
-    SetZF,
-    ClrZF,
-    SetCF,
-    ClrCF,
-    IntEnable,
-    IntDisable,
-
-    ExplicitHaltAndExit,
-    DebugPrintReg(u8),
+impl Scope {
+    fn new(name: &str) -> Self {
+        Scope {
+            name: name.to_string(),
+            code: Vec::new(),
+            vars: HashMap::new(),
+        }
+    }
+    /// Scope for the body of a function, with each parameter bound to the
+    /// register the calling convention passes it in, and the return value,
+    /// if it's named, to the return register. `None` if there are more
+    /// parameters than argument registers.
+    fn func(name: &str, params: &[String], ret: Option<&str>) -> Option<Self> {
+        if params.len() > ARG_REGS.len() {
+            return None;
+        }
+        let mut scope = Scope::new(name);
+        // A parameter wins over a return value of the same name.
+        if let Some(ret) = ret {
+            scope.vars.insert(ret.to_string(), VarStore::Reg(RET_REG));
+        }
+        for (param, r) in params.iter().zip(ARG_REGS) {
+            scope.vars.insert(param.clone(), VarStore::Reg(r));
+        }
+        Some(scope)
+    }
+    /// The register the variable `name` lives in, if there's one by that
+    /// name.
+    fn register(&self, name: &str) -> Option<u8> {
+        self.vars.get(name).map(|VarStore::Reg(r)| *r)
+    }
 }
 
-pub trait Assembler {
-    fn assemble_file(path: &str) -> Result<Vec<u8>, AssemblyError>;
+// The calling convention, see calling_convention.md. `.func` and
+// `CALL name(...)` follow it, and so does anything that calls them.
+const ARG_REGS: [u8; 4] = [0x0, 0x1, 0x2, 0x3]; // arguments, first to last
+const RET_REG: u8 = 0x0; // return value
+const CALLEE_SAVED: [u8; 4] = [0x4, 0x5, 0x6, 0x7]; // kept across calls
+const SHUFFLE_REG: u8 = 0xb; // tr, clobbered putting arguments in place
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JumpCond {
+    Always, // JMP
+    C,      // JC
+    NC,     // JNC
+    Z,      // JZ
+    NZ,     // JNZ
+    Call,   // CALL, pushes the return address first
 }
-
-pub struct Ver0;
-impl Ver0 {
-    fn tokenize(code: &str) -> Result<Scope, AssemblyError> {
-        todo!();
-    }
-    fn flatten_tokens(code: Scope) -> Result<Vec<Instruction>, AssemblyError> {
-        todo!();
+impl JumpCond {
+    /// Parses `JZ` or `JZ_OFFSET` style mnemonics, returning whether the
+    /// `_OFFSET` (relative only) spelling was used.
+    fn from_mnemonic(m: &str) -> Option<(JumpCond, bool)> {
+        let (m, offset) = match m.strip_suffix("_OFFSET") {
+            Some(m) => (m, true),
+            None => (m, false),
+        };
+        let cond = match m {
+            "JMP" => JumpCond::Always,
+            "JC" => JumpCond::C,
+            "JNC" => JumpCond::NC,
+            "JZ" => JumpCond::Z,
+            "JNZ" => JumpCond::NZ,
+            "CALL" => JumpCond::Call,
+            _ => return None,
+        };
+        Some((cond, offset))
     }
-    pub fn assemble_to_bytes(code: Vec<Instruction>) -> Vec<u8> {
-        let mut bytes = vec![];
+}
 
-        fn b0_16(n: u16) -> u8 {
-            unsafe { split16 { u: n }.b.0 }
-        }
-        fn b1_16(n: u16) -> u8 {
-            unsafe { split16 { u: n }.b.1 }
-        }
-        fn b0_24(n: u32) -> u8 {
-            unsafe { split32 { u: n }.b.0 }
-        }
-        fn b1_24(n: u32) -> u8 {
-            unsafe { split32 { u: n }.b.1 }
-        }
-        fn b2_24(n: u32) -> u8 {
-            unsafe { split32 { u: n }.b.2 }
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum JumpKind {
+    Any,      // JZ label: whichever encoding is shortest
+    Relative, // JZ_OFFSET label: shortest offset encoding
+}
+
+/// Every jump family, `CALL` included, has the same eight encodings, in variant byte order.
+/// Offsets are measured from the first operand byte (instruction start + 2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JumpForm {
+    Const24,
+    Const32,
+    OffsetA8,
+    OffsetS8,
+    OffsetA16,
+    OffsetS16,
+    OffsetA32,
+    OffsetS32,
+}
+impl JumpForm {
+    /// Encoded length in bytes, including the opcode and variant bytes.
+    fn size(&self) -> u32 {
+        match self {
+            JumpForm::Const24 => 5,
+            JumpForm::Const32 => 6,
+            JumpForm::OffsetA8 | JumpForm::OffsetS8 => 3,
+            JumpForm::OffsetA16 | JumpForm::OffsetS16 => 4,
+            JumpForm::OffsetA32 | JumpForm::OffsetS32 => 6,
         }
-        fn b0_32(n: u32) -> u8 {
-            unsafe { split32 { u: n }.b.0 }
+    }
+    /// Largest operand value the form can hold.
+    fn max(&self) -> u32 {
+        match self {
+            JumpForm::Const24 => 0xff_ffff,
+            JumpForm::OffsetA8 | JumpForm::OffsetS8 => 0xff,
+            JumpForm::OffsetA16 | JumpForm::OffsetS16 => 0xffff,
+            JumpForm::Const32 | JumpForm::OffsetA32 | JumpForm::OffsetS32 => 0xffff_ffff,
         }
-        fn b1_32(n: u32) -> u8 {
-            unsafe { split32 { u: n }.b.1 }
+    }
+    /// Smallest absolute form that can hold `dest`.
+    fn absolute(dest: u32) -> JumpForm {
+        if dest <= JumpForm::Const24.max() {
+            JumpForm::Const24
+        } else {
+            JumpForm::Const32
         }
-        fn b2_32(n: u32) -> u8 {
-            unsafe { split32 { u: n }.b.2 }
+    }
+    /// Smallest relative form that can hold `ofs` in the given direction.
+    fn relative(backward: bool, ofs: u32) -> JumpForm {
+        match (backward, ofs) {
+            (false, 0..=0xff) => JumpForm::OffsetA8,
+            (true, 0..=0xff) => JumpForm::OffsetS8,
+            (false, 0..=0xffff) => JumpForm::OffsetA16,
+            (true, 0..=0xffff) => JumpForm::OffsetS16,
+            (false, _) => JumpForm::OffsetA32,
+            (true, _) => JumpForm::OffsetS32,
         }
-        fn b3_32(n: u32) -> u8 {
-            unsafe { split32 { u: n }.b.3 }
+    }
+}
+
+/// The eight jump forms, in variant byte order.
+const FORMS: [JumpForm; 8] = [
+    JumpForm::Const24,
+    JumpForm::Const32,
+    JumpForm::OffsetA8,
+    JumpForm::OffsetS8,
+    JumpForm::OffsetA16,
+    JumpForm::OffsetS16,
+    JumpForm::OffsetA32,
+    JumpForm::OffsetS32,
+];
+
+/// Where each condition's eight encodings are: the opcode, and the variant
+/// byte of the first form in `FORMS`, with the others following in order.
+/// Jumps are built and taken apart through this and the `Instruction`
+/// encoder and decoder, so there's no second table to keep in step.
+const JUMPS: [(JumpCond, u8, u8); 6] = [
+    (JumpCond::Always, isa::JMP, 0x00),
+    (JumpCond::C, isa::JMP_C, 0x00),
+    (JumpCond::NC, isa::JMP_C, 0x08),
+    (JumpCond::Z, isa::JMP_C, 0x10),
+    (JumpCond::NZ, isa::JMP_C, 0x18),
+    (JumpCond::Call, isa::STACK, 0x06),
+];
+
+fn jump_instruction(cond: JumpCond, form: JumpForm, n: u32) -> Instruction {
+    let (_, opcode, first) = JUMPS.iter().find(|(c, _, _)| *c == cond).unwrap();
+    let ix = FORMS.iter().position(|f| *f == form).unwrap();
+    let mut code = vec![*opcode, first + ix as u8];
+    code.extend(&n.to_le_bytes()[..form.size() as usize - 2]);
+    Instruction::decode(&code)
+        .expect("every jump encoding is an instruction")
+        .0
+}
+
+/// Splits a jump into its condition, form and operand, the other way round
+/// from `jump_instruction`.
+fn jump_parts(i: &Instruction) -> Option<(JumpCond, JumpForm, u32)> {
+    if let Instruction::Data(_) = i {
+        return None;
+    }
+    let mut code = vec![];
+    i.encode(&mut code);
+    let (opcode, variant) = (code[0], *code.get(1)?);
+    let (cond, _, first) = JUMPS
+        .iter()
+        .find(|(_, o, first)| *o == opcode && (*first..*first + 8).contains(&variant))?;
+    let mut n = [0; 4];
+    n[..code.len() - 2].copy_from_slice(&code[2..]);
+    Some((
+        *cond,
+        FORMS[(variant - first) as usize],
+        u32::from_le_bytes(n),
+    ))
+}
+
+pub trait Assembler {
+    fn assemble_file(&self, path: &str) -> Result<Vec<u8>, AssemblyError>;
+}
+
+#[derive(Default)]
+pub struct Ver0 {
+    include_paths: Vec<PathBuf>,
+    defines: Vec<(String, i64)>,
+}
+impl Ver0 {
+    pub fn new() -> Self {
+        Ver0 {
+            include_paths: vec![],
+            defines: vec![],
         }
-        // fn s_16(v: u16) -> &'static [u8] {
-        // 	let s = split16{u:v}.b;
-        // 	return &[s.0,s.1]
-        // }
-        // fn s_24(v: u32) -> &'static [u8] {
-        // 	let s = split32{u:v}.b;
-        // 	return &[s.0,s.1,s.2]
-        // }
-        // fn s_32(v: u32) -> &'static [u8] {
-        // 	let s = split32{u:v}.b;
-        // 	return &[s.0,s.1,s.2,s.3]
-        // }
-
-        //TODO FIX BYTE APPENDING
-        // ! BYTE APPENDING IS COMPLETELY BROKEN RIGHT NOW
-        // ! CURRENTLY DOES NOT COMPILE !!!!!!!------------------------------------------
-
-        type I = Instruction;
-        for i in code {
-            match i {
-                I::Nop => bytes.extend([0x00]),
-                I::JmpConst24(dest) => {
-                    bytes.extend([0x02, 0x00, b0_24(dest), b1_24(dest), b2_24(dest)])
-                }
-                I::JmpConst32(dest) => {
-                    bytes.extend([0x02, 0x01, b0_24(dest), b1_24(dest), b2_24(dest)])
-                }
-                I::JmpOffsetA8(ofs) => bytes.extend([0x02, 0x02, ofs]),
-                I::JmpOffsetS8(ofs) => bytes.extend([0x02, 0x03, ofs]),
-                I::JmpOffsetA16(ofs) => bytes.extend([0x02, 0x04, b0_16(ofs), b1_16(ofs)]),
-                I::JmpOffsetS16(ofs) => bytes.extend([0x02, 0x05, b0_16(ofs), b1_16(ofs)]),
-                I::JmpOffsetA32(ofs) => {
-                    bytes.extend([0x02, 0x06, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
-                }
-                I::JmpOffsetS32(ofs) => {
-                    bytes.extend([0x02, 0x07, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
-                }
-                I::JCConst24(dest) => {
-                    bytes.extend([0x03, 0x00, b0_24(dest), b1_24(dest), b2_24(dest)])
-                }
-                I::JCConst32(dest) => bytes.extend([
-                    0x03,
-                    0x01,
-                    b0_32(dest),
-                    b1_32(dest),
-                    b2_32(dest),
-                    b3_32(dest),
-                ]),
-                I::JCOffsetA8(ofs) => bytes.extend([0x03, 0x02, ofs]),
-                I::JCOffsetS8(ofs) => bytes.extend([0x03, 0x03, ofs]),
-                I::JCOffsetA16(ofs) => bytes.extend([0x03, 0x04, b0_16(ofs), b1_16(ofs)]),
-                I::JCOffsetS16(ofs) => bytes.extend([0x03, 0x05, b0_16(ofs), b1_16(ofs)]),
-                I::JCOffsetA32(ofs) => {
-                    bytes.extend([0x03, 0x06, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
-                }
-                I::JCOffsetS32(ofs) => {
-                    bytes.extend([0x03, 0x07, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
+    }
+    /// Defines a constant before assembling starts, as if by `.equ`.
+    pub fn define(mut self, name: &str, value: i64) -> Self {
+        self.defines.push((name.to_string(), value));
+        self
+    }
+    /// Adds a directory to search for `.include` and `.incbin` files. The
+    /// including file's own directory is always searched first.
+    pub fn include_path(mut self, dir: &str) -> Self {
+        self.include_paths.push(PathBuf::from(dir));
+        self
+    }
+    /// Parses file `file` of `sources` along with everything it includes,
+    /// reporting every syntax error to `diags`. Statements with errors are
+    /// left out of the returned scope.
+    fn tokenize(&self, sources: &mut SourceMap, file: usize, diags: &mut Vec<Diagnostic>) -> Scope {
+        let lexemes = lexer::lex(sources.text(file), file, diags);
+        let mut scope = Scope::new("main");
+        scope.code =
+            parser::Parser::new(lexemes, sources, &self.include_paths, &self.defines, file)
+                .parse(diags);
+        scope
+    }
+    fn flatten_tokens(code: Scope) -> Result<(Vec<Instruction>, Labels), Vec<Diagnostic>> {
+        Ver0::check_labels(&code.code)?;
+
+        // Label jumps and instructions whose operands depend on labels start
+        // out at their smallest size and are grown until everything fits its
+        // operands. Sizes never shrink, so this always converges. Anything
+        // that can't be worked out yet keeps its size and is reported below.
+        let mut sizes: Vec<u32> = code
+            .code
+            .iter()
+            .map(|token| match token {
+                Token::Instruction(i, _) => i.size(),
+                Token::Label(_, _) => 0,
+                Token::Jump(_, _, _, _) => JumpForm::OffsetA8.size(),
+                Token::Pending(_) => 0,
+                Token::Data(width, values) => width * values.len() as u32,
+                Token::Bytes(b, _) => b.len() as u32,
+                // the parser keeps each one within MAX_IMAGE
+                Token::Fill(one, count, _) => one.len() as u32 * count,
+                // padding depends on the address, layout works it out
+                Token::Org(_, _) | Token::Align(_, _) => 0,
+            })
+            .collect();
+        let (addrs, labels) = loop {
+            let (addrs, labels) = match Ver0::layout(&code.code, &sizes) {
+                Ok(layout) => layout,
+                Err(ix) => {
+                    let message = format!(
+                        "the image would be bigger than the {} MiB limit",
+                        MAX_IMAGE >> 20
+                    );
+                    return Err(vec![match code.code[ix].span() {
+                        Some(span) => Diagnostic::error(ErrorCode::ImageTooLarge, message, span),
+                        None => Diagnostic::global(ErrorCode::ImageTooLarge, message),
+                    }]);
                 }
-                I::JNCConst24(dest) => {
-                    bytes.extend([0x03, 0x08, b0_24(dest), b1_24(dest), b2_24(dest)])
+            };
+            let mut grown = false;
+            for (ix, token) in code.code.iter().enumerate() {
+                let need = match token {
+                    Token::Jump(_, kind, dest, span) => {
+                        match Ver0::jump_target(dest, *span, &labels) {
+                            Ok(dest) => Ver0::label_jumps(*kind, addrs[ix], dest)[0].0.size(),
+                            Err(_) => continue,
+                        }
+                    }
+                    Token::Pending(s) => match s.lower(&labels) {
+                        Ok(i) => i.size(),
+                        Err(_) => continue,
+                    },
+                    _ => continue,
+                };
+                if need > sizes[ix] {
+                    sizes[ix] = need;
+                    grown = true;
                 }
-                I::JNCConst32(dest) => bytes.extend([
-                    0x03,
-                    0x09,
-                    b0_32(dest),
-                    b1_32(dest),
-                    b2_32(dest),
-                    b3_32(dest),
-                ]),
-                I::JNCOffsetA8(ofs) => bytes.extend([0x03, 0x0a, ofs]),
-                I::JNCOffsetS8(ofs) => bytes.extend([0x03, 0x0b, ofs]),
-                I::JNCOffsetA16(ofs) => bytes.extend([0x03, 0x0c, b0_16(ofs), b1_16(ofs)]),
-                I::JNCOffsetS16(ofs) => bytes.extend([0x03, 0x0d, b0_16(ofs), b1_16(ofs)]),
-                I::JNCOffsetA32(ofs) => {
-                    bytes.extend([0x03, 0x0e, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
+            }
+            if !grown {
+                break (addrs, labels);
+            }
+        };
+
+        let mut instructions = vec![];
+        let mut diags = vec![];
+        for (ix, token) in code.code.into_iter().enumerate() {
+            let (i, size) = match token {
+                Token::Instruction(i, _) => (i, sizes[ix]),
+                Token::Label(_, _) => continue,
+                Token::Org(target, span) if addrs[ix] > target => {
+                    diags.push(
+                        Diagnostic::error(
+                            ErrorCode::OrgBackwards,
+                            format!(".org 0x{:x} would move backwards", target),
+                            span,
+                        )
+                        .note(format!(
+                            "the code before it already reaches 0x{:x}",
+                            addrs[ix]
+                        )),
+                    );
+                    continue;
                 }
-                I::JNCOffsetS32(ofs) => {
-                    bytes.extend([0x03, 0x0f, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
+                Token::Org(_, _) | Token::Align(_, _) => {
+                    let pad = token.padding(addrs[ix]);
+                    (Instruction::Data(vec![0; pad as usize]), pad)
                 }
-                I::JZConst24(dest) => {
-                    bytes.extend([0x03, 0x10, b0_24(dest), b1_24(dest), b2_24(dest)])
+                Token::Bytes(b, _) => (Instruction::Data(b), sizes[ix]),
+                Token::Fill(one, count, _) => {
+                    (Instruction::Data(one.repeat(count as usize)), sizes[ix])
                 }
-                I::JZConst32(dest) => bytes.extend([
-                    0x03,
-                    0x11,
-                    b0_32(dest),
-                    b1_32(dest),
-                    b2_32(dest),
-                    b3_32(dest),
-                ]),
-                I::JZOffsetA8(ofs) => bytes.extend([0x03, 0x12, ofs]),
-                I::JZOffsetS8(ofs) => bytes.extend([0x03, 0x13, ofs]),
-                I::JZOffsetA16(ofs) => bytes.extend([0x03, 0x14, b0_16(ofs), b1_16(ofs)]),
-                I::JZOffsetS16(ofs) => bytes.extend([0x03, 0x15, b0_16(ofs), b1_16(ofs)]),
-                I::JZOffsetA32(ofs) => {
-                    bytes.extend([0x03, 0x16, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
+                Token::Data(width, values) => {
+                    let max = u32::MAX as u64 >> (32 - 8 * width);
+                    let mut bytes = vec![];
+                    for (e, span) in values {
+                        match e.value(&labels, span, max) {
+                            Ok(n) => bytes.extend(&n.to_le_bytes()[..width as usize]),
+                            Err(d) => diags.push(d),
+                        }
+                    }
+                    (Instruction::Data(bytes), sizes[ix])
                 }
-                I::JZOffsetS32(ofs) => {
-                    bytes.extend([0x03, 0x17, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
+                Token::Jump(cond, kind, dest, span) => {
+                    let dest = match Ver0::jump_target(&dest, span, &labels) {
+                        Ok(dest) => dest,
+                        Err(d) => {
+                            diags.push(d);
+                            continue;
+                        }
+                    };
+                    let forms = Ver0::label_jumps(kind, addrs[ix], dest);
+                    // a jump that grew early may now fit a shorter encoding,
+                    // but it has to keep the size the layout gave it
+                    let (form, n) = forms
+                        .iter()
+                        .find(|(f, _)| f.size() == sizes[ix])
+                        .unwrap_or(&forms[0]);
+                    (jump_instruction(cond, *form, *n), sizes[ix])
                 }
-                I::JNZConst24(dest) => {
-                    bytes.extend([0x03, 0x18, b0_24(dest), b1_24(dest), b2_24(dest)])
+                Token::Pending(s) => match s.lower(&labels) {
+                    Ok(i) => (i, sizes[ix]),
+                    Err(d) => {
+                        diags.push(d);
+                        continue;
+                    }
+                },
+            };
+            let pad = size - i.size();
+            instructions.push(i);
+            for _ in 0..pad {
+                instructions.push(Instruction::Nop);
+            }
+        }
+        if !diags.is_empty() {
+            return Err(diags);
+        }
+        let labels = labels.into_iter().map(|(k, v)| (k, v as u32)).collect();
+        Ok((instructions, labels))
+    }
+    /// Reports every duplicate label definition and every use of a label
+    /// that doesn't exist.
+    fn check_labels(code: &[Token]) -> Result<(), Vec<Diagnostic>> {
+        let mut diags = vec![];
+        let mut defined: HashMap<&String, Span> = HashMap::new();
+        for token in code {
+            if let Token::Label(name, span) = token {
+                match defined.get(name) {
+                    Some(first) => diags.push(
+                        Diagnostic::error(
+                            ErrorCode::DuplicateLabel,
+                            format!("label `{}` is defined more than once", name),
+                            *span,
+                        )
+                        .note_at("first defined here", *first),
+                    ),
+                    None => {
+                        defined.insert(name, *span);
+                    }
                 }
-                I::JNZConst32(dest) => bytes.extend([
-                    0x03,
-                    0x19,
-                    b0_32(dest),
-                    b1_32(dest),
-                    b2_32(dest),
-                    b3_32(dest),
-                ]),
-                I::JNZOffsetA8(ofs) => bytes.extend([0x03, 0x1a, ofs]),
-                I::JNZOffsetS8(ofs) => bytes.extend([0x03, 0x1b, ofs]),
-                I::JNZOffsetA16(ofs) => bytes.extend([0x03, 0x1c, b0_16(ofs), b1_16(ofs)]),
-                I::JNZOffsetS16(ofs) => bytes.extend([0x03, 0x1d, b0_16(ofs), b1_16(ofs)]),
-                I::JNZOffsetA32(ofs) => {
-                    bytes.extend([0x03, 0x1e, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
+            }
+        }
+        for token in code {
+            let used = match token {
+                Token::Jump(_, _, dest, _) => {
+                    let mut used = vec![];
+                    dest.labels(&mut used);
+                    used
                 }
-                I::JNZOffsetS32(ofs) => {
-                    bytes.extend([0x03, 0x1f, b0_32(ofs), b1_32(ofs), b2_32(ofs), b3_32(ofs)])
+                Token::Pending(s) => s.labels(),
+                Token::Data(_, values) => {
+                    let mut used = vec![];
+                    for (e, _) in values {
+                        e.labels(&mut used);
+                    }
+                    used
                 }
-
-                I::AddRRR(a, b, d) => bytes.extend([0x04, 0x00, a, b, d]),
-                I::AddZRR(a, b, d) => bytes.extend([0x04, 0x01, a, b, d]),
-                I::IncR1(r) => bytes.extend([0x04, 0x02, r]),
-                I::AddRRZ(a, b, d) => bytes.extend([0x04, 0x03, a, b, d]),
-                I::AddRZZ(a, b, d) => bytes.extend([0x04, 0x04, a, b, d]),
-                I::AddZZZ(a, b, d) => bytes.extend([0x04, 0x05, a, b, d]),
-                I::AddcRRR(a, b, d) => bytes.extend([0x04, 0x06, a, b, d]),
-                I::AddcZRR(a, b, d) => bytes.extend([0x04, 0x07, a, b, d]),
-                I::IncR4(r) => bytes.extend([0x04, 0x08, r]),
-                I::AddcRRZ(a, b, d) => bytes.extend([0x04, 0x09, a, b, d]),
-                I::AddcRZZ(a, b, d) => bytes.extend([0x04, 0x0a, a, b, d]),
-                I::AddcZZZ(a, b, d) => bytes.extend([0x04, 0x0b, a, b, d]),
-                I::SubRRR(a, b, d) => bytes.extend([0x04, 0x0c, a, b, d]),
-                I::SubZRR(a, b, d) => bytes.extend([0x04, 0x0d, a, b, d]),
-                I::SubRZR(a, b, d) => bytes.extend([0x04, 0x0e, a, b, d]),
-                I::SubRRZ(a, b, d) => bytes.extend([0x04, 0x0f, a, b, d]),
-                I::SubRZZ(a, b, d) => bytes.extend([0x04, 0x10, a, b, d]),
-                I::SubZZZ(a, b, d) => bytes.extend([0x04, 0x11, a, b, d]),
-                I::SubbRRR(a, b, d) => bytes.extend([0x04, 0x12, a, b, d]),
-                I::SubbZRR(a, b, d) => bytes.extend([0x04, 0x13, a, b, d]),
-                I::SubbRZR(a, b, d) => bytes.extend([0x04, 0x14, a, b, d]),
-                I::SubbRRZ(a, b, d) => bytes.extend([0x04, 0x15, a, b, d]),
-                I::SubbRZZ(a, b, d) => bytes.extend([0x04, 0x16, a, b, d]),
-                I::SubbZZZ(a, b, d) => bytes.extend([0x04, 0x17, a, b, d]),
-                I::MulRRR(a, b, d) => bytes.extend([0x04, 0x18, a, b, d]),
-                I::MulZRR(a, b, d) => bytes.extend([0x04, 0x19, a, b, d]),
-                I::MulRRZ(a, b, d) => bytes.extend([0x04, 0x1a, a, b, d]),
-                I::MulRZZ(a, b, d) => bytes.extend([0x04, 0x1b, a, b, d]),
-                I::MulZZZ(a, b, d) => bytes.extend([0x04, 0x1c, a, b, d]),
-                I::DivRRR(a, b, d) => bytes.extend([0x04, 0x1d, a, b, d]),
-                I::DivZRR(a, b, d) => bytes.extend([0x04, 0x1e, a, b, d]),
-                I::DivRZR(a, b, d) => bytes.extend([0x04, 0x1f, a, b, d]),
-                I::DivRRZ(a, b, d) => bytes.extend([0x04, 0x20, a, b, d]),
-                I::DivRZZ(a, b, d) => bytes.extend([0x04, 0x21, a, b, d]),
-                I::DivZZZ(a, b, d) => bytes.extend([0x04, 0x22, a, b, d]),
-                I::AndRRR(a, b, d) => bytes.extend([0x04, 0x23, a, b, d]),
-                I::AndZRR(a, b, d) => bytes.extend([0x04, 0x24, a, b, d]),
-                I::AndRRZ(a, b, d) => bytes.extend([0x04, 0x25, a, b, d]),
-                I::AndRZZ(a, b, d) => bytes.extend([0x04, 0x26, a, b, d]),
-                I::AndZZZ(a, b, d) => bytes.extend([0x04, 0x27, a, b, d]),
-                I::OrRRR(a, b, d) => bytes.extend([0x04, 0x28, a, b, d]),
-                I::OrZRR(a, b, d) => bytes.extend([0x04, 0x29, a, b, d]),
-                I::OrRRZ(a, b, d) => bytes.extend([0x04, 0x2a, a, b, d]),
-                I::OrRZZ(a, b, d) => bytes.extend([0x04, 0x2b, a, b, d]),
-                I::OrZZZ(a, b, d) => bytes.extend([0x04, 0x2c, a, b, d]),
-                I::XorRRR(a, b, d) => bytes.extend([0x04, 0x2d, a, b, d]),
-                I::XorZRR(a, b, d) => bytes.extend([0x04, 0x2e, a, b, d]),
-                I::XorRRZ(a, b, d) => bytes.extend([0x04, 0x2f, a, b, d]),
-                I::XorRZZ(a, b, d) => bytes.extend([0x04, 0x30, a, b, d]),
-                I::XorZZZ(a, b, d) => bytes.extend([0x04, 0x31, a, b, d]),
-                I::NotRR(a, d) => bytes.extend([0x04, 0x32, a, d]),
-                I::NotZR(a, d) => bytes.extend([0x04, 0x33, a, d]),
-                I::NotRZ(a, d) => bytes.extend([0x04, 0x34, a, d]),
-                I::NotZZ(a, d) => bytes.extend([0x04, 0x35, a, d]),
-                I::DecR1(r) => bytes.extend([0x04, 0x36, r]),
-                I::DecR4(r) => bytes.extend([0x04, 0x37, r]),
-                I::RemRRR(a, b, d) => bytes.extend([0x04, 0x38, a, b, d]),
-                I::RemZRR(a, b, d) => bytes.extend([0x04, 0x39, a, b, d]),
-                I::RemRZR(a, b, d) => bytes.extend([0x04, 0x3a, a, b, d]),
-                I::RemRRZ(a, b, d) => bytes.extend([0x04, 0x3b, a, b, d]),
-                I::RemRZZ(a, b, d) => bytes.extend([0x04, 0x3c, a, b, d]),
-                I::RemZZZ(a, b, d) => bytes.extend([0x04, 0x3d, a, b, d]),
-                I::ShlRRR(a, b, d) => bytes.extend([0x04, 0x3e, a, b, d]),
-                I::ShlZRR(a, b, d) => bytes.extend([0x04, 0x3f, a, b, d]),
-                I::ShlRCR(a, b, d) => bytes.extend([0x04, 0x40, a, b, d]),
-                I::ShlZCR(a, b, d) => bytes.extend([0x04, 0x41, a, b, d]),
-                I::ShlRCZ(a, b, d) => bytes.extend([0x04, 0x42, a, b, d]),
-                I::ShlZCZ(a, b, d) => bytes.extend([0x04, 0x43, a, b, d]),
-                I::ShrRRR(a, b, d) => bytes.extend([0x04, 0x44, a, b, d]),
-                I::ShrZRR(a, b, d) => bytes.extend([0x04, 0x45, a, b, d]),
-                I::ShrRCR(a, b, d) => bytes.extend([0x04, 0x46, a, b, d]),
-                I::ShrZCR(a, b, d) => bytes.extend([0x04, 0x47, a, b, d]),
-                I::ShrRCZ(a, b, d) => bytes.extend([0x04, 0x48, a, b, d]),
-                I::ShrZCZ(a, b, d) => bytes.extend([0x04, 0x49, a, b, d]),
-                I::PrintChar_R(r) => bytes.extend([0x06, 0x00, r]),
-                I::PrintChar_C8(c) => bytes.extend([0x06, 0x00, c]),
-                I::Read8_Cptr_R(ptr, r) => bytes.extend([
-                    0x07,
-                    0x00,
-                    b0_32(ptr),
-                    b1_32(ptr),
-                    b2_32(ptr),
-                    b3_32(ptr),
-                    r,
-                ]),
-                I::Read16_Cptr_R(_, _) => todo!(),
-                I::Read32_Cptr_R(_, _) => todo!(),
-                I::Read8_Rptr_R(_, _) => todo!(),
-                I::Read16_Rptr_R(_, _) => todo!(),
-                I::Read32_Rptr_R(_, _) => todo!(),
-                I::Write8_R_Cptr(_, _) => todo!(),
-                I::Write16_R_Cptr(_, _) => todo!(),
-                I::Write32_R_Cptr(_, _) => todo!(),
-                I::Write8_R_Rptr(_, _) => todo!(),
-                I::Write16_R_Rptr(_, _) => todo!(),
-                I::Write32_R_Rptr(_, _) => todo!(),
-                I::Write8_C8_Cptr(_, _) => todo!(),
-                I::Write8_C8_Rptr(_, _) => todo!(),
-                I::Write16_C16_Cptr(_, _) => todo!(),
-                I::Write16_C16_Rptr(_, _) => todo!(),
-                I::Write32_C32_Cptr(_, _) => todo!(),
-                I::Write32_C32_Rptr(_, _) => todo!(),
-                I::MovRR(_, _) => todo!(),
-                I::MovR4R4(_, _, _) => todo!(),
-                I::Mov4R4R(_, _, _) => todo!(),
-                I::Mov44(_) => todo!(),
-                I::ConstRegU8(r, c) => bytes.extend([0x14, c, r]),
-                I::ConstRegU16(r, c) => bytes.extend([0x15, b0_16(c), b1_16(c), r]),
-                I::ConstRegU32(r, c) => {
-                    bytes.extend([0x16, b0_32(c), b1_32(c), b2_32(c), b3_32(c), r])
+                _ => continue,
+            };
+            for (name, span) in used {
+                if !defined.contains_key(name) {
+                    let mut d = Diagnostic::error(
+                        ErrorCode::UndefinedLabel,
+                        format!("undefined label `{}`", name),
+                        span,
+                    );
+                    if let Some(similar) = diagnostic::suggest(name, defined.keys().copied()) {
+                        d = d.help(format!("did you mean `{}`?", similar));
+                    }
+                    diags.push(d);
                 }
-                I::SetZF => bytes.extend([0x20]),
-                I::ClrZF => bytes.extend([0x21]),
-                I::SetCF => bytes.extend([0x22]),
-                I::ClrCF => bytes.extend([0x23]),
-                I::IntEnable => bytes.extend([0x24]),
-                I::IntDisable => bytes.extend([0x25]),
-                I::ExplicitHaltAndExit => bytes.extend([0xFE]),
-                I::DebugPrintReg(r) => bytes.extend([0xFF, 0x01, r]),
-                //_ => panic!(),
             }
         }
-        return bytes;
+        if !diags.is_empty() {
+            return Err(diags);
+        }
+        Ok(())
+    }
+    /// Addresses every token from its size, and collects label addresses.
+    /// Fails with the index of the first token that reaches past MAX_IMAGE.
+    fn layout(code: &[Token], sizes: &[u32]) -> Result<(Vec<u32>, Symbols), usize> {
+        let mut addrs = Vec::with_capacity(code.len());
+        let mut labels = Symbols::new();
+        let mut addr = 0u32;
+        for (ix, (token, size)) in code.iter().zip(sizes).enumerate() {
+            if let Token::Label(name, _) = token {
+                labels.insert(name.clone(), addr as i64);
+            }
+            addrs.push(addr);
+            let end = addr as u64 + *size as u64 + token.padding(addr) as u64;
+            if end > MAX_IMAGE as u64 {
+                return Err(ix);
+            }
+            addr = end as u32;
+        }
+        Ok((addrs, labels))
+    }
+    fn jump_target(dest: &Expr, span: Span, labels: &Symbols) -> Result<u32, Diagnostic> {
+        Ok(dest.value(labels, span, 0xffff_ffff)? as u32)
+    }
+    /// Every encoding a jump at `addr` to `dest` could use, shortest first.
+    fn label_jumps(kind: JumpKind, addr: u32, dest: u32) -> Vec<(JumpForm, u32)> {
+        // offsets are measured from the first operand byte
+        let base = addr + 2;
+        let (forward, ofs) = if dest >= base {
+            (true, dest - base)
+        } else {
+            (false, base - dest)
+        };
+        let rel = |a: JumpForm, s: JumpForm| if forward { (a, ofs) } else { (s, ofs) };
+        let mut forms = vec![
+            rel(JumpForm::OffsetA8, JumpForm::OffsetS8),
+            rel(JumpForm::OffsetA16, JumpForm::OffsetS16),
+            rel(JumpForm::OffsetA32, JumpForm::OffsetS32),
+        ];
+        if kind == JumpKind::Any {
+            forms.push((JumpForm::Const24, dest));
+            forms.push((JumpForm::Const32, dest));
+        }
+        forms.retain(|(f, n)| *n <= f.max());
+        forms.sort_by_key(|(f, _)| f.size());
+        forms
+    }
+    /// Assembles `code`, using `path` to refer to it in diagnostics.
+    pub fn assemble_str(&self, path: &str, code: &str) -> Result<Vec<u8>, AssemblyError> {
+        Ok(self.assemble_str_with_labels(path, code)?.0)
+    }
+    /// Assembles the file at `path` like `assemble_file`, and also gives the
+    /// address of every label, for tools like the debugger.
+    pub fn assemble_file_with_labels(
+        &self,
+        path: &str,
+    ) -> Result<(Vec<u8>, Labels), AssemblyError> {
+        let code = std::fs::read_to_string(path).map_err(|e| AssemblyError {
+            diagnostics: vec![Diagnostic::global(
+                ErrorCode::Io,
+                format!("couldn't read `{}`: {}", path, e),
+            )],
+            sources: SourceMap::new(),
+        })?;
+        self.assemble_str_with_labels(path, &code)
+    }
+    /// Assembles `code` like `assemble_str`, and also gives the address of
+    /// every label.
+    pub fn assemble_str_with_labels(
+        &self,
+        path: &str,
+        code: &str,
+    ) -> Result<(Vec<u8>, Labels), AssemblyError> {
+        let mut sources = SourceMap::new();
+        let file = sources.add(path, code);
+        let mut diags = vec![];
+        let scope = self.tokenize(&mut sources, file, &mut diags);
+        let instructions = if diags.is_empty() {
+            Ver0::flatten_tokens(scope)
+        } else {
+            // label definitions survive syntax errors, so these are still worth reporting
+            diags.extend(Ver0::check_labels(&scope.code).err().unwrap_or_default());
+            Err(diags)
+        };
+        match instructions {
+            Ok((instructions, labels)) => Ok((Ver0::assemble_to_bytes(instructions), labels)),
+            Err(mut diagnostics) => {
+                diagnostics.sort_by_key(|d| d.span.map(|s| (s.file, s.line, s.col)));
+                Err(AssemblyError {
+                    diagnostics,
+                    sources,
+                })
+            }
+        }
+    }
+    pub fn assemble_to_bytes(code: Vec<Instruction>) -> Vec<u8> {
+        let mut bytes = vec![];
+        for i in code {
+            i.encode(&mut bytes);
+        }
+        bytes
     }
 }
 impl Assembler for Ver0 {
-    fn assemble_file(path: &str) -> Result<Vec<u8>, AssemblyError> {
-        return Ok(vec![0]);
+    fn assemble_file(&self, path: &str) -> Result<Vec<u8>, AssemblyError> {
+        Ok(self.assemble_file_with_labels(path)?.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(code: &str) -> Result<Vec<u8>, AssemblyError> {
+        Ver0::new().assemble_str("test.kasm", code)
+    }
+    fn codes(code: &str) -> Vec<ErrorCode> {
+        match assemble(code) {
+            Ok(_) => panic!("`{}` assembled", code),
+            Err(e) => e.diagnostics.iter().map(|d| d.code).collect(),
+        }
+    }
+
+    #[test]
+    fn jumps_come_apart_the_way_they_were_built() {
+        for (cond, _, _) in JUMPS {
+            for form in FORMS {
+                let i = jump_instruction(cond, form, form.max());
+                assert_eq!(jump_parts(&i), Some((cond, form, form.max())), "{}", i);
+                // the mnemonic the isa table gives it has to agree too
+                let text = i.to_string();
+                let mnemonic = text.split(' ').next().unwrap();
+                let offset = !matches!(form, JumpForm::Const24 | JumpForm::Const32);
+                assert!(
+                    JumpCond::from_mnemonic(mnemonic) == Some((cond, offset)),
+                    "{:?} {:?} is {}",
+                    cond,
+                    form,
+                    text
+                );
+            }
+        }
+        assert_eq!(jump_parts(&Instruction::CallR(1)), None);
+        assert_eq!(jump_parts(&Instruction::Nop), None);
+        assert_eq!(
+            jump_parts(&Instruction::Data(vec![isa::JMP, 0, 0, 0, 0])),
+            None
+        );
+    }
+
+    #[test]
+    fn jumps_to_labels_take_the_shortest_form() {
+        // JMP_OFFSET +n, counting from the operand byte
+        assert_eq!(assemble("JMP next\nnext:\n").unwrap(), [isa::JMP, 0x02, 1]);
+        let back = assemble("top:\nNOP\nJMP top\n").unwrap();
+        assert_eq!(back, [0, isa::JMP, 0x03, 3]);
+        let far = assemble("JMP end\n.fill 0x200\nend:\n").unwrap();
+        assert_eq!(far[..4], [isa::JMP, 0x04, 0x02, 0x02]);
+        // past what 16 bits reach, an absolute address is shorter than +32
+        let farther = assemble(".org 0x100\nJMP end\n.fill 0x10000\nend:\n").unwrap();
+        assert_eq!(farther[0x100..0x105], [isa::JMP, 0x00, 0x05, 0x01, 0x01]);
+        // only relative forms for _OFFSET
+        let offset = assemble(".org 0x100\nJMP_OFFSET end\n.fill 0x10000\nend:\n").unwrap();
+        assert_eq!(offset[0x100..0x106], [isa::JMP, 0x06, 0x04, 0, 0x01, 0]);
+    }
+
+    #[test]
+    fn a_jump_that_grows_can_push_another_out_of_reach() {
+        // the second jump needs 16 bits, and the byte that costs puts `end`
+        // 256 bytes past the first one's operand
+        let code = "JMP_OFFSET end\nJMP_OFFSET away\n.fill 251\nend:\n.fill 0x200\naway:\n";
+        let bytes = assemble(code).unwrap();
+        assert_eq!(
+            bytes[..8],
+            [isa::JMP, 0x04, 0x01, 0x01, isa::JMP, 0x04, 0xfd, 0x02]
+        );
+        // without the 16 bit one, 8 bits are enough
+        let code = "JMP_OFFSET end\nJMP_OFFSET end\n.fill 251\nend:\n";
+        assert_eq!(assemble(code).unwrap()[..3], [isa::JMP, 0x02, 0xff]);
+    }
+
+    #[test]
+    fn label_operands_grow_with_the_label() {
+        let near = assemble("CONST end -> r0\nend:\n").unwrap();
+        let far = assemble("CONST end -> r0\n.fill 0x100\nend:\n").unwrap();
+        assert_eq!(far.len() - 0x100, near.len() + 1);
+    }
+
+    #[test]
+    fn every_error_is_rendered_at_its_operand() {
+        let code = "start:\n\tJMP strt\n\tCONST8 1 / 0 -> r0\n\tCONST8 0x100 -> r0\n";
+        let err = assemble(code).unwrap_err().to_string();
+        let expected = "\
+error[E0007]: undefined label `strt`
+ --> test.kasm:2:6
+  |
+2 |     JMP strt
+  |         ^^^^
+  = help: did you mean `start`?
+
+error[E0010]: division by zero
+ --> test.kasm:3:9
+  |
+3 |     CONST8 1 / 0 -> r0
+  |            ^^^^^
+
+error[E0006]: value 0x100 does not fit in 8 bits
+ --> test.kasm:4:9
+  |
+4 |     CONST8 0x100 -> r0
+  |            ^^^^^
+  = note: this operand holds 0x0 to 0xff, or -0x80 to -0x1 as two's complement
+
+error: could not assemble due to 3 previous errors";
+        assert_eq!(err, expected);
+    }
+
+    #[test]
+    fn fill_repeats_its_value() {
+        let code = assemble(".fill 3, 2, 0x1234\n").unwrap();
+        assert_eq!(code, [0x34, 0x12, 0x34, 0x12, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn layout_directives_stay_within_the_image() {
+        assert_eq!(codes(".fill 0xffffffff\n"), [ErrorCode::ValueOutOfRange]);
+        assert_eq!(codes(".fill 0x800000, 4\n"), [ErrorCode::ValueOutOfRange]);
+        assert_eq!(codes(".org 0x2000000\n"), [ErrorCode::ValueOutOfRange]);
+        assert_eq!(codes(".align 0x80000000\n"), [ErrorCode::ValueOutOfRange]);
+    }
+
+    #[test]
+    fn image_past_the_limit_is_an_error() {
+        let code = ".fill 0xf00000\n".repeat(300);
+        assert_eq!(codes(&code), [ErrorCode::ImageTooLarge]);
+        assert_eq!(
+            codes(".org 0xffffff\n.dword 1\n"),
+            [ErrorCode::ImageTooLarge]
+        );
     }
 }
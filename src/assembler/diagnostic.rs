@@ -0,0 +1,325 @@
+use std::fmt;
+
+/// A range of source text, `len` characters long starting at `line:col`
+/// (both 1 based) in file number `file` of the `SourceMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+impl Span {
+    pub fn new(file: usize, line: usize, col: usize, len: usize) -> Self {
+        Span {
+            file,
+            line,
+            col,
+            len,
+        }
+    }
+    /// Span covering both `self` and `other`. Only `self` is kept if they
+    /// aren't on the same line, which happens with macro arguments.
+    pub fn to(self, other: Span) -> Span {
+        if (other.file, other.line) != (self.file, self.line) {
+            return self;
+        }
+        let end = (other.col + other.len).max(self.col + self.len);
+        Span {
+            len: end - self.col,
+            ..self
+        }
+    }
+}
+
+/// Every file the assembler has read, so diagnostics can quote them.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<(String, String)>, // (path, text)
+}
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap { files: vec![] }
+    }
+    pub fn add(&mut self, path: &str, text: &str) -> usize {
+        self.files.push((path.to_string(), text.to_string()));
+        self.files.len() - 1
+    }
+    pub fn path(&self, file: usize) -> &str {
+        &self.files[file].0
+    }
+    pub fn text(&self, file: usize) -> &str {
+        &self.files[file].1
+    }
+    fn line(&self, span: Span) -> Option<&str> {
+        self.files
+            .get(span.file)?
+            .1
+            .lines()
+            .nth(span.line.checked_sub(1)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Io = 1,
+    InvalidToken = 2,
+    Syntax = 3,
+    UnknownInstruction = 4,
+    InvalidOperands = 5,
+    ValueOutOfRange = 6,
+    UndefinedLabel = 7,
+    DuplicateLabel = 8,
+    InvalidExpression = 10,
+    UnknownDirective = 12,
+    OrgBackwards = 13,
+    MacroRecursion = 14,
+    Unterminated = 15,
+    DuplicateMacro = 16,
+    IncludeCycle = 17,
+    DuplicateConstant = 18,
+    ImageTooLarge = 19,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code: ErrorCode,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<(String, Option<Span>)>,
+    pub help: Option<String>,
+}
+impl Diagnostic {
+    pub fn error(code: ErrorCode, message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            code,
+            message: message.into(),
+            span: Some(span),
+            notes: vec![],
+            help: None,
+        }
+    }
+    /// An error that isn't tied to any source text, like a missing file.
+    pub fn global(code: ErrorCode, message: impl Into<String>) -> Self {
+        Diagnostic {
+            code,
+            message: message.into(),
+            span: None,
+            notes: vec![],
+            help: None,
+        }
+    }
+    pub fn note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push((note.into(), None));
+        self
+    }
+    pub fn note_at(mut self, note: impl Into<String>, span: Span) -> Self {
+        self.notes.push((note.into(), Some(span)));
+        self
+    }
+    pub fn help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Renders the diagnostic the way rustc does:
+    ///
+    /// ```text
+    /// error[E0007]: undefined label `lop`
+    ///  --> programs/carry_loop.kasm:8:13
+    ///   |
+    /// 8 |     JNC_OFFSET lop
+    ///   |                ^^^
+    ///   = help: did you mean `loop`?
+    /// ```
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let mut out = format!("error[E{:04}]: {}\n", self.code as u32, self.message);
+        let gutter = self
+            .span
+            .iter()
+            .chain(self.notes.iter().filter_map(|(_, s)| s.as_ref()))
+            .map(|s| s.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        if let Some(span) = self.span {
+            snippet(&mut out, sources, span, gutter);
+        }
+        for (note, span) in &self.notes {
+            match span {
+                Some(span) => {
+                    out += &format!("note: {}\n", note);
+                    snippet(&mut out, sources, *span, gutter);
+                }
+                None => out += &format!("{:w$} = note: {}\n", "", note, w = gutter),
+            }
+        }
+        if let Some(help) = &self.help {
+            out += &format!("{:w$} = help: {}\n", "", help, w = gutter);
+        }
+        out
+    }
+}
+
+fn snippet(out: &mut String, sources: &SourceMap, span: Span, gutter: usize) {
+    *out += &format!(
+        "{:w$}--> {}:{}:{}\n",
+        "",
+        sources.path(span.file),
+        span.line,
+        span.col,
+        w = gutter
+    );
+    let text = match sources.line(span) {
+        Some(text) => text,
+        None => return,
+    };
+    // tabs are expanded so the caret lines up under the source
+    let expand = |s: &str| s.replace('\t', "    ");
+    let before: String = text.chars().take(span.col - 1).collect();
+    let marked: String = text
+        .chars()
+        .skip(span.col - 1)
+        .take(span.len.max(1))
+        .collect();
+    *out += &format!("{:w$} |\n", "", w = gutter);
+    *out += &format!("{:>w$} | {}\n", span.line, expand(text), w = gutter);
+    *out += &format!(
+        "{:w$} | {}{}\n",
+        "",
+        " ".repeat(expand(&before).chars().count()),
+        "^".repeat(expand(&marked).chars().count().max(1)),
+        w = gutter
+    );
+}
+
+/// Every error found while assembling, along with the sources they point into.
+pub struct AssemblyError {
+    pub diagnostics: Vec<Diagnostic>,
+    pub sources: SourceMap,
+}
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for d in &self.diagnostics {
+            writeln!(f, "{}", d.render(&self.sources))?;
+        }
+        match self.diagnostics.len() {
+            1 => write!(f, "error: could not assemble due to 1 previous error"),
+            n => write!(f, "error: could not assemble due to {} previous errors", n),
+        }
+    }
+}
+impl fmt::Debug for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+impl std::error::Error for AssemblyError {}
+
+/// Closest name to `name` among `candidates`, if any is close enough to be
+/// a likely typo.
+pub fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a String>) -> Option<&'a str> {
+    candidates
+        .map(|c| (distance(name, c), c))
+        .filter(|(d, c)| *d <= (c.len().max(name.len()) / 3).max(1))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c.as_str())
+}
+
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + (ca != *cb) as usize;
+            cur.push(sub.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_like_rustc() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("loop.kasm", "loop:\n    JNC_OFFSET lop\n");
+        let d = Diagnostic::error(
+            ErrorCode::UndefinedLabel,
+            "undefined label `lop`",
+            Span::new(file, 2, 16, 3),
+        )
+        .help("did you mean `loop`?");
+        let expected = "\
+error[E0007]: undefined label `lop`
+ --> loop.kasm:2:16
+  |
+2 |     JNC_OFFSET lop
+  |                ^^^
+  = help: did you mean `loop`?
+";
+        assert_eq!(d.render(&sources), expected);
+    }
+
+    #[test]
+    fn notes_get_their_own_snippets_and_the_gutter_fits_them() {
+        let mut sources = SourceMap::new();
+        let text = format!("a:\n{}a:\n", "\n".repeat(8));
+        let file = sources.add("dup.kasm", &text);
+        let d = Diagnostic::error(
+            ErrorCode::DuplicateLabel,
+            "label `a` is defined twice",
+            Span::new(file, 10, 1, 1),
+        )
+        .note_at("first defined here", Span::new(file, 1, 1, 1))
+        .note("labels are global");
+        let expected = "\
+error[E0008]: label `a` is defined twice
+  --> dup.kasm:10:1
+   |
+10 | a:
+   | ^
+note: first defined here
+  --> dup.kasm:1:1
+   |
+ 1 | a:
+   | ^
+   = note: labels are global
+";
+        assert_eq!(d.render(&sources), expected);
+    }
+
+    #[test]
+    fn carets_line_up_under_tabs() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("t.kasm", "\tFOO r0\n");
+        let d = Diagnostic::error(
+            ErrorCode::UnknownInstruction,
+            "unknown instruction `FOO`",
+            Span::new(file, 1, 2, 3),
+        );
+        assert!(d
+            .render(&sources)
+            .ends_with("1 |     FOO r0\n  |     ^^^\n"));
+    }
+
+    #[test]
+    fn global_errors_have_no_snippet() {
+        let d = Diagnostic::global(ErrorCode::Io, "couldn't read `x.kasm`");
+        assert_eq!(
+            d.render(&SourceMap::new()),
+            "error[E0001]: couldn't read `x.kasm`\n"
+        );
+    }
+
+    #[test]
+    fn suggestions_are_close_names_only() {
+        let names = ["loop".to_string(), "start".to_string(), "end".to_string()];
+        assert_eq!(suggest("lop", names.iter()), Some("loop"));
+        assert_eq!(suggest("stat", names.iter()), Some("start"));
+        assert_eq!(suggest("banana", names.iter()), None);
+    }
+}
@@ -0,0 +1,225 @@
+use super::{jump_parts, Instruction, JumpCond, JumpForm};
+
+/// One decoded instruction and where it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoded {
+    pub addr: u32,
+    pub bytes: Vec<u8>,
+    pub instruction: Instruction,
+}
+
+/// Decodes `code` as if it was loaded at `base`. Bytes that don't decode are
+/// kept as `Instruction::Data`, up to eight to an entry, so decoding never
+/// fails and the entries always cover all of `code`.
+pub fn decode(code: &[u8], base: u32) -> Vec<Decoded> {
+    let mut out: Vec<Decoded> = vec![];
+    let mut pos = 0;
+    while pos < code.len() {
+        let addr = base.wrapping_add(pos as u32);
+        let (instruction, len) = match Instruction::decode(&code[pos..]) {
+            Some(d) => d,
+            None => {
+                let b = code[pos];
+                pos += 1;
+                match out.last_mut() {
+                    Some(Decoded {
+                        bytes,
+                        instruction: Instruction::Data(data),
+                        ..
+                    }) if data.len() < 8 => {
+                        bytes.push(b);
+                        data.push(b);
+                    }
+                    _ => out.push(Decoded {
+                        addr,
+                        bytes: vec![b],
+                        instruction: Instruction::Data(vec![b]),
+                    }),
+                }
+                continue;
+            }
+        };
+        out.push(Decoded {
+            addr,
+            bytes: code[pos..pos + len].to_vec(),
+            instruction,
+        });
+        pos += len;
+    }
+    out
+}
+
+/// Disassembles `code` loaded at `base` into source the assembler accepts.
+/// Addresses, raw bytes and jump targets go in comments, and runs of `NOP`s
+/// are folded into a `.fill`.
+///
+/// Reassembling gives the same bytes, except for jumps that weren't encoded
+/// in their shortest form, which come back shorter.
+pub fn disassemble(code: &[u8], base: u32) -> String {
+    let decoded = decode(code, base);
+    let mut out = String::new();
+    if base != 0 {
+        out += &format!(".org {:#x}\n", base);
+    }
+    let mut i = 0;
+    while i < decoded.len() {
+        let d = &decoded[i];
+        let nops = decoded[i..]
+            .iter()
+            .take_while(|d| d.instruction == Instruction::Nop)
+            .count();
+        if nops >= 4 {
+            let text = format!(".fill {:#x}", nops);
+            out += &format!("    {:<32}; {:04x}: {} NOPs\n", text, d.addr, nops);
+            i += nops;
+            continue;
+        }
+        let bytes: Vec<String> = d.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut comment = format!("{:04x}: {}", d.addr, bytes.join(" "));
+        if let Some(target) = jump_target(&d.instruction, d.addr) {
+            comment += &format!(" -> {:04x}", target);
+        }
+        out += &format!("    {:<32}; {}\n", d.instruction.to_string(), comment);
+        i += 1;
+    }
+    out
+}
+
+/// Address a jump or call at `addr` goes to, if `i` is one.
+pub fn jump_target(i: &Instruction, addr: u32) -> Option<u32> {
+    let (_, form, n) = jump_parts(i)?;
+    let from = addr.wrapping_add(2);
+    Some(match form {
+        JumpForm::Const24 | JumpForm::Const32 => n,
+        JumpForm::OffsetA8 | JumpForm::OffsetA16 | JumpForm::OffsetA32 => from.wrapping_add(n),
+        JumpForm::OffsetS8 | JumpForm::OffsetS16 | JumpForm::OffsetS32 => from.wrapping_sub(n),
+    })
+}
+
+/// Whether `i` is a `CALL` of any kind, which comes back to the instruction
+/// after it.
+pub fn is_call(i: &Instruction) -> bool {
+    matches!(i, Instruction::CallR(_)) || matches!(jump_parts(i), Some((JumpCond::Call, _, _)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Ver0;
+
+    /// Assembles `code`, disassembles it and assembles that again.
+    fn round_trip(code: &str) -> (Vec<u8>, Vec<u8>) {
+        let ver0 = Ver0::new();
+        let first = ver0.assemble_str("first.kasm", code).unwrap();
+        let text = disassemble(&first, 0);
+        let second = match ver0.assemble_str("second.kasm", &text) {
+            Ok(bytes) => bytes,
+            Err(e) => panic!("the disassembly doesn't assemble:\n{}\n{}", text, e),
+        };
+        (first, second)
+    }
+
+    #[test]
+    fn disassembly_reassembles_to_the_same_bytes() {
+        let code = "\
+            .org 0x100
+        start:
+            CONST8 0xff -> r0
+            CONST 0x12345678 -> r1
+            ADD r0, r1 -> r2
+            ADD r0, zp[2] -> r3
+            PUSH_32 r4
+            POP_32 r4
+            READ_16 [r1] -> r5
+            WRITE_32 r0 -> [0x200]
+            PRINT_CHAR 'A'
+            JZ start
+            JNC_OFFSET +0x300
+            CALL 0x1234567
+            JMP_OFFSET -0x10
+            .fill 0x10
+            .byte 0xff, 0xfe
+            HALT_AND_EXIT
+        ";
+        let (first, second) = round_trip(code);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_programs_round_trip() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/kasm");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "kasm") {
+                let code = std::fs::read_to_string(&path).unwrap();
+                let (first, second) = round_trip(&code);
+                assert_eq!(first, second, "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn every_encoding_decodes_to_its_own_bytes() {
+        for e in crate::isa::TABLE {
+            let mut bytes = e.opcode.to_vec();
+            bytes.resize(e.size(), 0);
+            let (i, len) = match Instruction::decode(&bytes) {
+                Some(d) => d,
+                None => panic!("{} {:02x?} doesn't decode", e.mnemonic, e.opcode),
+            };
+            assert_eq!(len, e.size(), "{} {:02x?}", e.mnemonic, e.opcode);
+            let mut again = vec![];
+            i.encode(&mut again);
+            assert_eq!(again, bytes, "{} {:02x?}", e.mnemonic, e.opcode);
+        }
+    }
+
+    #[test]
+    fn bytes_that_dont_decode_are_kept_as_data() {
+        // an unknown opcode, then CONST32 with three of its five operand bytes
+        let mut code = vec![0xee; 10];
+        code.extend([0x16, 0xee, 0xee, 0xee]);
+        let decoded = decode(&code, 0x100);
+        let entries: Vec<(u32, usize)> = decoded.iter().map(|d| (d.addr, d.bytes.len())).collect();
+        assert_eq!(entries, [(0x100, 8), (0x108, 6)]);
+        assert!(decoded
+            .iter()
+            .all(|d| matches!(&d.instruction, Instruction::Data(b) if *b == d.bytes)));
+        let all: Vec<u8> = decoded.iter().flat_map(|d| d.bytes.clone()).collect();
+        assert_eq!(all, code);
+    }
+
+    #[test]
+    fn decoding_picks_up_after_data() {
+        let decoded = decode(&[0xee, 0x00, 0xfe], 0);
+        let found: Vec<&Instruction> = decoded.iter().map(|d| &d.instruction).collect();
+        assert_eq!(
+            found,
+            [
+                &Instruction::Data(vec![0xee]),
+                &Instruction::Nop,
+                &Instruction::ExplicitHaltAndExit
+            ]
+        );
+        assert_eq!(decoded[2].addr, 2);
+    }
+
+    #[test]
+    fn jump_targets_count_from_the_operand() {
+        assert_eq!(
+            jump_target(&Instruction::JmpOffsetA8(4), 0x100),
+            Some(0x106)
+        );
+        assert_eq!(
+            jump_target(&Instruction::JZOffsetS16(2), 0x100),
+            Some(0x100)
+        );
+        assert_eq!(
+            jump_target(&Instruction::CallConst24(0x400), 0x100),
+            Some(0x400)
+        );
+        assert_eq!(jump_target(&Instruction::Nop, 0x100), None);
+        assert!(is_call(&Instruction::CallR(3)));
+        assert!(!is_call(&Instruction::JmpConst32(0)));
+    }
+}
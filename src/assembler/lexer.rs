@@ -0,0 +1,436 @@
+use std::fmt;
+
+use super::diagnostic::{Diagnostic, ErrorCode, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lexeme {
+    Ident(String),
+    Directive(String), // .name
+    Int(u64),
+    Str(Vec<u8>),
+    Comma,
+    Arrow, // ->
+    Colon,
+    Plus,
+    Minus,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    Star,
+    Slash,
+    Percent,
+    Shl, // <<
+    Shr, // >>
+    Amp,
+    Pipe,
+    Caret,
+    Tilde,
+    Bang,
+    EqEq,
+    NotEq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    AndAnd,
+    OrOr,
+    Newline,
+    EndExpansion, // put after a macro's body by the parser, never lexed
+    EndInclude,   // put after an included file by the parser, never lexed
+}
+
+impl fmt::Display for Lexeme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lexeme::Ident(name) => write!(f, "`{}`", name),
+            Lexeme::Directive(name) => write!(f, "`.{}`", name),
+            Lexeme::Int(n) => write!(f, "`{}`", n),
+            Lexeme::Str(_) => write!(f, "a string"),
+            Lexeme::Comma => write!(f, "`,`"),
+            Lexeme::Arrow => write!(f, "`->`"),
+            Lexeme::Colon => write!(f, "`:`"),
+            Lexeme::Plus => write!(f, "`+`"),
+            Lexeme::Minus => write!(f, "`-`"),
+            Lexeme::LBracket => write!(f, "`[`"),
+            Lexeme::RBracket => write!(f, "`]`"),
+            Lexeme::LParen => write!(f, "`(`"),
+            Lexeme::RParen => write!(f, "`)`"),
+            Lexeme::Star => write!(f, "`*`"),
+            Lexeme::Slash => write!(f, "`/`"),
+            Lexeme::Percent => write!(f, "`%`"),
+            Lexeme::Shl => write!(f, "`<<`"),
+            Lexeme::Shr => write!(f, "`>>`"),
+            Lexeme::Amp => write!(f, "`&`"),
+            Lexeme::Pipe => write!(f, "`|`"),
+            Lexeme::Caret => write!(f, "`^`"),
+            Lexeme::Tilde => write!(f, "`~`"),
+            Lexeme::Bang => write!(f, "`!`"),
+            Lexeme::EqEq => write!(f, "`==`"),
+            Lexeme::NotEq => write!(f, "`!=`"),
+            Lexeme::Lt => write!(f, "`<`"),
+            Lexeme::Le => write!(f, "`<=`"),
+            Lexeme::Gt => write!(f, "`>`"),
+            Lexeme::Ge => write!(f, "`>=`"),
+            Lexeme::AndAnd => write!(f, "`&&`"),
+            Lexeme::OrOr => write!(f, "`||`"),
+            Lexeme::Newline => write!(f, "end of line"),
+            Lexeme::EndExpansion => write!(f, "end of macro"),
+            Lexeme::EndInclude => write!(f, "end of file"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Lexed {
+    pub lexeme: Lexeme,
+    pub span: Span,
+}
+
+/// Splits `.kasm` source into lexemes. Comments run from `;` to the end of
+/// the line, and every line ends with a `Newline` so the parser can work
+/// one statement per line. Bad characters are reported and skipped.
+pub fn lex(src: &str, file: usize, diags: &mut Vec<Diagnostic>) -> Vec<Lexed> {
+    let mut out = vec![];
+    for (line_ix, text) in src.lines().enumerate() {
+        let line = line_ix + 1;
+        let chars: Vec<char> = text.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let start = i;
+            let span = |end: usize| Span::new(file, line, start + 1, end - start);
+            let lexeme = match chars[i] {
+                ';' => break,
+                ' ' | '\t' | '\r' => {
+                    i += 1;
+                    continue;
+                }
+                ',' => Lexeme::Comma,
+                ':' => Lexeme::Colon,
+                '+' => Lexeme::Plus,
+                '[' => Lexeme::LBracket,
+                ']' => Lexeme::RBracket,
+                '(' => Lexeme::LParen,
+                ')' => Lexeme::RParen,
+                '*' => Lexeme::Star,
+                '/' => Lexeme::Slash,
+                '%' => Lexeme::Percent,
+                '^' => Lexeme::Caret,
+                '~' => Lexeme::Tilde,
+                // operators that may be two characters long
+                c @ ('&' | '|' | '<' | '>' | '=' | '!') => {
+                    let pair = match (c, chars.get(i + 1)) {
+                        ('&', Some('&')) => Some(Lexeme::AndAnd),
+                        ('|', Some('|')) => Some(Lexeme::OrOr),
+                        ('<', Some('<')) => Some(Lexeme::Shl),
+                        ('<', Some('=')) => Some(Lexeme::Le),
+                        ('>', Some('>')) => Some(Lexeme::Shr),
+                        ('>', Some('=')) => Some(Lexeme::Ge),
+                        ('=', Some('=')) => Some(Lexeme::EqEq),
+                        ('!', Some('=')) => Some(Lexeme::NotEq),
+                        _ => None,
+                    };
+                    match (pair, c) {
+                        (Some(l), _) => {
+                            i += 1;
+                            l
+                        }
+                        (None, '&') => Lexeme::Amp,
+                        (None, '|') => Lexeme::Pipe,
+                        (None, '<') => Lexeme::Lt,
+                        (None, '>') => Lexeme::Gt,
+                        (None, '!') => Lexeme::Bang,
+                        (None, _) => {
+                            diags.push(Diagnostic::error(
+                                ErrorCode::InvalidToken,
+                                "unexpected character `=`",
+                                span(i + 1),
+                            ));
+                            i += 1;
+                            continue;
+                        }
+                    }
+                }
+                '-' if chars.get(i + 1) == Some(&'>') => {
+                    i += 1;
+                    Lexeme::Arrow
+                }
+                '-' => Lexeme::Minus,
+                '.' if chars.get(i + 1).is_some_and(|c| c.is_ascii_alphabetic()) => {
+                    i += 1;
+                    while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                        i += 1;
+                    }
+                    let name: String = chars[start + 1..i].iter().collect();
+                    out.push(Lexed {
+                        lexeme: Lexeme::Directive(name.to_ascii_lowercase()),
+                        span: span(i),
+                    });
+                    continue;
+                }
+                '"' => {
+                    i += 1;
+                    let mut bytes = vec![];
+                    let mut closed = false;
+                    while i < chars.len() {
+                        let c = chars[i];
+                        i += 1;
+                        match c {
+                            '"' => {
+                                closed = true;
+                                break;
+                            }
+                            '\\' => {
+                                let at = i - 1;
+                                match escape(&chars, &mut i) {
+                                    Some(b) => bytes.push(b),
+                                    None => diags.push(Diagnostic::error(
+                                        ErrorCode::InvalidToken,
+                                        "unknown escape sequence",
+                                        Span::new(file, line, at + 1, i - at),
+                                    )),
+                                }
+                            }
+                            c => {
+                                let mut buf = [0; 4];
+                                bytes.extend(c.encode_utf8(&mut buf).bytes());
+                            }
+                        }
+                    }
+                    if !closed {
+                        diags.push(Diagnostic::error(
+                            ErrorCode::InvalidToken,
+                            "unterminated string",
+                            span(i),
+                        ));
+                    }
+                    out.push(Lexed {
+                        lexeme: Lexeme::Str(bytes),
+                        span: span(i),
+                    });
+                    continue;
+                }
+                '\'' => {
+                    // character literal, e.g. 'A'
+                    match (chars.get(i + 1), chars.get(i + 2)) {
+                        (Some(&ch), Some('\'')) => {
+                            i += 2;
+                            Lexeme::Int(ch as u64)
+                        }
+                        _ => {
+                            diags.push(Diagnostic::error(
+                                ErrorCode::InvalidToken,
+                                "unterminated character literal",
+                                span(i + 1),
+                            ));
+                            i += 1;
+                            continue;
+                        }
+                    }
+                }
+                c if c.is_ascii_alphanumeric() || c == '_' => {
+                    while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                        i += 1;
+                    }
+                    let text: String = chars[start..i].iter().collect();
+                    let lexeme = if c.is_ascii_digit() {
+                        match parse_int(&text) {
+                            Some(n) => Lexeme::Int(n),
+                            None => {
+                                diags.push(Diagnostic::error(
+                                    ErrorCode::InvalidToken,
+                                    format!("invalid number `{}`", text),
+                                    span(i),
+                                ));
+                                Lexeme::Int(0)
+                            }
+                        }
+                    } else {
+                        Lexeme::Ident(text)
+                    };
+                    out.push(Lexed {
+                        lexeme,
+                        span: span(i),
+                    });
+                    continue;
+                }
+                c => {
+                    diags.push(Diagnostic::error(
+                        ErrorCode::InvalidToken,
+                        format!("unexpected character `{}`", c),
+                        span(i + 1),
+                    ));
+                    i += 1;
+                    continue;
+                }
+            };
+            i += 1;
+            out.push(Lexed {
+                lexeme,
+                span: span(i),
+            });
+        }
+        out.push(Lexed {
+            lexeme: Lexeme::Newline,
+            span: Span::new(file, line, chars.len() + 1, 1),
+        });
+    }
+    out
+}
+
+/// Decodes the escape after a `\` in a string, leaving `i` past it.
+fn escape(chars: &[char], i: &mut usize) -> Option<u8> {
+    let c = *chars.get(*i)?;
+    *i += 1;
+    Some(match c {
+        'n' => b'\n',
+        'r' => b'\r',
+        't' => b'\t',
+        '0' => 0,
+        '\\' => b'\\',
+        '"' => b'"',
+        '\'' => b'\'',
+        'x' => {
+            let hex: String = chars.get(*i..*i + 2)?.iter().collect();
+            *i += 2;
+            u8::from_str_radix(&hex, 16).ok()?
+        }
+        _ => return None,
+    })
+}
+
+fn parse_int(text: &str) -> Option<u64> {
+    let text: String = text.chars().filter(|c| *c != '_').collect();
+    let (digits, radix) = match text.get(..2) {
+        Some("0x") | Some("0X") => (&text[2..], 16),
+        Some("0b") | Some("0B") => (&text[2..], 2),
+        Some("0o") | Some("0O") => (&text[2..], 8),
+        _ => (&text[..], 10),
+    };
+    u64::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lexemes(src: &str) -> Vec<Lexeme> {
+        let mut diags = vec![];
+        let out = lex(src, 0, &mut diags);
+        assert!(diags.is_empty(), "{:?}", diags);
+        out.into_iter().map(|l| l.lexeme).collect()
+    }
+
+    fn errors(src: &str) -> Vec<String> {
+        let mut diags = vec![];
+        lex(src, 0, &mut diags);
+        diags.into_iter().map(|d| d.message).collect()
+    }
+
+    fn ident(s: &str) -> Lexeme {
+        Lexeme::Ident(s.to_string())
+    }
+
+    #[test]
+    fn a_statement_and_its_comment() {
+        assert_eq!(
+            lexemes("loop: ADD r0, [r1] -> r2 ; a comment -> ignored"),
+            [
+                ident("loop"),
+                Lexeme::Colon,
+                ident("ADD"),
+                ident("r0"),
+                Lexeme::Comma,
+                Lexeme::LBracket,
+                ident("r1"),
+                Lexeme::RBracket,
+                Lexeme::Arrow,
+                ident("r2"),
+                Lexeme::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn operators_take_the_longest_match() {
+        assert_eq!(
+            lexemes("<< <= < >> >= > == != ! && & || | - ->"),
+            [
+                Lexeme::Shl,
+                Lexeme::Le,
+                Lexeme::Lt,
+                Lexeme::Shr,
+                Lexeme::Ge,
+                Lexeme::Gt,
+                Lexeme::EqEq,
+                Lexeme::NotEq,
+                Lexeme::Bang,
+                Lexeme::AndAnd,
+                Lexeme::Amp,
+                Lexeme::OrOr,
+                Lexeme::Pipe,
+                Lexeme::Minus,
+                Lexeme::Arrow,
+                Lexeme::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_characters_and_directives() {
+        assert_eq!(
+            lexemes("0x1F 0b101 0o17 1_000 'A' .ORG"),
+            [
+                Lexeme::Int(0x1f),
+                Lexeme::Int(5),
+                Lexeme::Int(15),
+                Lexeme::Int(1000),
+                Lexeme::Int(65),
+                Lexeme::Directive("org".to_string()),
+                Lexeme::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn strings_decode_their_escapes() {
+        assert_eq!(
+            lexemes(r#""a\n\t\0\\\"\x41;""#),
+            [Lexeme::Str(b"a\n\t\0\\\"A;".to_vec()), Lexeme::Newline]
+        );
+    }
+
+    #[test]
+    fn every_line_ends_with_a_newline_where_the_line_ends() {
+        let mut diags = vec![];
+        let out = lex("NOP\n\n  HALT", 3, &mut diags);
+        let spans: Vec<_> = out
+            .iter()
+            .map(|l| (l.span.line, l.span.col, l.span.len))
+            .collect();
+        assert_eq!(
+            spans,
+            [(1, 1, 3), (1, 4, 1), (2, 1, 1), (3, 3, 4), (3, 7, 1)]
+        );
+        assert!(out.iter().all(|l| l.span.file == 3));
+    }
+
+    #[test]
+    fn bad_input_is_reported_and_skipped() {
+        assert_eq!(
+            errors("= $ 0xzz 'ab \"\\q\" \"open"),
+            [
+                "unexpected character `=`",
+                "unexpected character `$`",
+                "invalid number `0xzz`",
+                "unterminated character literal",
+                "unknown escape sequence",
+                "unterminated string",
+            ]
+        );
+        let mut diags = vec![];
+        let out = lex("$ NOP", 0, &mut diags);
+        assert_eq!(out[0].lexeme, ident("NOP"));
+        assert_eq!(diags[0].span, Some(Span::new(0, 1, 1, 1)));
+    }
+}
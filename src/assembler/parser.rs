@@ -0,0 +1,1960 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::diagnostic::{Diagnostic, ErrorCode, SourceMap, Span};
+use super::lexer::{self, Lexed, Lexeme};
+use super::{
+    fit, jump_instruction, Expr, Instruction, JumpCond, JumpForm, JumpKind, Scope, Symbols, Token,
+    ARG_REGS, CALLEE_SAVED, MAX_IMAGE, SHUFFLE_REG,
+};
+
+enum Operand {
+    Reg(u8),
+    Zp(Expr, Span),
+    Imm(Expr, Span),
+    Offset(Expr, Span), // +n / -n
+    CPtr(Expr, Span),   // [const]
+    RPtr(u8),           // [reg]
+}
+
+/// An operand with its expression folded, ready for encoding.
+enum Arg {
+    Reg(u8),
+    Zp(u8),
+    Imm(i64, Span),
+    Offset(i64, Span),
+    CPtr(i64, Span),
+    RPtr(u8),
+}
+
+pub struct Statement {
+    mnemonic: String,
+    srcs: Vec<Operand>,
+    dst: Option<Operand>,
+    dst2: Option<Operand>, // only the paired MOVs have a second destination
+    mnemonic_span: Span,
+    span: Span,
+}
+impl Statement {
+    /// Every label the operands refer to.
+    pub fn labels(&self) -> Vec<(&String, Span)> {
+        let mut out = vec![];
+        for o in self.srcs.iter().chain(&self.dst).chain(&self.dst2) {
+            match o {
+                Operand::Zp(e, _)
+                | Operand::Imm(e, _)
+                | Operand::Offset(e, _)
+                | Operand::CPtr(e, _) => e.labels(&mut out),
+                Operand::Reg(_) | Operand::RPtr(_) => {}
+            }
+        }
+        out
+    }
+    pub fn span(&self) -> Span {
+        self.span
+    }
+    /// Every register the operands name.
+    pub fn registers(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for o in self.srcs.iter().chain(&self.dst).chain(&self.dst2) {
+            if let Operand::Reg(r) | Operand::RPtr(r) = o {
+                out.push(*r);
+            }
+        }
+        out
+    }
+    /// Folds the operands with `symbols` and picks the instruction.
+    pub fn lower(&self, symbols: &Symbols) -> Result<Instruction, Diagnostic> {
+        let jump = JumpCond::from_mnemonic(&self.mnemonic);
+        let arg = |o: &Operand| -> Result<Arg, Diagnostic> {
+            Ok(match o {
+                Operand::Reg(r) => Arg::Reg(*r),
+                Operand::Zp(e, es) => Arg::Zp(e.value(symbols, *es, 0xff)? as u8),
+                Operand::Imm(e, es) => Arg::Imm(e.eval(symbols, *es)?, *es),
+                // a leading sign only means "relative" to jumps
+                Operand::Offset(e, es) if jump.is_some() => Arg::Offset(e.eval(symbols, *es)?, *es),
+                Operand::Offset(e, es) => Arg::Imm(e.eval(symbols, *es)?, *es),
+                Operand::CPtr(e, es) => Arg::CPtr(e.eval(symbols, *es)?, *es),
+                Operand::RPtr(r) => Arg::RPtr(*r),
+            })
+        };
+        let srcs = self.srcs.iter().map(arg).collect::<Result<Vec<_>, _>>()?;
+        let dst = self.dst.as_ref().map(arg).transpose()?;
+        if let Some(dst2) = &self.dst2 {
+            return pair_move(self, &srcs, &dst, &arg(dst2)?);
+        }
+        match jump {
+            Some((cond, offset)) => jump_to(self, cond, offset, &srcs, &dst),
+            None => select(self, &srcs, &dst),
+        }
+    }
+}
+
+pub fn register(name: &str) -> Option<u8> {
+    let name = name.to_ascii_lowercase();
+    match name.as_str() {
+        "ip" => return Some(0x8),
+        "sp" => return Some(0x9),
+        "rp" => return Some(0xa),
+        "tr" => return Some(0xb),
+        "ix" => return Some(0xc),
+        _ => {}
+    }
+    let n: u8 = name.strip_prefix('r')?.parse().ok()?;
+    if n < 16 {
+        Some(n)
+    } else {
+        None
+    }
+}
+
+/// A `.macro` definition, kept as lexemes until it's expanded.
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Lexed>,
+    locals: Vec<String>, // labels defined in the body
+    span: Span,
+}
+
+/// A `.equ`/`.set` constant, or a `-D` define.
+struct Constant {
+    value: i64,
+    fixed: bool,        // set by `.equ` or `-D`, so it can't be changed
+    span: Option<Span>, // None for `-D`
+}
+
+/// One `.if` block being parsed.
+struct Cond {
+    active: bool,    // whether the current branch is being assembled
+    taken: bool,     // whether any branch so far has been (or can't be)
+    parent: bool,    // whether the enclosing block is active
+    seen_else: bool, // whether `.else` has come yet
+    span: Span,
+}
+
+/// The `.func` being parsed.
+struct Func {
+    scope: Scope,     // the parameters and the named return value
+    start: usize,     // index of the first token of the body
+    used: [bool; 16], // registers the body names
+    span: Span,
+}
+
+/// How deep macros can expand inside other macros.
+const MACRO_DEPTH: usize = 64;
+
+pub struct Parser<'a> {
+    lexemes: Vec<Lexed>,
+    pos: usize,
+    macros: HashMap<String, Macro>,
+    expansions: Vec<(String, Span)>, // macros being expanded, innermost last
+    expanded: usize,                 // expansions so far, to name their local labels
+    sources: &'a mut SourceMap,
+    include_paths: &'a [PathBuf],
+    root: PathBuf,
+    includes: Vec<(PathBuf, Span)>, // files being included and where from, innermost last
+    constants: HashMap<String, Constant>,
+    conds: Vec<Cond>, // open `.if` blocks, innermost last
+    func: Option<Func>,
+    arity: HashMap<String, (usize, Span)>, // parameter counts of `.func`s so far
+    register_values: bool,                 // registers in expressions stand for their values
+}
+impl<'a> Parser<'a> {
+    /// Parser for `lexemes`, which were lexed from file `file` of `sources`.
+    pub fn new(
+        lexemes: Vec<Lexed>,
+        sources: &'a mut SourceMap,
+        include_paths: &'a [PathBuf],
+        defines: &[(String, i64)],
+        file: usize,
+    ) -> Self {
+        let root = canonical(Path::new(sources.path(file)));
+        let constants = defines
+            .iter()
+            .map(|(name, value)| {
+                let c = Constant {
+                    value: *value,
+                    fixed: true,
+                    span: None,
+                };
+                (name.clone(), c)
+            })
+            .collect();
+        Parser {
+            lexemes,
+            pos: 0,
+            macros: HashMap::new(),
+            expansions: vec![],
+            expanded: 0,
+            sources,
+            include_paths,
+            root,
+            includes: vec![],
+            constants,
+            conds: vec![],
+            func: None,
+            arity: HashMap::new(),
+            register_values: false,
+        }
+    }
+    /// Parses the lexemes as a single expression in which registers stand for
+    /// their values, by their plain names (`r0`, `sp`, ...).
+    pub fn register_expr(mut self) -> Result<(Expr, Span), Diagnostic> {
+        self.register_values = true;
+        let e = self.expr()?;
+        self.expect(&Lexeme::Newline, "the end of the condition")?;
+        Ok(e)
+    }
+    /// Parses every statement, reporting bad ones to `diags` and carrying on
+    /// from the next line.
+    pub fn parse(mut self, diags: &mut Vec<Diagnostic>) -> Vec<Token> {
+        let mut tokens = vec![];
+        while self.pos < self.lexemes.len() {
+            if self.eat(&Lexeme::Newline) {
+                continue;
+            }
+            if self.eat(&Lexeme::EndExpansion) {
+                self.expansions.pop();
+                continue;
+            }
+            if self.eat(&Lexeme::EndInclude) {
+                self.includes.pop();
+                continue;
+            }
+            if let Lexeme::Directive(name) = self.peek().clone() {
+                if ["if", "ifdef", "ifndef", "elif", "else", "endif"].contains(&name.as_str()) {
+                    if let Err(d) = self.conditional(&name) {
+                        self.report(diags, d);
+                        self.skip_line();
+                    }
+                    continue;
+                }
+            }
+            if !self.conds.last().is_none_or(|c| c.active) {
+                self.skip_line();
+                continue;
+            }
+            if let Some((name, span)) = self.label_def() {
+                tokens.push(Token::Label(name, span));
+                continue;
+            }
+            let line = match self.peek().clone() {
+                Lexeme::Directive(name) if name == "macro" => {
+                    if let Err(d) = self.macro_def() {
+                        self.report(diags, d);
+                    }
+                    continue;
+                }
+                Lexeme::Directive(name) if name == "include" => self.include(diags).map(|()| None),
+                Lexeme::Directive(name) if name == "func" => {
+                    // the body starts after the label
+                    let start = tokens.len() + 1;
+                    self.func_def(start)
+                        .map(|(label, ls)| Some(Token::Label(label, ls)))
+                }
+                Lexeme::Directive(name) if name == "endfunc" => {
+                    self.end_func(&mut tokens).map(|()| None)
+                }
+                Lexeme::Ident(name)
+                    if name.eq_ignore_ascii_case("CALL") && self.call_args_follow() =>
+                {
+                    match self.call_with_args() {
+                        Ok(call) => {
+                            tokens.extend(call);
+                            continue;
+                        }
+                        Err(d) => Err(d),
+                    }
+                }
+                Lexeme::Directive(name) => self.directive(&name),
+                Lexeme::Ident(name) if self.macros.contains_key(&name.to_ascii_uppercase()) => {
+                    self.expand().map(|()| None)
+                }
+                _ => match self.statement() {
+                    Ok(stmt) => {
+                        if let Some(f) = &mut self.func {
+                            for r in stmt.registers() {
+                                f.used[r as usize] = true;
+                            }
+                        }
+                        match token(stmt) {
+                            Ok(token) => tokens.push(token),
+                            Err(d) => self.report(diags, d),
+                        }
+                        continue;
+                    }
+                    Err(d) => Err(d),
+                },
+            };
+            match line {
+                Ok(Some(token)) => tokens.push(token),
+                Ok(None) => {}
+                Err(d) => {
+                    self.report(diags, d);
+                    self.skip_line();
+                }
+            }
+        }
+        for cond in &self.conds {
+            diags.push(Diagnostic::error(
+                ErrorCode::Unterminated,
+                "`.if` without a matching `.endif`",
+                cond.span,
+            ));
+        }
+        if let Some(f) = &self.func {
+            diags.push(Diagnostic::error(
+                ErrorCode::Unterminated,
+                format!("`.func {}` without a matching `.endfunc`", f.scope.name),
+                f.span,
+            ));
+        }
+        tokens
+    }
+    /// Records `d`, noting which macro expansions it came from. Only the
+    /// innermost and outermost are shown, recursion would bury the error.
+    fn report(&self, diags: &mut Vec<Diagnostic>, mut d: Diagnostic) {
+        let n = self.expansions.len();
+        for (ix, (name, span)) in self.expansions.iter().enumerate().rev() {
+            if ix == n - 1 || ix == 0 {
+                d = d.note_at(format!("in this expansion of `{}`", name), *span);
+            } else if ix == n - 2 {
+                d = d.note(format!("{} more expansions in between", n - 2));
+            }
+        }
+        diags.push(d);
+    }
+    fn skip_line(&mut self) {
+        while !self.eat(&Lexeme::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> &Lexeme {
+        &self.lexemes[self.pos].lexeme
+    }
+    fn span(&self) -> Span {
+        self.lexemes[self.pos].span
+    }
+    /// Span of the lexeme just consumed.
+    fn prev_span(&self) -> Span {
+        self.lexemes[self.pos - 1].span
+    }
+    /// Consumes a `name:` label definition if one starts here.
+    fn label_def(&mut self) -> Option<(String, Span)> {
+        match (
+            self.peek(),
+            self.lexemes.get(self.pos + 1).map(|l| &l.lexeme),
+        ) {
+            (Lexeme::Ident(name), Some(Lexeme::Colon)) => {
+                let name = name.clone();
+                let span = self.span();
+                self.pos += 2;
+                Some((name, span))
+            }
+            _ => None,
+        }
+    }
+    fn eat(&mut self, l: &Lexeme) -> bool {
+        if self.peek() == l {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+    fn expect(&mut self, l: &Lexeme, what: &str) -> Result<(), Diagnostic> {
+        if self.eat(l) {
+            return Ok(());
+        }
+        Err(self.error(format!("expected {}", what)))
+    }
+    fn error(&self, msg: String) -> Diagnostic {
+        Diagnostic::error(
+            ErrorCode::Syntax,
+            format!("{}, found {}", msg, self.peek()),
+            self.span(),
+        )
+    }
+    /// Handles `.if`, `.ifdef`, `.ifndef`, `.elif`, `.else` and `.endif`.
+    /// Nothing in a block that's being skipped is evaluated, and a condition
+    /// with an error skips its whole block.
+    fn conditional(&mut self, name: &str) -> Result<(), Diagnostic> {
+        let span = self.span();
+        self.pos += 1;
+        let active = self.conds.last().is_none_or(|c| c.active);
+        let unmatched = |what: &str| {
+            Diagnostic::error(
+                ErrorCode::Syntax,
+                format!("`.{}` without an `.if`", what),
+                span,
+            )
+        };
+        match name {
+            "if" | "ifdef" | "ifndef" => {
+                if !active {
+                    self.conds.push(Cond {
+                        active: false,
+                        taken: true,
+                        parent: false,
+                        seen_else: false,
+                        span,
+                    });
+                    self.skip_line();
+                    return Ok(());
+                }
+                let cond = self.condition(name);
+                let on = matches!(cond, Ok(true));
+                self.conds.push(Cond {
+                    active: on,
+                    taken: on || cond.is_err(),
+                    parent: true,
+                    seen_else: false,
+                    span,
+                });
+                cond?;
+            }
+            "elif" => {
+                let top = self.conds.last_mut().ok_or_else(|| unmatched(name))?;
+                if top.seen_else {
+                    return Err(Diagnostic::error(
+                        ErrorCode::Syntax,
+                        "`.elif` after `.else`",
+                        span,
+                    )
+                    .note_at("the block starts here", top.span));
+                }
+                if top.taken {
+                    top.active = false;
+                    self.skip_line();
+                    return Ok(());
+                }
+                let cond = self.condition("if");
+                let top = self.conds.last_mut().unwrap();
+                top.active = matches!(cond, Ok(true));
+                top.taken = top.active || cond.is_err();
+                cond?;
+            }
+            "else" => {
+                let top = self.conds.last_mut().ok_or_else(|| unmatched(name))?;
+                if top.seen_else {
+                    return Err(Diagnostic::error(
+                        ErrorCode::Syntax,
+                        "`.else` after `.else`",
+                        span,
+                    )
+                    .note_at("the block starts here", top.span));
+                }
+                top.active = top.parent && !top.taken;
+                top.taken = true;
+                top.seen_else = true;
+            }
+            _ => {
+                self.conds.pop().ok_or_else(|| unmatched(name))?;
+            }
+        }
+        self.expect(&Lexeme::Newline, "end of line")
+    }
+    /// Evaluates the condition of an `.if`, `.ifdef` or `.ifndef`.
+    fn condition(&mut self, kind: &str) -> Result<bool, Diagnostic> {
+        if kind == "if" {
+            return Ok(self.known_expr()?.0 != 0);
+        }
+        let name = match self.peek() {
+            Lexeme::Ident(name) => name.clone(),
+            _ => return Err(self.error("expected a name".to_string())),
+        };
+        self.pos += 1;
+        let defined = self.constants.contains_key(&name)
+            || self.macros.contains_key(&name.to_ascii_uppercase());
+        Ok(defined == (kind == "ifdef"))
+    }
+    /// Records a `.macro` definition. This leaves `pos` after the `.endm`
+    /// line even when the definition is bad, so the body isn't parsed as code.
+    fn macro_def(&mut self) -> Result<(), Diagnostic> {
+        let span = self.span();
+        self.pos += 1;
+        let header = self.macro_header();
+        self.skip_line();
+        let body_start = self.pos;
+        let mut nested = None;
+        loop {
+            if self.pos >= self.lexemes.len() {
+                return Err(Diagnostic::error(
+                    ErrorCode::Unterminated,
+                    "`.macro` without a matching `.endm`",
+                    span,
+                ));
+            }
+            match self.peek() {
+                Lexeme::EndInclude => {
+                    return Err(Diagnostic::error(
+                        ErrorCode::Unterminated,
+                        "`.macro` without a matching `.endm` in the same file",
+                        span,
+                    ))
+                }
+                Lexeme::Directive(d) if d == "endm" => break,
+                Lexeme::Directive(d) if d == "macro" => nested = nested.or(Some(self.span())),
+                _ => {}
+            }
+            self.skip_line();
+        }
+        let body = self.lexemes[body_start..self.pos].to_vec();
+        self.skip_line();
+        if let Some(ns) = nested {
+            return Err(Diagnostic::error(
+                ErrorCode::Syntax,
+                "macros can't be defined inside other macros",
+                ns,
+            ));
+        }
+        let (name, params) = header?;
+        if let Some(first) = self.macros.get(&name) {
+            return Err(Diagnostic::error(
+                ErrorCode::DuplicateMacro,
+                format!("macro `{}` is defined more than once", name),
+                span,
+            )
+            .note_at("first defined here", first.span));
+        }
+        let mut locals = vec![];
+        for (ix, l) in body.iter().enumerate() {
+            let line_start = ix == 0 || body[ix - 1].lexeme == Lexeme::Newline;
+            if let (true, Lexeme::Ident(label), Some(Lexeme::Colon)) =
+                (line_start, &l.lexeme, body.get(ix + 1).map(|l| &l.lexeme))
+            {
+                locals.push(label.clone());
+            }
+        }
+        self.macros.insert(
+            name,
+            Macro {
+                params,
+                body,
+                locals,
+                span,
+            },
+        );
+        Ok(())
+    }
+    /// `NAME param, param, ...`, stopping at the end of the line.
+    fn macro_header(&mut self) -> Result<(String, Vec<String>), Diagnostic> {
+        let name = match self.peek() {
+            Lexeme::Ident(name) => name.to_ascii_uppercase(),
+            _ => return Err(self.error("expected a macro name".to_string())),
+        };
+        self.pos += 1;
+        let mut params = vec![];
+        while let Lexeme::Ident(param) = self.peek().clone() {
+            self.pos += 1;
+            params.push(param);
+            if !self.eat(&Lexeme::Comma) {
+                break;
+            }
+        }
+        if self.peek() != &Lexeme::Newline {
+            return Err(self.error("expected a parameter name".to_string()));
+        }
+        Ok((name, params))
+    }
+    /// Replaces a macro invocation with the macro's body, with arguments put
+    /// in place of parameters and the body's labels renamed so every
+    /// expansion gets its own.
+    fn expand(&mut self) -> Result<(), Diagnostic> {
+        let start = self.span();
+        let name = match self.peek() {
+            Lexeme::Ident(name) => name.to_ascii_uppercase(),
+            _ => unreachable!(),
+        };
+        self.pos += 1;
+        // arguments are split on commas that aren't inside brackets
+        let mut args: Vec<Vec<Lexed>> = vec![];
+        if self.peek() != &Lexeme::Newline {
+            let mut arg = vec![];
+            let mut depth = 0;
+            loop {
+                match self.peek() {
+                    Lexeme::Newline => break,
+                    Lexeme::Comma if depth == 0 => {
+                        args.push(std::mem::take(&mut arg));
+                        self.pos += 1;
+                        continue;
+                    }
+                    Lexeme::LParen | Lexeme::LBracket => depth += 1,
+                    Lexeme::RParen | Lexeme::RBracket => depth -= 1,
+                    _ => {}
+                }
+                arg.push(self.lexemes[self.pos].clone());
+                self.pos += 1;
+            }
+            args.push(arg);
+        }
+        let span = start.to(self.prev_span());
+
+        let m = &self.macros[&name];
+        if args.len() != m.params.len() {
+            return Err(Diagnostic::error(
+                ErrorCode::InvalidOperands,
+                format!(
+                    "macro `{}` takes {} argument(s) but {} were given",
+                    name,
+                    m.params.len(),
+                    args.len()
+                ),
+                span,
+            )
+            .note_at("macro defined here", m.span));
+        }
+        if self.expansions.len() >= MACRO_DEPTH {
+            return Err(Diagnostic::error(
+                ErrorCode::MacroRecursion,
+                format!(
+                    "macro `{}` is expanded more than {} levels deep",
+                    name, MACRO_DEPTH
+                ),
+                span,
+            )
+            .help("a macro that uses itself needs an `.if` that stops it"));
+        }
+        let n = self.expanded + 1;
+        let mut body = vec![];
+        for l in &m.body {
+            if let Lexeme::Ident(id) = &l.lexeme {
+                if let Some(ix) = m.params.iter().position(|p| p == id) {
+                    body.extend(args[ix].iter().cloned());
+                    continue;
+                }
+                if m.locals.contains(id) {
+                    body.push(Lexed {
+                        lexeme: Lexeme::Ident(format!("{}.{}", id, n)),
+                        span: l.span,
+                    });
+                    continue;
+                }
+            }
+            body.push(l.clone());
+        }
+        body.push(Lexed {
+            lexeme: Lexeme::EndExpansion,
+            span,
+        });
+        self.expanded = n;
+        self.pos += 1; // the newline
+        self.lexemes.splice(self.pos..self.pos, body);
+        self.expansions.push((name, span));
+        Ok(())
+    }
+
+    /// Replaces an `.include` with the lexemes of the file it names.
+    fn include(&mut self, diags: &mut Vec<Diagnostic>) -> Result<(), Diagnostic> {
+        let start = self.span();
+        self.pos += 1;
+        let name = self.string()?;
+        let span = start.to(self.prev_span());
+        if self.peek() != &Lexeme::Newline {
+            return Err(self.error("expected end of line".to_string()));
+        }
+        let (found, path, bytes) = self.load(&name, span)?;
+        if path == self.root || self.includes.iter().any(|(p, _)| *p == path) {
+            let mut d = Diagnostic::error(
+                ErrorCode::IncludeCycle,
+                format!("`{}` includes itself", found),
+                span,
+            );
+            for (_, from) in self.includes.iter().rev() {
+                d = d.note_at("which is included from here", *from);
+            }
+            return Err(d);
+        }
+        let text = String::from_utf8(bytes).map_err(|_| {
+            Diagnostic::error(
+                ErrorCode::Io,
+                format!("`{}` isn't valid UTF-8 text", found),
+                span,
+            )
+            .help("use `.incbin` to include binary data")
+        })?;
+        let file = self.sources.add(&found, &text);
+        let mut lexemes = lexer::lex(&text, file, diags);
+        lexemes.push(Lexed {
+            lexeme: Lexeme::EndInclude,
+            span,
+        });
+        self.pos += 1; // the newline
+        self.lexemes.splice(self.pos..self.pos, lexemes);
+        self.includes.push((path, span));
+        Ok(())
+    }
+    /// Finds `name` next to the file `span` is in, or else in one of the
+    /// include paths, and reads it. Returns the path it was found at, that
+    /// path made canonical, and the contents.
+    fn load(&self, name: &[u8], span: Span) -> Result<(String, PathBuf, Vec<u8>), Diagnostic> {
+        let name = String::from_utf8_lossy(name).to_string();
+        let here = Path::new(self.sources.path(span.file))
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        let dirs: Vec<PathBuf> = std::iter::once(here)
+            .chain(self.include_paths.iter().cloned())
+            .collect();
+        for dir in &dirs {
+            let path = dir.join(&name);
+            if !path.is_file() {
+                continue;
+            }
+            return match std::fs::read(&path) {
+                Ok(bytes) => Ok((path.display().to_string(), canonical(&path), bytes)),
+                Err(e) => Err(Diagnostic::error(
+                    ErrorCode::Io,
+                    format!("couldn't read `{}`: {}", path.display(), e),
+                    span,
+                )),
+            };
+        }
+        let searched: Vec<String> = dirs
+            .iter()
+            .map(|d| match d.as_os_str().is_empty() {
+                true => "`.`".to_string(),
+                false => format!("`{}`", d.display()),
+            })
+            .collect();
+        Err(
+            Diagnostic::error(ErrorCode::Io, format!("couldn't find `{}`", name), span)
+                .note(format!("looked in {}", searched.join(", "))),
+        )
+    }
+
+    /// Parses a whole directive line, up to and including the newline.
+    fn directive(&mut self, name: &str) -> Result<Option<Token>, Diagnostic> {
+        let span = self.span();
+        self.pos += 1;
+        let token = match name {
+            "byte" | "word" | "dword" => {
+                let width = match name {
+                    "byte" => 1,
+                    "word" => 2,
+                    _ => 4,
+                };
+                let mut values = vec![self.expr()?];
+                while self.eat(&Lexeme::Comma) {
+                    values.push(self.expr()?);
+                }
+                if values.iter().any(|(e, _)| !e.is_const()) {
+                    Token::Data(width, values)
+                } else {
+                    let max = u32::MAX as u64 >> (32 - 8 * width);
+                    let mut bytes = vec![];
+                    for (e, es) in &values {
+                        let n = e.value(&Symbols::new(), *es, max)?;
+                        bytes.extend(&n.to_le_bytes()[..width as usize]);
+                    }
+                    Token::Bytes(bytes, span)
+                }
+            }
+            "ascii" | "asciz" => {
+                let mut bytes = self.string()?;
+                while self.eat(&Lexeme::Comma) {
+                    bytes.extend(self.string()?);
+                }
+                if name == "asciz" {
+                    bytes.push(0);
+                }
+                Token::Bytes(bytes, span)
+            }
+            "org" => {
+                let (target, ts) = self.const_expr(0xffff_ffff)?;
+                Token::Org(image_size(target, ts, "`.org`")?, span)
+            }
+            "align" => {
+                let (n, ns) = self.const_expr(0xffff_ffff)?;
+                if n == 0 {
+                    return Err(Diagnostic::error(
+                        ErrorCode::InvalidOperands,
+                        "can't align to 0 bytes",
+                        ns,
+                    ));
+                }
+                Token::Align(image_size(n, ns, "`.align`")?, span)
+            }
+            "fill" => {
+                // .fill count[, size[, value]], the same as GNU as
+                let (count, cs) = self.const_expr(0xffff_ffff)?;
+                let mut width = 1;
+                let mut value = 0;
+                if self.eat(&Lexeme::Comma) {
+                    let ws;
+                    (width, ws) = self.const_expr(0xff)?;
+                    if ![1, 2, 4].contains(&width) {
+                        return Err(Diagnostic::error(
+                            ErrorCode::InvalidOperands,
+                            format!("can't fill with {} byte values", width),
+                            ws,
+                        )
+                        .help("the size has to be 1, 2 or 4"));
+                    }
+                    if self.eat(&Lexeme::Comma) {
+                        value = self.const_expr(u32::MAX as u64 >> (32 - 8 * width))?.0;
+                    }
+                }
+                image_size(count * width, cs, "`.fill`")?;
+                let one = value.to_le_bytes()[..width as usize].to_vec();
+                Token::Fill(one, count as u32, span)
+            }
+            "incbin" => {
+                // .incbin "file"[, skip[, count]], the same as GNU as
+                let name = self.string()?;
+                let ns = span.to(self.prev_span());
+                let mut skip = 0;
+                let mut count = None;
+                if self.eat(&Lexeme::Comma) {
+                    skip = self.const_expr(0xffff_ffff)?.0 as usize;
+                    if self.eat(&Lexeme::Comma) {
+                        count = Some(self.const_expr(0xffff_ffff)?.0 as usize);
+                    }
+                }
+                let (found, _, bytes) = self.load(&name, ns)?;
+                let end = count.map_or(bytes.len(), |c| skip.saturating_add(c));
+                match bytes.get(skip..end) {
+                    Some(bytes) => Token::Bytes(bytes.to_vec(), span),
+                    None => {
+                        return Err(Diagnostic::error(
+                            ErrorCode::ValueOutOfRange,
+                            format!("`{}` is only {} bytes long", found, bytes.len()),
+                            ns,
+                        ))
+                    }
+                }
+            }
+            "equ" | "set" => {
+                let (constant, cs) = match self.peek() {
+                    Lexeme::Ident(c) if register(c).is_none() => (c.clone(), self.span()),
+                    _ => return Err(self.error("expected a constant name".to_string())),
+                };
+                self.pos += 1;
+                self.expect(&Lexeme::Comma, "`,`")?;
+                let (value, _) = self.known_expr()?;
+                if let Some(old) = self.constants.get(&constant) {
+                    if old.fixed || name == "equ" {
+                        let d = Diagnostic::error(
+                            ErrorCode::DuplicateConstant,
+                            format!("constant `{}` is already defined", constant),
+                            cs,
+                        );
+                        return Err(match old.span {
+                            Some(first) => d.note_at("first defined here", first),
+                            None => d.note("it was defined on the command line with -D"),
+                        }
+                        .help("use `.set` for constants that change"));
+                    }
+                }
+                let c = Constant {
+                    value,
+                    fixed: name == "equ",
+                    span: Some(cs),
+                };
+                self.expect(&Lexeme::Newline, "end of line")?;
+                self.constants.insert(constant, c);
+                return Ok(None);
+            }
+            "endm" => {
+                return Err(Diagnostic::error(
+                    ErrorCode::Syntax,
+                    "`.endm` without a `.macro`",
+                    span,
+                ))
+            }
+            _ => {
+                return Err(Diagnostic::error(
+                    ErrorCode::UnknownDirective,
+                    format!("unknown directive `.{}`", name),
+                    span,
+                ))
+            }
+        };
+        self.expect(&Lexeme::Newline, "end of line")?;
+        Ok(Some(token))
+    }
+    /// An expression that has to be known while parsing, like an `.org`
+    /// address or an `.if` condition. Only constants can be used in it.
+    fn known_expr(&mut self) -> Result<(i64, Span), Diagnostic> {
+        let (e, span) = self.expr()?;
+        let mut labels = vec![];
+        e.labels(&mut labels);
+        if let Some((name, ls)) = labels.first() {
+            return Err(Diagnostic::error(
+                ErrorCode::InvalidOperands,
+                format!("`{}` isn't a constant defined before this point", name),
+                *ls,
+            )
+            .note("labels can't be used here, their addresses aren't known until the code is laid out"));
+        }
+        Ok((e.eval(&Symbols::new(), span)?, span))
+    }
+    fn const_expr(&mut self, max: u64) -> Result<(u64, Span), Diagnostic> {
+        let (n, span) = self.known_expr()?;
+        Ok((fit(n, max, span)?, span))
+    }
+    fn string(&mut self) -> Result<Vec<u8>, Diagnostic> {
+        match self.peek().clone() {
+            Lexeme::Str(bytes) => {
+                self.pos += 1;
+                Ok(bytes)
+            }
+            _ => Err(self.error("expected a string".to_string())),
+        }
+    }
+
+    /// The register `name` stands for, counting the parameter and return
+    /// value names of the `.func` being parsed.
+    fn register(&self, name: &str) -> Option<u8> {
+        if let Some(r) = self.func.as_ref().and_then(|f| f.scope.register(name)) {
+            return Some(r);
+        }
+        register(name)
+    }
+    /// Starts a `.func name(params) -> ret` block whose body starts at token
+    /// `start`, returning the label it defines.
+    fn func_def(&mut self, start: usize) -> Result<(String, Span), Diagnostic> {
+        let span = self.span();
+        self.pos += 1;
+        if let Some(f) = &self.func {
+            return Err(Diagnostic::error(
+                ErrorCode::Syntax,
+                "functions can't be defined inside other functions",
+                span,
+            )
+            .note_at(format!("`{}` starts here", f.scope.name), f.span));
+        }
+        let (name, ns) = self.func_name()?;
+        self.expect(&Lexeme::LParen, "`(`")?;
+        let mut params: Vec<String> = vec![];
+        while let Lexeme::Ident(_) = self.peek() {
+            let (param, ps) = self.func_name()?;
+            if params.contains(&param) {
+                return Err(Diagnostic::error(
+                    ErrorCode::Syntax,
+                    format!("parameter `{}` is named twice", param),
+                    ps,
+                ));
+            }
+            params.push(param);
+            if !self.eat(&Lexeme::Comma) {
+                break;
+            }
+        }
+        self.expect(&Lexeme::RParen, "`)` or a parameter name")?;
+        let signature = span.to(self.prev_span());
+        let ret = match self.eat(&Lexeme::Arrow) {
+            true => Some(self.func_name()?.0),
+            false => None,
+        };
+        let scope = match Scope::func(&name, &params, ret.as_deref()) {
+            Some(scope) => scope,
+            None => {
+                return Err(Diagnostic::error(
+                    ErrorCode::InvalidOperands,
+                    format!(
+                        "`{}` has {} parameters, but only {} can be passed",
+                        name,
+                        params.len(),
+                        ARG_REGS.len()
+                    ),
+                    signature,
+                )
+                .help("pass a pointer to the rest in memory"))
+            }
+        };
+        self.expect(&Lexeme::Newline, "end of line")?;
+        self.arity.insert(name.clone(), (params.len(), ns));
+        self.func = Some(Func {
+            scope,
+            start,
+            used: [false; 16],
+            span,
+        });
+        Ok((name, ns))
+    }
+    /// A function or parameter name, which can't be a register.
+    fn func_name(&mut self) -> Result<(String, Span), Diagnostic> {
+        match self.peek().clone() {
+            Lexeme::Ident(name) if register(&name).is_none() => {
+                self.pos += 1;
+                Ok((name, self.prev_span()))
+            }
+            _ => Err(self.error("expected a name".to_string())),
+        }
+    }
+    /// Ends the `.func` block, putting the prologue at its start and the
+    /// epilogue before every `RET` in it. The prologue saves the callee
+    /// saved registers the body uses, and a body that doesn't end in `RET`
+    /// gets one.
+    fn end_func(&mut self, tokens: &mut Vec<Token>) -> Result<(), Diagnostic> {
+        let span = self.span();
+        self.pos += 1;
+        if self.peek() != &Lexeme::Newline {
+            return Err(self.error("expected end of line".to_string()));
+        }
+        let f = match self.func.take() {
+            Some(f) => f,
+            None => {
+                return Err(Diagnostic::error(
+                    ErrorCode::Syntax,
+                    "`.endfunc` without a `.func`",
+                    span,
+                ))
+            }
+        };
+        let saved: Vec<u8> = CALLEE_SAVED
+            .into_iter()
+            .filter(|r| f.used[*r as usize])
+            .collect();
+        let epilogue = |span: Span| -> Vec<Token> {
+            let mut out: Vec<Token> = saved
+                .iter()
+                .rev()
+                .map(|r| Token::Instruction(Instruction::Pop32(*r), span))
+                .collect();
+            out.push(Token::Instruction(Instruction::Ret, span));
+            out
+        };
+        let body = tokens.split_off(f.start);
+        let ends_in_ret = matches!(body.last(), Some(Token::Instruction(Instruction::Ret, _)));
+        for r in &saved {
+            tokens.push(Token::Instruction(Instruction::Push32(*r), f.span));
+        }
+        for token in body {
+            match token {
+                Token::Instruction(Instruction::Ret, rs) => tokens.extend(epilogue(rs)),
+                token => tokens.push(token),
+            }
+        }
+        if !ends_in_ret {
+            tokens.extend(epilogue(span));
+        }
+        Ok(())
+    }
+    /// Whether a `CALL` is followed by `name(`, rather than a plain target.
+    fn call_args_follow(&self) -> bool {
+        let next = |n: usize| self.lexemes.get(self.pos + n).map(|l| &l.lexeme);
+        matches!(
+            (next(1), next(2)),
+            (Some(Lexeme::Ident(_)), Some(Lexeme::LParen))
+        )
+    }
+    /// `CALL name(arg, ...)`: puts each argument, a register or a value, in
+    /// its argument register and calls `name`. Registers are moved before
+    /// values are loaded, and moves that would overwrite each other's
+    /// sources go through `tr`.
+    fn call_with_args(&mut self) -> Result<Vec<Token>, Diagnostic> {
+        let start = self.span();
+        self.pos += 1;
+        let (name, ns) = match self.peek().clone() {
+            Lexeme::Ident(name) => (name, self.span()),
+            _ => unreachable!(),
+        };
+        self.pos += 2;
+        let mut args = vec![];
+        if self.peek() != &Lexeme::RParen {
+            args.push(self.operand()?);
+            while self.eat(&Lexeme::Comma) {
+                args.push(self.operand()?);
+            }
+        }
+        self.expect(&Lexeme::RParen, "`)`")?;
+        let span = start.to(self.prev_span());
+        // the newline is left for the caller, so errors skip just this line
+        if self.peek() != &Lexeme::Newline {
+            return Err(self.error("expected end of line".to_string()));
+        }
+        if let Some((n, fs)) = self.arity.get(&name) {
+            if *n != args.len() {
+                return Err(Diagnostic::error(
+                    ErrorCode::InvalidOperands,
+                    format!(
+                        "`{}` takes {} argument(s) but {} were given",
+                        name,
+                        n,
+                        args.len()
+                    ),
+                    span,
+                )
+                .note_at("function defined here", *fs));
+            }
+        }
+        if args.len() > ARG_REGS.len() {
+            return Err(Diagnostic::error(
+                ErrorCode::InvalidOperands,
+                format!("only {} arguments can be passed", ARG_REGS.len()),
+                span,
+            ));
+        }
+
+        let mut moves = vec![];
+        let mut values = vec![];
+        for (arg, r) in args.into_iter().zip(ARG_REGS) {
+            match arg {
+                Operand::Reg(a) if a != r => moves.push((a, r)),
+                Operand::Reg(_) => {}
+                Operand::Imm(e, es) => values.push((e, es, r)),
+                _ => {
+                    return Err(Diagnostic::error(
+                        ErrorCode::InvalidOperands,
+                        "arguments have to be registers or values",
+                        span,
+                    ))
+                }
+            }
+        }
+        let mut out = vec![];
+        while !moves.is_empty() {
+            let free = moves
+                .iter()
+                .position(|(_, d)| !moves.iter().any(|(s, _)| s == d));
+            match free {
+                Some(ix) => {
+                    let (a, d) = moves.remove(ix);
+                    out.push(Token::Instruction(Instruction::MovRR(a, d), span));
+                }
+                None => {
+                    // every move left is part of a cycle, park one source
+                    let a = moves[0].0;
+                    out.push(Token::Instruction(Instruction::MovRR(a, SHUFFLE_REG), span));
+                    for m in &mut moves {
+                        if m.0 == a {
+                            m.0 = SHUFFLE_REG;
+                        }
+                    }
+                }
+            }
+        }
+        for (e, es, r) in values {
+            out.push(token(Statement {
+                mnemonic: "CONST".to_string(),
+                srcs: vec![Operand::Imm(e, es)],
+                dst: Some(Operand::Reg(r)),
+                dst2: None,
+                mnemonic_span: start,
+                span,
+            })?);
+        }
+        out.push(Token::Jump(
+            JumpCond::Call,
+            JumpKind::Any,
+            Expr::Label(name, ns),
+            span,
+        ));
+        Ok(out)
+    }
+
+    fn statement(&mut self) -> Result<Statement, Diagnostic> {
+        let mnemonic_span = self.span();
+        let mnemonic = match self.peek() {
+            Lexeme::Ident(m) => m.to_ascii_uppercase(),
+            _ => return Err(self.error("expected a mnemonic".to_string())),
+        };
+        self.pos += 1;
+
+        let mut srcs = vec![];
+        let mut dst = None;
+        let mut dst2 = None;
+        if !matches!(self.peek(), Lexeme::Newline | Lexeme::Arrow) {
+            srcs.push(self.operand()?);
+            while self.eat(&Lexeme::Comma) {
+                srcs.push(self.operand()?);
+            }
+        }
+        if self.eat(&Lexeme::Arrow) {
+            dst = Some(self.operand()?);
+            if self.eat(&Lexeme::Comma) {
+                dst2 = Some(self.operand()?);
+            }
+        }
+        let span = mnemonic_span.to(self.prev_span());
+        self.expect(&Lexeme::Newline, "end of line")?;
+        Ok(Statement {
+            mnemonic,
+            srcs,
+            dst,
+            dst2,
+            mnemonic_span,
+            span,
+        })
+    }
+
+    fn operand(&mut self) -> Result<Operand, Diagnostic> {
+        match self.peek().clone() {
+            Lexeme::Ident(name) => {
+                if let Some(r) = self.register(&name) {
+                    self.pos += 1;
+                    return Ok(Operand::Reg(r));
+                }
+                let next = self.lexemes.get(self.pos + 1).map(|l| &l.lexeme);
+                if name.eq_ignore_ascii_case("zp") && next == Some(&Lexeme::LBracket) {
+                    self.pos += 2;
+                    let (e, span) = self.expr()?;
+                    self.expect(&Lexeme::RBracket, "`]`")?;
+                    return Ok(Operand::Zp(e, span));
+                }
+            }
+            Lexeme::Plus | Lexeme::Minus => {
+                let (e, span) = self.expr()?;
+                return Ok(Operand::Offset(e, span));
+            }
+            Lexeme::LBracket => {
+                self.pos += 1;
+                let ptr = match self.peek().clone() {
+                    Lexeme::Ident(name) if self.register(&name).is_some() => {
+                        self.pos += 1;
+                        Operand::RPtr(self.register(&name).unwrap())
+                    }
+                    _ => {
+                        let (e, span) = self.expr()?;
+                        Operand::CPtr(e, span)
+                    }
+                };
+                self.expect(&Lexeme::RBracket, "`]`")?;
+                return Ok(ptr);
+            }
+            _ => {}
+        }
+        let (e, span) = self.expr()?;
+        Ok(Operand::Imm(e, span))
+    }
+
+    /// Parses an expression along with the span it covers.
+    fn expr(&mut self) -> Result<(Expr, Span), Diagnostic> {
+        let start = self.span();
+        let e = self.binary(0)?;
+        Ok((e, start.to(self.prev_span())))
+    }
+    /// Binary operators, loosest binding first, the same as C.
+    fn binary(&mut self, level: usize) -> Result<Expr, Diagnostic> {
+        if level == BINARY_LEVELS {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = binary_op(level, self.peek()) {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = op(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
+        let span = self.span();
+        match self.peek().clone() {
+            Lexeme::Minus => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.unary()?)))
+            }
+            Lexeme::Plus => {
+                self.pos += 1;
+                self.unary()
+            }
+            Lexeme::Tilde => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Lexeme::Bang => {
+                self.pos += 1;
+                Ok(Expr::LogicNot(Box::new(self.unary()?)))
+            }
+            Lexeme::LParen => {
+                self.pos += 1;
+                let e = self.binary(0)?;
+                self.expect(&Lexeme::RParen, "`)`")?;
+                Ok(e)
+            }
+            Lexeme::Int(n) => {
+                self.pos += 1;
+                match i64::try_from(n) {
+                    Ok(n) => Ok(Expr::Const(n)),
+                    Err(_) => Err(Diagnostic::error(
+                        ErrorCode::ValueOutOfRange,
+                        format!("0x{:x} is too large for an expression", n),
+                        span,
+                    )),
+                }
+            }
+            Lexeme::Ident(name) => {
+                if let (Some(r), true) = (self.register(&name), self.register_values) {
+                    self.pos += 1;
+                    return Ok(Expr::Label(crate::isa::reg(r), span));
+                }
+                let next = self.lexemes.get(self.pos + 1).map(|l| &l.lexeme);
+                let zp = name.eq_ignore_ascii_case("zp") && next == Some(&Lexeme::LBracket);
+                if zp && self.register_values {
+                    // zero page slots stand for their values too, by `zp[n]`
+                    self.pos += 2;
+                    let n = match self.peek() {
+                        Lexeme::Int(n) if *n < 0x100 => *n,
+                        _ => return Err(self.error("expected a zero page slot".to_string())),
+                    };
+                    self.pos += 1;
+                    self.expect(&Lexeme::RBracket, "`]`")?;
+                    return Ok(Expr::Label(format!("zp[{}]", n), span.to(self.prev_span())));
+                }
+                if self.register(&name).is_some() {
+                    return Err(Diagnostic::error(
+                        ErrorCode::InvalidExpression,
+                        format!("register `{}` can't be used in an expression", name),
+                        span,
+                    )
+                    .help("registers can only be used as operands on their own"));
+                }
+                self.pos += 1;
+                if let Some(c) = self.constants.get(&name) {
+                    return Ok(Expr::Const(c.value));
+                }
+                Ok(Expr::Label(name, span))
+            }
+            _ => Err(self.error("expected an expression".to_string())),
+        }
+    }
+}
+
+/// `path` with symlinks and `..` resolved, so the same file always
+/// compares equal.
+/// `n`, the bytes `what` needs, if the image can hold that many.
+fn image_size(n: u64, span: Span, what: &str) -> Result<u32, Diagnostic> {
+    if n > MAX_IMAGE as u64 {
+        return Err(Diagnostic::error(
+            ErrorCode::ValueOutOfRange,
+            format!(
+                "{} needs 0x{:x} bytes, more than the {} MiB image limit",
+                what,
+                n,
+                MAX_IMAGE >> 20
+            ),
+            span,
+        ));
+    }
+    Ok(n as u32)
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+const BINARY_LEVELS: usize = 10;
+
+type BinOp = fn(Box<Expr>, Box<Expr>) -> Expr;
+
+fn binary_op(level: usize, l: &Lexeme) -> Option<BinOp> {
+    Some(match (level, l) {
+        (0, Lexeme::OrOr) => Expr::LogicOr,
+        (1, Lexeme::AndAnd) => Expr::LogicAnd,
+        (2, Lexeme::Pipe) => Expr::Or,
+        (3, Lexeme::Caret) => Expr::Xor,
+        (4, Lexeme::Amp) => Expr::And,
+        (5, Lexeme::EqEq) => Expr::Eq,
+        (5, Lexeme::NotEq) => Expr::Ne,
+        (6, Lexeme::Lt) => Expr::Lt,
+        (6, Lexeme::Le) => Expr::Le,
+        (6, Lexeme::Gt) => Expr::Gt,
+        (6, Lexeme::Ge) => Expr::Ge,
+        (7, Lexeme::Shl) => Expr::Shl,
+        (7, Lexeme::Shr) => Expr::Shr,
+        (8, Lexeme::Plus) => Expr::Add,
+        (8, Lexeme::Minus) => Expr::Sub,
+        (9, Lexeme::Star) => Expr::Mul,
+        (9, Lexeme::Slash) => Expr::Div,
+        (9, Lexeme::Percent) => Expr::Rem,
+        _ => return None,
+    })
+}
+
+type Op3 = fn(u8, u8, u8) -> Instruction;
+type Op2 = fn(u8, u8) -> Instruction;
+type Op1 = fn(u8) -> Instruction;
+
+/// Register/zero page forms of a three operand MATH instruction, indexed
+/// as RRR, ZRR, RZR, RRZ, RZZ, ZZZ. The bool is set for commutative ops,
+/// which may swap their sources to reach a form the ISA has.
+fn math3(m: &str) -> Option<([Option<Op3>; 6], bool)> {
+    type I = Instruction;
+    Some(match m {
+        "ADD" => (
+            [
+                Some(I::AddRRR),
+                Some(I::AddZRR),
+                None,
+                Some(I::AddRRZ),
+                Some(I::AddRZZ),
+                Some(I::AddZZZ),
+            ],
+            true,
+        ),
+        "ADDC" => (
+            [
+                Some(I::AddcRRR),
+                Some(I::AddcZRR),
+                None,
+                Some(I::AddcRRZ),
+                Some(I::AddcRZZ),
+                Some(I::AddcZZZ),
+            ],
+            true,
+        ),
+        "SUB" => (
+            [
+                Some(I::SubRRR),
+                Some(I::SubZRR),
+                Some(I::SubRZR),
+                Some(I::SubRRZ),
+                Some(I::SubRZZ),
+                Some(I::SubZZZ),
+            ],
+            false,
+        ),
+        "SUBB" => (
+            [
+                Some(I::SubbRRR),
+                Some(I::SubbZRR),
+                Some(I::SubbRZR),
+                Some(I::SubbRRZ),
+                Some(I::SubbRZZ),
+                Some(I::SubbZZZ),
+            ],
+            false,
+        ),
+        "MUL" => (
+            [
+                Some(I::MulRRR),
+                Some(I::MulZRR),
+                None,
+                Some(I::MulRRZ),
+                Some(I::MulRZZ),
+                Some(I::MulZZZ),
+            ],
+            true,
+        ),
+        "DIV" => (
+            [
+                Some(I::DivRRR),
+                Some(I::DivZRR),
+                Some(I::DivRZR),
+                Some(I::DivRRZ),
+                Some(I::DivRZZ),
+                Some(I::DivZZZ),
+            ],
+            false,
+        ),
+        "AND" => (
+            [
+                Some(I::AndRRR),
+                Some(I::AndZRR),
+                None,
+                Some(I::AndRRZ),
+                Some(I::AndRZZ),
+                Some(I::AndZZZ),
+            ],
+            true,
+        ),
+        "OR" => (
+            [
+                Some(I::OrRRR),
+                Some(I::OrZRR),
+                None,
+                Some(I::OrRRZ),
+                Some(I::OrRZZ),
+                Some(I::OrZZZ),
+            ],
+            true,
+        ),
+        "XOR" => (
+            [
+                Some(I::XorRRR),
+                Some(I::XorZRR),
+                None,
+                Some(I::XorRRZ),
+                Some(I::XorRZZ),
+                Some(I::XorZZZ),
+            ],
+            true,
+        ),
+        "REM" => (
+            [
+                Some(I::RemRRR),
+                Some(I::RemZRR),
+                Some(I::RemRZR),
+                Some(I::RemRRZ),
+                Some(I::RemRZZ),
+                Some(I::RemZZZ),
+            ],
+            false,
+        ),
+        _ => return None,
+    })
+}
+
+/// Shift forms, indexed as RRR, ZRR, RCR, ZCR, RCZ, ZCZ.
+fn shift(m: &str) -> Option<[Op3; 6]> {
+    type I = Instruction;
+    Some(match m {
+        "SHL" => [
+            I::ShlRRR,
+            I::ShlZRR,
+            I::ShlRCR,
+            I::ShlZCR,
+            I::ShlRCZ,
+            I::ShlZCZ,
+        ],
+        "SHR" => [
+            I::ShrRRR,
+            I::ShrZRR,
+            I::ShrRCR,
+            I::ShrZCR,
+            I::ShrRCZ,
+            I::ShrZCZ,
+        ],
+        _ => return None,
+    })
+}
+
+fn single(m: &str) -> Option<Op1> {
+    type I = Instruction;
+    Some(match m {
+        "INC" => I::IncR1,
+        "INC4" => I::IncR4,
+        "DEC" => I::DecR1,
+        "DEC4" => I::DecR4,
+        "INT_MASK" => I::IntMask,
+        "FAULT_HANDLER" => I::FaultHandler,
+        "PUSH_8" => I::Push8,
+        "PUSH_16" => I::Push16,
+        "PUSH_32" => I::Push32,
+        "POP_8" => I::Pop8,
+        "POP_16" => I::Pop16,
+        "POP_32" => I::Pop32,
+        _ => return None,
+    })
+}
+
+/// Lowers `s` right away if it doesn't depend on any labels. Otherwise it
+/// has to wait until the layout is known.
+fn token(mut s: Statement) -> Result<Token, Diagnostic> {
+    let span = s.span;
+    if let Some((cond, offset)) = JumpCond::from_mnemonic(&s.mnemonic) {
+        let label_jump = matches!(
+            (&s.srcs[..], &s.dst),
+            ([Operand::Imm(e, _)], None) if !e.is_const()
+        );
+        if label_jump {
+            if let Some(Operand::Imm(e, es)) = s.srcs.pop() {
+                let kind = if offset {
+                    JumpKind::Relative
+                } else {
+                    JumpKind::Any
+                };
+                return Ok(Token::Jump(cond, kind, e, es));
+            }
+        }
+    }
+    if s.labels().is_empty() {
+        return Ok(Token::Instruction(s.lower(&Symbols::new())?, span));
+    }
+    Ok(Token::Pending(Box::new(s)))
+}
+
+fn jump_to(
+    s: &Statement,
+    cond: JumpCond,
+    offset: bool,
+    srcs: &[Arg],
+    dst: &Option<Arg>,
+) -> Result<Instruction, Diagnostic> {
+    match (srcs, dst, offset) {
+        ([Arg::Imm(dest, ds)], None, false) => {
+            let dest = fit(*dest, 0xffff_ffff, *ds)? as u32;
+            Ok(jump_instruction(cond, JumpForm::absolute(dest), dest))
+        }
+        ([Arg::Offset(ofs, os)], None, _) => {
+            let back = *ofs < 0;
+            let ofs = fit(ofs.checked_abs().unwrap_or(i64::MAX), 0xffff_ffff, *os)? as u32;
+            Ok(jump_instruction(cond, JumpForm::relative(back, ofs), ofs))
+        }
+        ([Arg::Reg(r)], None, false) if cond == JumpCond::Call => Ok(Instruction::CallR(*r)),
+        ([Arg::Imm(_, ds)], None, true) => Err(Diagnostic::error(
+            ErrorCode::InvalidOperands,
+            format!("{} needs a label or a signed offset", s.mnemonic),
+            *ds,
+        )
+        .help("write the offset as `+n` or `-n`")),
+        _ => Err(bad_operands(s)),
+    }
+}
+
+/// `MOVR4R4 a, b -> c, d` or `MOV4R4R a, b -> c, d`, which move `a` to `c`
+/// and `b` to `d`.
+fn pair_move(
+    s: &Statement,
+    srcs: &[Arg],
+    dst: &Option<Arg>,
+    dst2: &Arg,
+) -> Result<Instruction, Diagnostic> {
+    let (a, b, c, d) = match (srcs, dst, dst2) {
+        ([Arg::Reg(a), Arg::Reg(b)], Some(Arg::Reg(c)), Arg::Reg(d)) => (*a, *b, *c, *d),
+        _ => return Err(bad_operands(s)),
+    };
+    match s.mnemonic.as_str() {
+        "MOVR4R4" => Ok(Instruction::MovR4R4(b << 4 | a, (d as u16) << 8 | c as u16)),
+        "MOV4R4R" => Ok(Instruction::Mov4R4R((b as u16) << 8 | a as u16, d << 4 | c)),
+        _ => Err(bad_operands(s)),
+    }
+}
+
+fn bad_operands(s: &Statement) -> Diagnostic {
+    Diagnostic::error(
+        ErrorCode::InvalidOperands,
+        format!("invalid operands for {}", s.mnemonic),
+        s.span,
+    )
+}
+
+fn select(s: &Statement, srcs: &[Arg], dst: &Option<Arg>) -> Result<Instruction, Diagnostic> {
+    type I = Instruction;
+    type O = Arg;
+    let m = s.mnemonic.as_str();
+    let bad = || bad_operands(s);
+
+    if let Some(i) = Instruction::bare(m) {
+        if !srcs.is_empty() || dst.is_some() {
+            return Err(bad());
+        }
+        return Ok(i);
+    }
+    if let Some(op) = single(m) {
+        return match (srcs, dst) {
+            ([O::Reg(r)], None) => Ok(op(*r)),
+            _ => Err(bad()),
+        };
+    }
+    if let Some((forms, commutative)) = math3(m) {
+        let (a, b, d) = match (srcs, dst) {
+            ([a, b], Some(d)) => (a, b, d),
+            _ => return Err(bad()),
+        };
+        let (d_zp, dst) = match d {
+            O::Reg(r) => (false, *r),
+            O::Zp(r) => (true, *r),
+            _ => return Err(bad()),
+        };
+        let form = |a: &O, b: &O| -> Option<(Op3, u8, u8)> {
+            let (ix, a, b) = match (a, b, d_zp) {
+                (O::Reg(a), O::Reg(b), false) => (0, a, b),
+                (O::Zp(a), O::Reg(b), false) => (1, a, b),
+                (O::Reg(a), O::Zp(b), false) => (2, a, b),
+                (O::Reg(a), O::Reg(b), true) => (3, a, b),
+                (O::Reg(a), O::Zp(b), true) => (4, a, b),
+                (O::Zp(a), O::Zp(b), true) => (5, a, b),
+                _ => return None,
+            };
+            forms[ix].map(|f| (f, *a, *b))
+        };
+        if let Some((f, a, b)) = form(a, b) {
+            return Ok(f(a, b, dst));
+        }
+        if commutative {
+            if let Some((f, b, a)) = form(b, a) {
+                return Ok(f(b, a, dst));
+            }
+        }
+        return Err(bad());
+    }
+    if let Some(forms) = shift(m) {
+        return match (srcs, dst) {
+            ([O::Reg(a), O::Reg(b)], Some(O::Reg(d))) => Ok(forms[0](*a, *b, *d)),
+            ([O::Zp(a), O::Reg(b)], Some(O::Reg(d))) => Ok(forms[1](*a, *b, *d)),
+            ([O::Reg(a), O::Imm(c, cs)], Some(O::Reg(d))) => {
+                Ok(forms[2](*a, fit(*c, 0xff, *cs)? as u8, *d))
+            }
+            ([O::Zp(a), O::Imm(c, cs)], Some(O::Reg(d))) => {
+                Ok(forms[3](*a, fit(*c, 0xff, *cs)? as u8, *d))
+            }
+            ([O::Reg(a), O::Imm(c, cs)], Some(O::Zp(d))) => {
+                Ok(forms[4](*a, fit(*c, 0xff, *cs)? as u8, *d))
+            }
+            ([O::Zp(a), O::Imm(c, cs)], Some(O::Zp(d))) => {
+                Ok(forms[5](*a, fit(*c, 0xff, *cs)? as u8, *d))
+            }
+            _ => Err(bad()),
+        };
+    }
+
+    match m {
+        "NOT" => {
+            let forms: [Op2; 4] = [I::NotRR, I::NotZR, I::NotRZ, I::NotZZ];
+            match (srcs, dst) {
+                ([O::Reg(a)], Some(O::Reg(d))) => Ok(forms[0](*a, *d)),
+                ([O::Zp(a)], Some(O::Reg(d))) => Ok(forms[1](*a, *d)),
+                ([O::Reg(a)], Some(O::Zp(d))) => Ok(forms[2](*a, *d)),
+                ([O::Zp(a)], Some(O::Zp(d))) => Ok(forms[3](*a, *d)),
+                _ => Err(bad()),
+            }
+        }
+        "MOV" => match (srcs, dst) {
+            ([O::Reg(a)], Some(O::Reg(d))) => Ok(I::MovRR(*a, *d)),
+            _ => Err(bad()),
+        },
+        "INT_PENDING" | "FAULT_CODE" => match (srcs, dst) {
+            ([], Some(O::Reg(d))) if m == "INT_PENDING" => Ok(I::IntPending(*d)),
+            ([], Some(O::Reg(d))) => Ok(I::FaultCode(*d)),
+            _ => Err(bad()),
+        },
+        "READ_CHAR" | "KEY_AVAILABLE" => match (srcs, dst) {
+            ([], Some(O::Reg(d))) if m == "READ_CHAR" => Ok(I::ReadChar(*d)),
+            ([], Some(O::Reg(d))) => Ok(I::KeyAvailable(*d)),
+            _ => Err(bad()),
+        },
+        // with one destination, they can't be anything else
+        "MOVR4R4" | "MOV4R4R" => Err(bad()),
+        "MOV44" => match (srcs, dst) {
+            ([O::Reg(a)], Some(O::Reg(d))) => Ok(I::Mov44(*d << 4 | *a)),
+            _ => Err(bad()),
+        },
+        "CONST" | "CONST8" | "CONST16" | "CONST32" => {
+            let (c, cs, r) = match (srcs, dst) {
+                ([O::Imm(c, cs)], Some(O::Reg(r))) => (*c, *cs, *r),
+                _ => return Err(bad()),
+            };
+            let width = match m {
+                "CONST8" => 8,
+                "CONST16" => 16,
+                "CONST32" => 32,
+                _ if c <= 0xff => 8,
+                _ if c <= 0xffff => 16,
+                _ => 32,
+            };
+            Ok(match width {
+                8 => I::ConstRegU8(fit(c, 0xff, cs)? as u8, r),
+                16 => I::ConstRegU16(fit(c, 0xffff, cs)? as u16, r),
+                _ => I::ConstRegU32(fit(c, 0xffff_ffff, cs)? as u32, r),
+            })
+        }
+        "PRINT_CHAR" => match (srcs, dst) {
+            ([O::Reg(r)], None) => Ok(I::PrintCharR(*r)),
+            ([O::Imm(c, cs)], None) => Ok(I::PrintCharC8(fit(*c, 0xff, *cs)? as u8)),
+            _ => Err(bad()),
+        },
+        "READ_8" | "READ_16" | "READ_32" => {
+            let (c, r): (fn(u32, u8) -> I, Op2) = match m {
+                "READ_8" => (I::Read8CptrR, I::Read8RptrR),
+                "READ_16" => (I::Read16CptrR, I::Read16RptrR),
+                _ => (I::Read32CptrR, I::Read32RptrR),
+            };
+            match (srcs, dst) {
+                ([O::CPtr(p, ps)], Some(O::Reg(d))) => Ok(c(fit(*p, 0xffff_ffff, *ps)? as u32, *d)),
+                ([O::RPtr(p)], Some(O::Reg(d))) => Ok(r(*p, *d)),
+                _ => Err(bad()),
+            }
+        }
+        "WRITE_8" => match (srcs, dst) {
+            ([O::Reg(r)], Some(O::CPtr(p, ps))) => {
+                Ok(I::Write8RCptr(*r, fit(*p, 0xffff_ffff, *ps)? as u32))
+            }
+            ([O::Reg(r)], Some(O::RPtr(p))) => Ok(I::Write8RRptr(*r, *p)),
+            ([O::Imm(c, cs)], Some(O::CPtr(p, ps))) => Ok(I::Write8C8Cptr(
+                fit(*c, 0xff, *cs)? as u8,
+                fit(*p, 0xffff_ffff, *ps)? as u32,
+            )),
+            ([O::Imm(c, cs)], Some(O::RPtr(p))) => {
+                Ok(I::Write8C8Rptr(fit(*c, 0xff, *cs)? as u8, *p))
+            }
+            _ => Err(bad()),
+        },
+        "WRITE_16" => match (srcs, dst) {
+            ([O::Reg(r)], Some(O::CPtr(p, ps))) => {
+                Ok(I::Write16RCptr(*r, fit(*p, 0xffff_ffff, *ps)? as u32))
+            }
+            ([O::Reg(r)], Some(O::RPtr(p))) => Ok(I::Write16RRptr(*r, *p)),
+            ([O::Imm(c, cs)], Some(O::CPtr(p, ps))) => Ok(I::Write16C16Cptr(
+                fit(*c, 0xffff, *cs)? as u16,
+                fit(*p, 0xffff_ffff, *ps)? as u32,
+            )),
+            ([O::Imm(c, cs)], Some(O::RPtr(p))) => {
+                Ok(I::Write16C16Rptr(fit(*c, 0xffff, *cs)? as u16, *p))
+            }
+            _ => Err(bad()),
+        },
+        "WRITE_32" => match (srcs, dst) {
+            ([O::Reg(r)], Some(O::CPtr(p, ps))) => {
+                Ok(I::Write32RCptr(*r, fit(*p, 0xffff_ffff, *ps)? as u32))
+            }
+            ([O::Reg(r)], Some(O::RPtr(p))) => Ok(I::Write32RRptr(*r, *p)),
+            ([O::Imm(c, cs)], Some(O::CPtr(p, ps))) => Ok(I::Write32C32Cptr(
+                fit(*c, 0xffff_ffff, *cs)? as u32,
+                fit(*p, 0xffff_ffff, *ps)? as u32,
+            )),
+            ([O::Imm(c, cs)], Some(O::RPtr(p))) => {
+                Ok(I::Write32C32Rptr(fit(*c, 0xffff_ffff, *cs)? as u32, *p))
+            }
+            _ => Err(bad()),
+        },
+        _ => Err(Diagnostic::error(
+            ErrorCode::UnknownInstruction,
+            format!("unknown instruction `{}`", s.mnemonic),
+            s.mnemonic_span,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Assembler, AssemblyError, Ver0};
+
+    fn assemble(code: &str) -> Result<Vec<u8>, AssemblyError> {
+        Ver0::new().assemble_str("test.kasm", code)
+    }
+    fn codes(code: &str) -> Vec<ErrorCode> {
+        match assemble(code) {
+            Ok(_) => panic!("`{}` assembled", code),
+            Err(e) => e.diagnostics.iter().map(|d| d.code).collect(),
+        }
+    }
+
+    #[test]
+    fn macros_expand_their_arguments_in_place() {
+        let code = "\
+            .macro LOAD reg, n
+            CONST8 (n) + 1 -> reg
+            .endm
+            load r2, 3 * 2
+        ";
+        assert_eq!(
+            assemble(code).unwrap(),
+            assemble("CONST8 7 -> r2\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn macro_labels_are_renamed_every_expansion() {
+        let code = "\
+            .macro SPIN reg
+        again:
+            DEC reg
+            JNZ_OFFSET again
+            .endm
+            SPIN r0
+            SPIN r1
+        ";
+        // the offset counts back from its own byte to the DEC
+        let by_hand = "DEC r0\nJNZ_OFFSET -5\nDEC r1\nJNZ_OFFSET -5\n";
+        assert_eq!(assemble(code).unwrap(), assemble(by_hand).unwrap());
+    }
+
+    #[test]
+    fn macro_mistakes_are_reported() {
+        let twice = ".macro M\nNOP\n.endm\n.macro m\nNOP\n.endm\n";
+        assert_eq!(codes(twice), [ErrorCode::DuplicateMacro]);
+        assert_eq!(codes(".macro M\nNOP\n"), [ErrorCode::Unterminated]);
+        let forever = ".macro M\nM\n.endm\nM\n";
+        assert_eq!(codes(forever), [ErrorCode::MacroRecursion]);
+    }
+
+    #[test]
+    fn recursive_macros_stop_with_if() {
+        let code = "\
+            .macro REPEAT n, what
+            .if (n) > 0
+            what
+            REPEAT (n) - 1, what
+            .endif
+            .endm
+            REPEAT 3, NOP
+            HALT_AND_EXIT
+        ";
+        assert_eq!(assemble(code).unwrap(), [0, 0, 0, 0xfe]);
+    }
+
+    #[test]
+    fn only_the_first_true_branch_is_assembled() {
+        let pick = |n: i64| {
+            let code = format!(
+                ".equ N, {}\n.if N == 1\n.byte 1\n.elif N == 2\n.byte 2\n.else\n.byte 3\n.endif\n",
+                n
+            );
+            assemble(&code).unwrap()
+        };
+        assert_eq!(pick(1), [1]);
+        assert_eq!(pick(2), [2]);
+        assert_eq!(pick(7), [3]);
+    }
+
+    #[test]
+    fn skipped_branches_may_hold_anything_but_unbalanced_ifs() {
+        let code = ".if 0\n.if 1\n.byte 1\n.endif\nNOT AN INSTRUCTION\n.endif\n.byte 2\n";
+        assert_eq!(assemble(code).unwrap(), [2]);
+        assert_eq!(codes(".if 1\n.byte 1\n"), [ErrorCode::Unterminated]);
+    }
+
+    #[test]
+    fn ifdef_sees_defines_constants_and_macros() {
+        let code = ".ifdef DEBUG\n.byte 1\n.endif\n.ifndef DEBUG\n.byte 2\n.endif\n";
+        assert_eq!(assemble(code).unwrap(), [2]);
+        let debug = Ver0::new().define("DEBUG", 1);
+        assert_eq!(debug.assemble_str("test.kasm", code).unwrap(), [1]);
+        let mac = ".macro M\n.endm\n.ifdef m\n.byte 3\n.endif\n";
+        assert_eq!(assemble(mac).unwrap(), [3]);
+    }
+
+    #[test]
+    fn set_can_change_but_equ_cannot() {
+        let code = ".set N, 1\n.byte N\n.set N, N + 1\n.byte N\n";
+        assert_eq!(assemble(code).unwrap(), [1, 2]);
+        assert_eq!(
+            codes(".equ N, 1\n.equ N, 2\n"),
+            [ErrorCode::DuplicateConstant]
+        );
+    }
+
+    /// A fresh directory with `files` in it, for the include tests.
+    fn files(test: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("kmm0-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, text) in files {
+            std::fs::write(dir.join(name), text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn includes_are_assembled_in_place() {
+        let dir = files(
+            "include",
+            &[
+                (
+                    "main.kasm",
+                    ".byte 1\n.include \"lib.kasm\"\n.byte TWO + 1\n",
+                ),
+                ("lib.kasm", ".equ TWO, 2\n.byte TWO\n"),
+            ],
+        );
+        let main = dir.join("main.kasm");
+        let code = Ver0::new().assemble_file(&main.to_string_lossy());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(code.unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let dir = files(
+            "cycle",
+            &[
+                ("a.kasm", ".include \"b.kasm\"\n"),
+                ("b.kasm", ".include \"a.kasm\"\n"),
+            ],
+        );
+        let a = dir.join("a.kasm");
+        let err = Ver0::new().assemble_file(&a.to_string_lossy());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let err = err.unwrap_err();
+        let codes: Vec<ErrorCode> = err.diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, [ErrorCode::IncludeCycle]);
+        // it points into b.kasm, which a.kasm included
+        let d = &err.diagnostics[0];
+        assert!(err.sources.path(d.span.unwrap().file).ends_with("b.kasm"));
+        assert_eq!(d.notes.len(), 1);
+    }
+}
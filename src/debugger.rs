@@ -0,0 +1,779 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+
+use crate::assembler::disassembler::{self, is_call, jump_target};
+use crate::assembler::{self, Condition, Instruction, Labels, Lexeme};
+use crate::emulator::{Access, Fault, Watchpoint, KMM0};
+use crate::isa;
+
+const HELP: &str = "\
+step [n]             run n instructions, 1 by default (s)
+next                 step, running a CALL to its return (n)
+continue             run until a breakpoint, a watchpoint or a fault (c)
+break [where]        stop when ip reaches where, or list breakpoints (b)
+break <where> if <e> stop there only when e, like `r0 == 0 && zf`, holds
+delete <where>       remove a breakpoint (d)
+watch [read|write|change] <where> [len]
+                     stop after an access to len bytes of data memory, 1 by
+                     default, or to zero page slot zp[n] (w)
+watch                list watchpoints
+unwatch <n>          remove watchpoint n from the list
+regs                 show the registers and flags (r)
+mem <addr> [len]     dump data memory, 64 bytes by default (m)
+disasm [where] [n]   disassemble n instructions, 10 by default, from ip (x)
+set <reg|zf|cf> <v>  change a register or a flag
+quit                 stop debugging (q)
+Addresses can be numbers, labels, registers or label+n. Conditions are
+expressions over registers, zp[n], zf, cf and labels. An empty line repeats
+the last command.";
+
+/// What `set` changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Reg(u8),
+    Zf,
+    Cf,
+}
+
+/// One debugger command, as typed. Addresses and counts are kept as the
+/// tokens they were written as, since labels and registers are only looked
+/// up when the command runs.
+pub enum Command {
+    Quit,
+    Help,
+    Step(Option<Vec<Lexeme>>),
+    Next,
+    Continue,
+    Breakpoints,
+    Break(Vec<Lexeme>, Option<(String, Condition)>), // the condition as typed too
+    Delete(Vec<Lexeme>),
+    Watchpoints,
+    Watch(Access, Vec<Lexeme>, Option<Vec<Lexeme>>), // where, and how many bytes
+    Unwatch(Vec<Lexeme>),
+    Regs,
+    Mem(Vec<Lexeme>, Option<Vec<Lexeme>>),
+    Disasm(Option<Vec<Lexeme>>, Option<Vec<Lexeme>>),
+    Set(Target, Vec<Lexeme>),
+}
+impl Command {
+    /// Reads one command line. Every word but the first is a token group
+    /// like `loop+4` or `zp[2]`.
+    pub fn parse(line: &str) -> Result<Command, String> {
+        if let Some((at, cond)) = line.split_once(" if ") {
+            let at = match at.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["b" | "break", at] => tokens(at)?,
+                _ => return Err("only breakpoints can have conditions".to_string()),
+            };
+            let cond = cond.trim();
+            let condition = Condition::parse(cond).map_err(|e| e.to_string())?;
+            return Ok(Command::Break(at, Some((cond.to_string(), condition))));
+        }
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or_default();
+        let mut args = words.map(tokens).collect::<Result<Vec<_>, _>>()?;
+        // `watch` can say what kind of access before where
+        let mut access = None;
+        if matches!(cmd, "w" | "watch") {
+            access = args.first().and_then(|a| match a.as_slice() {
+                [Lexeme::Ident(a)] if a == "read" => Some(Access::Read),
+                [Lexeme::Ident(a)] if a == "write" => Some(Access::Write),
+                [Lexeme::Ident(a)] if a == "change" => Some(Access::Change),
+                _ => None,
+            });
+            if access.is_some() {
+                args.remove(0);
+            }
+        }
+        let n = args.len();
+        let mut args = args.into_iter();
+        let mut arg = || args.next().unwrap_or_default();
+        Ok(match (cmd, n) {
+            ("q" | "quit", 0) => Command::Quit,
+            ("h" | "help", 0) => Command::Help,
+            ("s" | "step", 0) => Command::Step(None),
+            ("s" | "step", 1) => Command::Step(Some(arg())),
+            ("n" | "next", 0) => Command::Next,
+            ("c" | "continue", 0) => Command::Continue,
+            ("b" | "break", 0) => Command::Breakpoints,
+            ("b" | "break", 1) => Command::Break(arg(), None),
+            ("d" | "delete", 1) => Command::Delete(arg()),
+            ("w" | "watch", 0) if access.is_none() => Command::Watchpoints,
+            ("w" | "watch", 1) => Command::Watch(access.unwrap_or(Access::Write), arg(), None),
+            ("w" | "watch", 2) => {
+                Command::Watch(access.unwrap_or(Access::Write), arg(), Some(arg()))
+            }
+            ("w" | "watch", _) => {
+                return Err("watch needs an address and maybe a length".to_string())
+            }
+            ("unwatch", 1) => Command::Unwatch(arg()),
+            ("r" | "regs", 0) => Command::Regs,
+            ("m" | "mem", 1) => Command::Mem(arg(), None),
+            ("m" | "mem", 2) => Command::Mem(arg(), Some(arg())),
+            ("x" | "disasm", 0) => Command::Disasm(None, None),
+            ("x" | "disasm", 1) => Command::Disasm(Some(arg()), None),
+            ("x" | "disasm", 2) => Command::Disasm(Some(arg()), Some(arg())),
+            ("set", 2) => Command::Set(target(&arg())?, arg()),
+            _ => return Err(format!("don't know `{}`, try `help`", line)),
+        })
+    }
+}
+
+/// One argument, like `loop+4` or `zp[2]`, as tokens.
+fn tokens(word: &str) -> Result<Vec<Lexeme>, String> {
+    assembler::tokens(word).ok_or(format!("can't read `{}`", word))
+}
+
+/// A register or a flag, for `set`.
+fn target(toks: &[Lexeme]) -> Result<Target, String> {
+    match toks {
+        [Lexeme::Ident(f)] if f.eq_ignore_ascii_case("zf") => Ok(Target::Zf),
+        [Lexeme::Ident(f)] if f.eq_ignore_ascii_case("cf") => Ok(Target::Cf),
+        [Lexeme::Ident(r)] => match assembler::register(r) {
+            Some(r) => Ok(Target::Reg(r)),
+            None => Err(format!("`{}` isn't a register or a flag", r)),
+        },
+        _ => Err("set needs a register or a flag".to_string()),
+    }
+}
+
+/// An interactive debugger around a cpu and the program it's running. It
+/// knows the program's labels, so addresses are shown next to the nearest
+/// one and can be given by name.
+pub struct Debugger {
+    cpu: KMM0,
+    code: Vec<u8>,
+    labels: Labels,
+    names: BTreeMap<u32, String>, // the labels by address
+    breakpoints: BTreeMap<u32, Option<(String, Condition)>>, // the condition as typed too
+    exited: bool,
+    last: String,
+}
+impl Debugger {
+    pub fn new(cpu: KMM0, code: Vec<u8>, labels: Labels) -> Self {
+        let mut names = BTreeMap::new();
+        for (name, addr) in &labels {
+            // Macro labels are renamed per expansion; prefer real ones.
+            let keep = match names.get(addr) {
+                Some(old) => name.len() < String::len(old),
+                None => true,
+            };
+            if keep {
+                names.insert(*addr, name.clone());
+            }
+        }
+        Debugger {
+            cpu,
+            code,
+            labels,
+            names,
+            breakpoints: BTreeMap::new(),
+            exited: false,
+            last: String::new(),
+        }
+    }
+    /// Reads commands from `input` until `quit` or the end of it, writing
+    /// what they print to `out`.
+    pub fn repl(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "{}", self.current())?;
+        let mut lines = input.lines();
+        loop {
+            write!(out, "(kdb) ")?;
+            out.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            match self.command(&line) {
+                Ok(Some(text)) => writeln!(out, "{}", text)?,
+                Ok(None) => return Ok(()),
+                Err(e) => writeln!(out, "error: {}", e)?,
+            }
+        }
+    }
+    /// Runs one command line, giving what it prints, or `None` for `quit`.
+    /// An empty line runs the last one again.
+    pub fn command(&mut self, line: &str) -> Result<Option<String>, String> {
+        let line = match line.trim() {
+            "" => self.last.clone(),
+            line => line.to_string(),
+        };
+        self.last = line.clone();
+        self.run(Command::parse(&line)?)
+    }
+    /// Runs `cmd`, giving what it prints, or `None` for `quit`.
+    pub fn run(&mut self, cmd: Command) -> Result<Option<String>, String> {
+        let out = match cmd {
+            Command::Quit => return Ok(None),
+            Command::Help => HELP.to_string(),
+            Command::Step(None) => self.resume(Some(1), None),
+            Command::Step(Some(n)) => self.resume(Some(self.value(&n)? as u64), None),
+            Command::Next => self.next(),
+            Command::Continue => self.resume(None, None),
+            Command::Breakpoints => self.list_breakpoints(),
+            Command::Break(at, None) => {
+                let addr = self.value(&at)?;
+                self.breakpoints.insert(addr, None);
+                format!("breakpoint at {}", self.location(addr))
+            }
+            Command::Break(at, Some((text, condition))) => {
+                let addr = self.value(&at)?;
+                let out = format!("breakpoint at {} if {}", self.location(addr), text);
+                self.breakpoints.insert(addr, Some((text, condition)));
+                out
+            }
+            Command::Delete(at) => {
+                let addr = self.value(&at)?;
+                if self.breakpoints.remove(&addr).is_none() {
+                    return Err(format!("no breakpoint at {}", self.location(addr)));
+                }
+                format!("deleted breakpoint at {}", self.location(addr))
+            }
+            Command::Watchpoints => self.list_watchpoints(),
+            Command::Watch(access, at, len) => {
+                let w = match (zero_page(&at), len) {
+                    (Some(n), None) => Watchpoint::zero_page(n, access),
+                    (_, None) => Watchpoint::new(self.value(&at)?, 1, access),
+                    (_, Some(len)) => Watchpoint::new(self.value(&at)?, self.value(&len)?, access),
+                };
+                self.cpu.watch(w);
+                format!("watching {}", w)
+            }
+            Command::Unwatch(n) => {
+                let n = self.value(&n)? as usize;
+                let w = match self.cpu.watchpoints().get(n.wrapping_sub(1)) {
+                    Some(w) => *w,
+                    None => return Err(format!("no watchpoint {}", n)),
+                };
+                self.cpu.unwatch(&w);
+                format!("deleted watchpoint on {}", w)
+            }
+            Command::Regs => self.regs(),
+            Command::Mem(addr, len) => {
+                let len = match len {
+                    Some(len) => self.value(&len)?,
+                    None => 64,
+                };
+                self.mem(self.value(&addr)?, len)
+            }
+            Command::Disasm(at, n) => {
+                let addr = match at {
+                    Some(at) => self.value(&at)?,
+                    None => self.cpu.ip_reg() as u32,
+                };
+                let n = match n {
+                    Some(n) => self.value(&n)?,
+                    None => 10,
+                };
+                self.disasm(addr, n)
+            }
+            Command::Set(what, v) => {
+                let v = self.value(&v)?;
+                match what {
+                    Target::Zf => self.cpu.set_flags(v != 0, self.cpu.cf()),
+                    Target::Cf => self.cpu.set_flags(self.cpu.zf(), v != 0),
+                    Target::Reg(r) => self.cpu.set_reg(r as usize, v),
+                }
+                self.regs()
+            }
+        };
+        Ok(Some(out))
+    }
+
+    /// Steps `count` instructions, or forever if it's `None`, stopping early
+    /// at a breakpoint whose condition holds, after an access a watchpoint
+    /// catches, on a fault, or when `until` says so.
+    fn resume(&mut self, count: Option<u64>, until: Option<&dyn Fn(&KMM0) -> bool>) -> String {
+        if self.exited {
+            return "the program has exited".to_string();
+        }
+        let mut steps = 0;
+        loop {
+            if let Err(halt) = self.cpu.step() {
+                if halt.fault == Fault::ExplicitHalt {
+                    self.exited = true;
+                    return format!("exited at {}", self.location(halt.ip));
+                }
+                return format!(
+                    "stopped by fault {:#04x} at {}: {}\n{}",
+                    halt.fault.code(),
+                    self.location(halt.ip),
+                    halt.fault,
+                    self.current()
+                );
+            }
+            steps += 1;
+            let ip = self.cpu.ip_reg() as u32;
+            let hits = self.cpu.take_watch_hits();
+            let done = count.is_some_and(|n| steps >= n);
+            let why = if !hits.is_empty() {
+                let lines: Vec<String> = hits.iter().map(|h| format!("{}\n", h)).collect();
+                format!("watchpoint, {}", lines.concat())
+            } else if done || until.is_some_and(|f| f(&self.cpu)) {
+                String::new()
+            } else {
+                match self.at_breakpoint(ip) {
+                    Ok(true) => "breakpoint\n".to_string(),
+                    Err(e) => format!("breakpoint, but can't work out its condition: {}\n", e),
+                    Ok(false) => continue,
+                }
+            };
+            return format!("{}{}", why, self.current());
+        }
+    }
+    /// Whether there's a breakpoint at `addr` and its condition, if it has
+    /// one, holds.
+    fn at_breakpoint(&self, addr: u32) -> Result<bool, String> {
+        let (_, condition) = match self.breakpoints.get(&addr) {
+            Some(Some(cond)) => cond,
+            Some(None) => return Ok(true),
+            None => return Ok(false),
+        };
+        let mut values = HashMap::new();
+        for name in condition.names() {
+            if let Some(v) = self.name_value(name)? {
+                values.insert(name.clone(), v);
+            }
+        }
+        Ok(condition.eval(&values)? != 0)
+    }
+    /// What `name` stands for in a condition: a register or flag's value, a
+    /// zero page slot's contents or a label's address. `None` if it's none
+    /// of them, which `eval` reports.
+    fn name_value(&self, name: &str) -> Result<Option<i64>, String> {
+        if let Some(slot) = name.strip_prefix("zp[").and_then(|s| s.strip_suffix(']')) {
+            let addr = slot.parse::<u32>().unwrap_or_default() * 4;
+            let mut v = 0;
+            for i in 0..4 {
+                match self.cpu.peek(addr + i) {
+                    Ok(b) => v |= (b as u32) << (8 * i),
+                    Err(_) => return Err(format!("nothing is mapped at {:#x}", addr + i)),
+                }
+            }
+            return Ok(Some(v as i64));
+        }
+        let v = match name {
+            "zf" => self.cpu.zf() as u32,
+            "cf" => self.cpu.cf() as u32,
+            _ => match (assembler::register(name), self.labels.get(name)) {
+                (Some(r), _) => self.cpu.reg(r as usize),
+                (None, Some(addr)) => *addr,
+                (None, None) => return Ok(None),
+            },
+        };
+        Ok(Some(v as i64))
+    }
+    /// Steps, but runs a `CALL` until it returns.
+    fn next(&mut self) -> String {
+        let ip = self.cpu.ip_reg() as u32;
+        match self.decode(ip) {
+            Some((i, len)) if is_call(&i) => {
+                let ret = ip + len as u32;
+                let sp = self.cpu.sp_reg();
+                // A recursive call passes `ret` with a deeper stack.
+                let back = move |cpu: &KMM0| cpu.ip_reg() as u32 == ret && cpu.sp_reg() >= sp;
+                self.resume(None, Some(&back))
+            }
+            _ => self.resume(Some(1), None),
+        }
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "no breakpoints".to_string();
+        }
+        let lines: Vec<String> = self
+            .breakpoints
+            .iter()
+            .map(|(addr, cond)| match cond {
+                Some((text, _)) => format!("{} if {}", self.location(*addr), text),
+                None => self.location(*addr),
+            })
+            .collect();
+        lines.join("\n")
+    }
+    fn list_watchpoints(&self) -> String {
+        if self.cpu.watchpoints().is_empty() {
+            return "no watchpoints".to_string();
+        }
+        let lines: Vec<String> = (self.cpu.watchpoints().iter().enumerate())
+            .map(|(i, w)| format!("{:>2} {}", i + 1, w))
+            .collect();
+        lines.join("\n")
+    }
+    fn regs(&self) -> String {
+        let mut out = String::new();
+        for r in 0..16u8 {
+            let name = isa::reg(r);
+            out += &format!("{:>3} {:08x}", name, self.cpu.reg(r as usize));
+            out += if r % 4 == 3 { "\n" } else { "  " };
+        }
+        out += &format!(
+            " zf {}  cf {}  ip at {}",
+            self.cpu.zf() as u8,
+            self.cpu.cf() as u8,
+            self.location(self.cpu.ip_reg() as u32)
+        );
+        out
+    }
+    fn mem(&self, addr: u32, len: u32) -> String {
+        let mut lines = vec![];
+        for row in (0..len).step_by(16) {
+            let start = addr.wrapping_add(row);
+            let bytes: Vec<Option<u8>> = (0..16.min(len - row))
+                .map(|i| self.cpu.peek(start.wrapping_add(i)).ok())
+                .collect();
+            let hex: Vec<String> = bytes
+                .iter()
+                .map(|b| match b {
+                    Some(b) => format!("{:02x}", b),
+                    None => "??".to_string(),
+                })
+                .collect();
+            let text: String = bytes
+                .iter()
+                .map(|b| match b {
+                    Some(b @ 0x20..=0x7e) => *b as char,
+                    _ => '.',
+                })
+                .collect();
+            lines.push(format!("{:08x}  {:<47}  {}", start, hex.join(" "), text));
+        }
+        lines.join("\n")
+    }
+    fn disasm(&self, addr: u32, n: u32) -> String {
+        let from = (addr as usize).min(self.code.len());
+        let mut lines = vec![];
+        for d in disassembler::decode(&self.code[from..], addr)
+            .iter()
+            .take(n as usize)
+        {
+            if let Some(name) = self.names.get(&d.addr) {
+                lines.push(format!("{}:", name));
+            }
+            lines.push(self.line(d.addr, &d.instruction, format!("{:04x}", d.addr)));
+        }
+        if lines.is_empty() {
+            return format!("no code at {:#x}", addr);
+        }
+        lines.join("\n")
+    }
+
+    /// The instruction at ip, as `step` and friends show it.
+    fn current(&self) -> String {
+        let ip = self.cpu.ip_reg() as u32;
+        match self.decode(ip) {
+            Some((i, _)) => self.line(ip, &i, self.location(ip)),
+            None => format!("{}: no instruction here", self.location(ip)),
+        }
+    }
+    /// One disassembled instruction at `addr`, shown as `at`, marked if ip
+    /// is on it or it has a breakpoint.
+    fn line(&self, addr: u32, i: &Instruction, at: String) -> String {
+        let mark = match (
+            addr == self.cpu.ip_reg() as u32,
+            self.breakpoints.contains_key(&addr),
+        ) {
+            (true, _) => "=>",
+            (false, true) => " *",
+            (false, false) => "  ",
+        };
+        let mut text = format!("{} {}  {}", mark, at, i);
+        if let Some(target) = jump_target(i, addr) {
+            text += &format!("  ; {}", self.location(target));
+        }
+        text
+    }
+    fn decode(&self, addr: u32) -> Option<(Instruction, usize)> {
+        Instruction::decode(self.code.get(addr as usize..)?)
+    }
+    /// `addr` along with the nearest label at or before it.
+    fn location(&self, addr: u32) -> String {
+        match self.names.range(..=addr).next_back() {
+            Some((at, name)) if *at == addr => format!("{:04x} <{}>", addr, name),
+            Some((at, name)) => format!("{:04x} <{}+{}>", addr, name, addr - at),
+            None => format!("{:04x}", addr),
+        }
+    }
+    /// A number, a label, a register's value, or a label plus or minus a
+    /// number.
+    fn value(&self, toks: &[Lexeme]) -> Result<u32, String> {
+        let base = |t: &Lexeme| match t {
+            Lexeme::Int(n) => Ok(*n as u32),
+            Lexeme::Ident(name) => match (self.labels.get(name), assembler::register(name)) {
+                (Some(addr), _) => Ok(*addr),
+                (None, Some(r)) => Ok(self.cpu.reg(r as usize)),
+                (None, None) => Err(format!("no label or register `{}`", name)),
+            },
+            _ => Err("expected a number, a label or a register".to_string()),
+        };
+        match toks {
+            [a] => base(a),
+            [a, Lexeme::Plus, Lexeme::Int(n)] => Ok(base(a)?.wrapping_add(*n as u32)),
+            [a, Lexeme::Minus, Lexeme::Int(n)] => Ok(base(a)?.wrapping_sub(*n as u32)),
+            _ => Err("expected a number, a label or a register".to_string()),
+        }
+    }
+}
+
+/// `zp[n]`, zero page slot n.
+fn zero_page(toks: &[Lexeme]) -> Option<u8> {
+    match toks {
+        [Lexeme::Ident(zp), Lexeme::LBracket, Lexeme::Int(n), Lexeme::RBracket] if zp == "zp" => {
+            u8::try_from(*n).ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Ver0;
+    use crate::emulator::{console::Buffer, Bus};
+
+    const PROGRAM: &str = "\
+        .org 0x100
+    start:
+        CONST8 1 -> r0
+        CALL double
+        CONST8 3 -> r1
+    loop:
+        DEC r1
+        JNZ loop
+        HALT_AND_EXIT
+    double:
+        PRINT_CHAR 'd'
+        ADD r0, r0 -> r0
+        RET
+    ";
+
+    /// Writes zero page slot 128, at 0x200, a few times and reads it back.
+    const WRITES: &str = "\
+        .org 0x100
+        CONST8 5 -> r0
+        WRITE_32 r0 -> [0x200]
+        WRITE_32 r0 -> [0x200]
+        CONST8 6 -> r0
+        WRITE_32 r0 -> [0x200]
+    check:
+        READ_8 [0x200] -> r1
+        HALT_AND_EXIT
+    ";
+
+    /// A debugger on `PROGRAM`, and the console its output goes to.
+    fn debugger() -> (Debugger, Buffer) {
+        debugger_on(PROGRAM)
+    }
+    fn debugger_on(program: &str) -> (Debugger, Buffer) {
+        let (code, labels) = Ver0::new()
+            .assemble_str_with_labels("test.kasm", program)
+            .unwrap();
+        let console = Buffer::new();
+        let cpu = KMM0::new()
+            .bus(Bus::new().ram(0, 0x1000))
+            .console(Box::new(console.clone()))
+            .clock_speed_hz(0.)
+            .load_code(code.clone());
+        (Debugger::new(cpu, code, labels), console)
+    }
+    fn run(d: &mut Debugger, line: &str) -> String {
+        d.command(line).unwrap().unwrap()
+    }
+
+    #[test]
+    fn commands_parse_without_a_cpu() {
+        assert!(matches!(Command::parse("q"), Ok(Command::Quit)));
+        assert!(matches!(Command::parse("s"), Ok(Command::Step(None))));
+        let step = Command::parse("step 5").unwrap();
+        assert!(matches!(step, Command::Step(Some(n)) if n == [Lexeme::Int(5)]));
+        let brk = Command::parse("b loop+4").unwrap();
+        assert!(
+            matches!(brk, Command::Break(at, None) if at == assembler::tokens("loop+4").unwrap())
+        );
+        let watch = Command::parse("watch change zp[2]").unwrap();
+        assert!(matches!(watch, Command::Watch(Access::Change, _, None)));
+        let watch = Command::parse("w 0x200 4").unwrap();
+        assert!(matches!(watch, Command::Watch(Access::Write, _, Some(_))));
+        let set = Command::parse("set r3 7").unwrap();
+        assert!(matches!(set, Command::Set(Target::Reg(3), _)));
+        assert!(matches!(
+            Command::parse("set cf 1"),
+            Ok(Command::Set(Target::Cf, _))
+        ));
+    }
+
+    #[test]
+    fn bad_commands_say_why() {
+        let err = |line: &str| Command::parse(line).err().unwrap();
+        assert_eq!(err("frobnicate"), "don't know `frobnicate`, try `help`");
+        assert_eq!(err("step 1 2"), "don't know `step 1 2`, try `help`");
+        assert_eq!(err("set pc 1"), "`pc` isn't a register or a flag");
+        assert_eq!(err("step 1 if r0"), "only breakpoints can have conditions");
+        assert_eq!(
+            err("watch read"),
+            "watch needs an address and maybe a length"
+        );
+    }
+
+    #[test]
+    fn step_shows_the_next_instruction() {
+        let (mut d, _) = debugger();
+        assert_eq!(
+            run(&mut d, "s"),
+            "=> 0103 <start+3>  CALL_OFFSET +0xb  ; 0110 <double>"
+        );
+        assert_eq!(d.cpu.reg(0), 1);
+    }
+
+    #[test]
+    fn next_runs_a_call_to_its_return() {
+        let (mut d, console) = debugger();
+        run(&mut d, "s");
+        let out = run(&mut d, "n");
+        assert!(out.starts_with("=> 0106 <start+6>"), "{}", out);
+        assert_eq!(d.cpu.reg(0), 2);
+        assert_eq!(console.output(), "d");
+    }
+
+    #[test]
+    fn continue_stops_at_a_breakpoint_on_a_label() {
+        let (mut d, _) = debugger();
+        assert_eq!(run(&mut d, "b loop"), "breakpoint at 0109 <loop>");
+        let out = run(&mut d, "c");
+        assert!(out.starts_with("breakpoint\n=> 0109 <loop>"), "{}", out);
+        assert_eq!(d.cpu.reg(1), 3);
+        // the loop comes back to it twice more, then the program exits
+        run(&mut d, "c");
+        assert_eq!(d.cpu.reg(1), 2);
+        run(&mut d, "d loop");
+        assert_eq!(run(&mut d, "c"), "exited at 010f <loop+6>");
+        assert_eq!(run(&mut d, "c"), "the program has exited");
+    }
+
+    #[test]
+    fn an_empty_line_repeats_the_last_command() {
+        let (mut d, _) = debugger();
+        run(&mut d, "s");
+        run(&mut d, "");
+        assert_eq!(d.cpu.ip_reg(), 0x110);
+    }
+
+    #[test]
+    fn set_changes_registers_and_flags() {
+        let (mut d, _) = debugger();
+        run(&mut d, "set r5 0x2a");
+        run(&mut d, "set zf 1");
+        assert_eq!(d.cpu.reg(5), 0x2a);
+        assert!(d.cpu.zf());
+    }
+
+    #[test]
+    fn repl_reads_a_script() {
+        let (mut d, console) = debugger();
+        let mut out = vec![];
+        d.repl(&b"b double\nc\nbogus\nq\nc\n"[..], &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let expected = "\
+=> 0100 <start>  CONST8 0x1 -> r0
+(kdb) breakpoint at 0110 <double>
+(kdb) breakpoint
+=> 0110 <double>  PRINT_CHAR 'd'
+(kdb) error: don't know `bogus`, try `help`
+(kdb) ";
+        assert_eq!(out, expected);
+        // it stopped at `q`, before the second `c`
+        assert_eq!(console.output(), "");
+    }
+
+    #[test]
+    fn write_watchpoints_stop_after_every_write() {
+        let (mut d, _) = debugger_on(WRITES);
+        assert_eq!(run(&mut d, "watch 0x200"), "watching write [0x200]");
+        let out = run(&mut d, "c");
+        assert!(
+            out.starts_with("watchpoint, wrote [0x200] = 0x05\n=> 010a"),
+            "{}",
+            out
+        );
+        // putting back the same value is still a write
+        let out = run(&mut d, "c");
+        assert!(
+            out.starts_with("watchpoint, wrote [0x200] = 0x05\n=> 0111"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn change_watchpoints_skip_writes_of_the_same_value() {
+        let (mut d, _) = debugger_on(WRITES);
+        run(&mut d, "watch change zp[128]");
+        let out = run(&mut d, "c");
+        assert!(
+            out.starts_with("watchpoint, wrote [0x200]: 0x00 -> 0x05\n"),
+            "{}",
+            out
+        );
+        let out = run(&mut d, "c");
+        assert!(
+            out.starts_with("watchpoint, wrote [0x200]: 0x05 -> 0x06\n"),
+            "{}",
+            out
+        );
+        assert_eq!(run(&mut d, "c"), "exited at 0122 <check+7>");
+    }
+
+    #[test]
+    fn looking_at_memory_isnt_a_read() {
+        let (mut d, _) = debugger_on(WRITES);
+        run(&mut d, "watch read 0x200 4");
+        assert!(run(&mut d, "mem 0x200 4").starts_with("00000200  00 00 00 00"));
+        // the first read is the program's own, at `check`
+        let out = run(&mut d, "c");
+        assert!(
+            out.starts_with("watchpoint, read [0x200] = 0x06\n"),
+            "{}",
+            out
+        );
+        assert_eq!(d.cpu.reg(1), 6);
+        assert_eq!(
+            run(&mut d, "unwatch 1"),
+            "deleted watchpoint on read zp[128] [0x200..0x204]"
+        );
+        assert_eq!(run(&mut d, "watch"), "no watchpoints");
+    }
+
+    #[test]
+    fn conditional_breakpoints_stop_only_when_it_holds() {
+        let (mut d, _) = debugger();
+        let out = run(&mut d, "b loop if r1 == 1");
+        assert_eq!(out, "breakpoint at 0109 <loop> if r1 == 1");
+        assert!(run(&mut d, "c").starts_with("breakpoint\n=> 0109 <loop>"));
+        assert_eq!(d.cpu.reg(1), 1);
+        assert_eq!(run(&mut d, "b"), "0109 <loop> if r1 == 1");
+    }
+
+    #[test]
+    fn conditions_can_read_the_zero_page() {
+        let (mut d, _) = debugger_on(WRITES);
+        run(&mut d, "b check if zp[128] == 5");
+        assert_eq!(run(&mut d, "c"), "exited at 0122 <check+7>");
+        let (mut d, _) = debugger_on(WRITES);
+        run(&mut d, "b check if zp[128] == 6 && r0 == 6");
+        assert!(run(&mut d, "c").starts_with("breakpoint\n=> 011b <check>"));
+    }
+
+    #[test]
+    fn conditions_that_cant_be_worked_out_stop_and_say_why() {
+        let (mut d, _) = debugger();
+        assert!(d.command("b loop if r1 ==").is_err());
+        run(&mut d, "b loop if nowhere > 1");
+        let out = run(&mut d, "c");
+        assert!(
+            out.starts_with("breakpoint, but can't work out its condition: "),
+            "{}",
+            out
+        );
+    }
+}
@@ -1,14 +1,25 @@
-use std::io::{self, Write};
+use std::fmt;
+
+use crate::isa::{opcode, variant, IO, JMP, JMP_C, MATH, MEMORY, STACK};
+
+mod bus;
+pub mod console;
+mod display;
+mod keyboard;
+mod timer;
+mod watch;
+pub use bus::{Bus, BusError, Device};
+pub use console::Console;
+pub use display::Display;
+use keyboard::Keyboard;
+pub use timer::{Timer, TIMER_SIZE};
+pub use watch::{Access, WatchHit, Watchpoint};
 
 union BitConvert32 {
     u: u32,
     b: (u8, u8, u8, u8),
 }
 
-fn u32_split(n: u32) -> (u8, u8, u8, u8) {
-    unsafe { BitConvert32 { u: n }.b }
-}
-
 fn u32_join(n: (u8, u8, u8, u8)) -> u32 {
     unsafe { BitConvert32 { b: n }.u }
 }
@@ -17,64 +28,200 @@ fn u32_join(n: (u8, u8, u8, u8)) -> u32 {
 const IP: usize = 0x8; // instruction pointer
 const SP: usize = 0x9; // stack pointer
 const RP: usize = 0xa; // interrupt return pointer
-const TR: usize = 0xb; // temp register
-const IX: usize = 0xc; // index register
 
-// fault constants
-const EXPLICIT_HALT_AND_EXIT: u8 = 0x01;
-const INVALID_INSTRUCTION: u8 = 0x10;
-const INVALID_INSTRUCTION_VARIANT: u8 = 0x11;
-const INVALID_CHAR: u8 = 0x20;
+/// Number of interrupt vectors. IRQs 1-15 can be raised, vector 0 is where
+/// code starts.
+pub const IRQS: u8 = 16;
+// vector n is at VECTORS + 8n, room for a jump to the handler
+const VECTORS: u32 = 0x100;
+
+/// Why the cpu stopped. Each one has the fault code software sees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    ExplicitHalt,           // HALT_AND_EXIT
+    InvalidInstruction(u8), // the opcode
+    InvalidVariant(u8, u8), // the opcode and the variant byte
+    InvalidRegister(u8),    // a register byte past r15
+    InvalidChar(u32),       // a value that isn't a char
+    DivideByZero,
+    MemoryOutOfRange(u32), // an address nothing is mapped at
+    WriteToRom(u32),
+    StackOverflow,
+    StackUnderflow,
+}
+impl Fault {
+    pub fn code(self) -> u8 {
+        match self {
+            Fault::ExplicitHalt => 0x01,
+            Fault::InvalidInstruction(_) => 0x10,
+            Fault::InvalidVariant(_, _) => 0x11,
+            Fault::InvalidRegister(_) => 0x12,
+            Fault::InvalidChar(_) => 0x20,
+            Fault::DivideByZero => 0x30,
+            Fault::MemoryOutOfRange(_) => 0x40,
+            Fault::WriteToRom(_) => 0x41,
+            Fault::StackOverflow => 0x50,
+            Fault::StackUnderflow => 0x51,
+        }
+    }
+}
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::ExplicitHalt => write!(f, "halted"),
+            Fault::InvalidInstruction(op) => write!(f, "invalid instruction {:#04x}", op),
+            Fault::InvalidVariant(op, v) => {
+                write!(f, "invalid variant {:#04x} of instruction {:#04x}", v, op)
+            }
+            Fault::InvalidRegister(r) => write!(f, "invalid register {:#04x}", r),
+            Fault::InvalidChar(n) => write!(f, "{:#x} isn't a char", n),
+            Fault::DivideByZero => write!(f, "divide by zero"),
+            Fault::MemoryOutOfRange(addr) => write!(f, "nothing is mapped at {:#x}", addr),
+            Fault::WriteToRom(addr) => write!(f, "{:#x} is read only", addr),
+            Fault::StackOverflow => write!(f, "stack overflow"),
+            Fault::StackUnderflow => write!(f, "stack underflow"),
+        }
+    }
+}
+
+/// A fault and the address of the instruction that raised it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Halt {
+    pub fault: Fault,
+    pub ip: u32,
+}
+impl fmt::Display for Halt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "fault {:#04x} at {:04x}: {}",
+            self.fault.code(),
+            self.ip,
+            self.fault
+        )
+    }
+}
+
+/// The bitwise and shift ops in the `(result, carry)` shape `do_math!`
+/// expects. The bitwise ops always clear carry, and shifts leave the last
+/// bit shifted out in it.
+trait Alu {
+    fn and_c(self, b: u32) -> (u32, bool);
+    fn or_c(self, b: u32) -> (u32, bool);
+    fn xor_c(self, b: u32) -> (u32, bool);
+    fn shl_c(self, b: u32) -> (u32, bool);
+    fn shr_c(self, b: u32) -> (u32, bool);
+}
+impl Alu for u32 {
+    fn and_c(self, b: u32) -> (u32, bool) {
+        (self & b, false)
+    }
+    fn or_c(self, b: u32) -> (u32, bool) {
+        (self | b, false)
+    }
+    fn xor_c(self, b: u32) -> (u32, bool) {
+        (self ^ b, false)
+    }
+    fn shl_c(self, b: u32) -> (u32, bool) {
+        match b {
+            0 => (self, false),
+            1..=32 => (((self as u64) << b) as u32, (self >> (32 - b)) & 1 == 1),
+            _ => (0, false),
+        }
+    }
+    fn shr_c(self, b: u32) -> (u32, bool) {
+        match b {
+            0 => (self, false),
+            1..=32 => (((self as u64) >> b) as u32, (self >> (b - 1)) & 1 == 1),
+            _ => (0, false),
+        }
+    }
+}
 
 pub struct KMM0 {
-    print_exec: bool,
     debug_clock_speed_hz: f64,
     debug_uptime_cycles: u64,
 
-    acc: u32,
     z: bool,
     c: bool,
     int_enable: bool,
-    int_idx: u8,
-    fault: u8,
+    int_pending: u16, // bit n is set while IRQ n waits to be taken
+    int_mask: u16,    // bit n is set if IRQ n can be taken
+    fault: Option<Fault>,
+    fault_handler: u32, // 0 if faults stop the cpu
+    fault_code: u8,     // code of the last fault handed to the handler
+    in_fault: bool,     // the fault handler is running
+    stack_limit: u32,
+    stack_top: u32,
     reg: [u32; 16],
     i_mem: Vec<u8>,
-    d_mem: Vec<u8>,
+    bus: Bus,
+    console: Box<dyn Console>,
+    keyboard: Keyboard,
+    keyboard_irq: Option<u8>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hits: Vec<WatchHit>, // since the debugger last took them
 }
 impl KMM0 {
     pub fn new() -> Self {
         KMM0 {
-            print_exec: false,
             debug_clock_speed_hz: 1f64,
             debug_uptime_cycles: 0u64,
 
-            acc: 0u32,
             z: false,
             c: false,
             int_enable: false,
-            int_idx: 0u8,
-            fault: 0u8,
+            int_pending: 0u16,
+            int_mask: 0xfffeu16,
+            fault: None,
+            fault_handler: 0u32,
+            fault_code: 0u8,
+            in_fault: false,
+            stack_limit: 0u32,
+            stack_top: 0u32,
             reg: [0u32; 16],
             i_mem: Vec::new(),
-            d_mem: Vec::new(),
+            bus: Bus::new(),
+            console: Box::new(console::Stdio::new()),
+            keyboard: Keyboard::new(),
+            keyboard_irq: None,
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
         }
     }
-    pub fn print_exec(mut self) -> Self {
-        self.print_exec = true;
-        return self;
+    /// Replaces the data bus, with an empty stack at the top of its first
+    /// RAM region.
+    pub fn bus(mut self, bus: Bus) -> Self {
+        self.bus = bus;
+        self.stack_top = self.bus.ram_top();
+        self.reg[SP] = self.stack_top;
+        self
+    }
+    /// Replaces the console, stdout with no input by default.
+    pub fn console(mut self, console: Box<dyn Console>) -> Self {
+        self.console = console;
+        self
+    }
+    /// Raises IRQ `n` whenever input arrives from the console.
+    pub fn keyboard_irq(mut self, n: u8) -> Self {
+        assert!((1..IRQS).contains(&n), "there's no IRQ {}", n);
+        self.keyboard_irq = Some(n);
+        self
     }
-    pub fn init_mem(mut self, size: usize) -> Self {
-        self.d_mem = vec![0x00; size];
-        return self;
+    /// Lets the stack grow down to `size` bytes below the top of memory
+    /// before pushes fault. By default it can use all of memory.
+    pub fn stack_size(mut self, size: u32) -> Self {
+        self.stack_limit = self.stack_top.saturating_sub(size);
+        self
     }
     pub fn load_code(mut self, code: Vec<u8>) -> Self {
         self.i_mem = code.clone();
         self.reg[IP] = 0x100;
-        return self;
+        self
     }
     pub fn clock_speed_hz(mut self, hz: f64) -> Self {
         self.debug_clock_speed_hz = hz;
-        return self;
+        self
     }
     pub fn clock(&mut self) {
         if self.debug_clock_speed_hz != 0. {
@@ -83,9 +230,10 @@ impl KMM0 {
             ));
         }
         self.debug_uptime_cycles += 1;
-    }
-    pub fn ix_reg(&self) -> usize {
-        self.reg[IX] as usize
+        self.int_pending |= self.bus.tick(self.debug_uptime_cycles) & 0xfffe;
+        if let (true, Some(n)) = (self.keyboard.poll(&mut *self.console), self.keyboard_irq) {
+            self.raise_irq(n);
+        }
     }
     pub fn ip_reg(&self) -> usize {
         self.reg[IP] as usize
@@ -93,21 +241,71 @@ impl KMM0 {
     pub fn sp_reg(&self) -> usize {
         self.reg[SP] as usize
     }
+    pub fn reg(&self, r: usize) -> u32 {
+        self.reg[r]
+    }
+    pub fn set_reg(&mut self, r: usize, v: u32) {
+        self.reg[r] = v;
+    }
+    pub fn zf(&self) -> bool {
+        self.z
+    }
+    pub fn cf(&self) -> bool {
+        self.c
+    }
+    pub fn set_flags(&mut self, z: bool, c: bool) {
+        self.z = z;
+        self.c = c;
+    }
+    /// Clock cycles since the cpu started.
+    pub fn cycles(&self) -> u64 {
+        self.debug_uptime_cycles
+    }
+    /// Starts noting accesses to the memory `w` covers.
+    pub fn watch(&mut self, w: Watchpoint) {
+        if !self.watchpoints.contains(&w) {
+            self.watchpoints.push(w);
+        }
+    }
+    /// Stops watching `w`, returning false if it wasn't being watched.
+    pub fn unwatch(&mut self, w: &Watchpoint) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|x| x != w);
+        self.watchpoints.len() != before
+    }
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+    /// The watched accesses since the last call, oldest first.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+    /// Reads data memory without taking any cycles, setting off watchpoints
+    /// or disturbing devices.
+    pub fn peek(&self, addr: u32) -> Result<u8, BusError> {
+        self.bus.peek(addr)
+    }
+    /// The byte of instruction memory at `addr`, if the code reaches it.
+    pub fn code(&self, addr: u32) -> Option<u8> {
+        self.i_mem.get(addr as usize).copied()
+    }
     pub fn mrn8_ip(&mut self) -> u8 {
         self.clock();
-        let n = self.i_mem[self.ip_reg()];
-        self.reg[IP] += 1;
-        if self.print_exec {
-            print!("{:02x?} ", n);
-            io::stdout().flush().unwrap();
-        }
-        return n;
+        let n = match self.i_mem.get(self.ip_reg()) {
+            Some(n) => *n,
+            None => {
+                self.fault(Fault::MemoryOutOfRange(self.reg[IP]));
+                0
+            }
+        };
+        self.reg[IP] = self.reg[IP].wrapping_add(1);
+        n
     }
     pub fn mrn16_ip(&mut self) -> u16 {
-        u32_join((0, 0, self.mrn8_ip(), self.mrn8_ip())) as u16
+        u32_join((self.mrn8_ip(), self.mrn8_ip(), 0, 0)) as u16
     }
     pub fn mrn24_ip(&mut self) -> u32 {
-        u32_join((0, self.mrn8_ip(), self.mrn8_ip(), self.mrn8_ip()))
+        u32_join((self.mrn8_ip(), self.mrn8_ip(), self.mrn8_ip(), 0))
     }
     pub fn mrn32_ip(&mut self) -> u32 {
         u32_join((
@@ -117,63 +315,239 @@ impl KMM0 {
             self.mrn8_ip(),
         ))
     }
-    pub fn mrn8_sp(&mut self) -> u8 {
-        self.clock();
-        let n = self.d_mem[self.sp_reg()];
-        self.reg[SP] -= 1;
-        return n;
+    /// Reads a `bytes` wide offset and adds it to the address of the offset
+    /// itself, or takes it away when `back`. The flag says the sum ran off
+    /// either end of the address space.
+    pub fn mrn_offset(&mut self, bytes: u8, back: bool) -> (u32, bool) {
+        let from = self.reg[IP];
+        let n = match bytes {
+            1 => self.mrn8_ip() as u32,
+            2 => self.mrn16_ip() as u32,
+            _ => self.mrn32_ip(),
+        };
+        if back {
+            return from.overflowing_sub(n);
+        }
+        from.overflowing_add(n)
     }
-    pub fn mrn16_sp(&mut self) -> u16 {
-        u32_join((0, 0, self.mrn8_sp(), self.mrn8_sp())) as u16
+    /// A relative jump, if it's `taken`. One that would leave the address
+    /// space faults instead of wrapping around.
+    pub fn jump_offset(&mut self, bytes: u8, back: bool, taken: bool) {
+        let (dest, out) = self.mrn_offset(bytes, back);
+        if !taken {
+            return;
+        }
+        if out {
+            self.fault(Fault::MemoryOutOfRange(dest));
+        } else {
+            self.reg[IP] = dest;
+        }
     }
-    pub fn mrn32_sp(&mut self) -> u32 {
-        u32_join((
-            self.mrn8_sp(),
-            self.mrn8_sp(),
-            self.mrn8_sp(),
-            self.mrn8_sp(),
-        ))
+    pub fn call_offset(&mut self, bytes: u8, back: bool) {
+        let (dest, out) = self.mrn_offset(bytes, back);
+        if out {
+            self.fault(Fault::MemoryOutOfRange(dest));
+        } else {
+            self.call(dest);
+        }
     }
     pub fn zpr(&mut self, ix: u8) -> u32 {
         self.clock();
         self.clock();
-        let ix = (ix as usize) * 4;
-        u32_join((
-            self.d_mem[ix + 0],
-            self.d_mem[ix + 1],
-            self.d_mem[ix + 2],
-            self.d_mem[ix + 3],
-        ))
+        self.bus_read(ix as u32 * 4, 4)
     }
     pub fn zpw(&mut self, v: u32, ix: u8) {
         self.clock();
         self.clock();
-        let ix = (ix as usize) * 4;
-        let b = u32_split(v);
-        self.d_mem[ix + 0] = b.0;
-        self.d_mem[ix + 1] = b.1;
-        self.d_mem[ix + 2] = b.2;
-        self.d_mem[ix + 3] = b.3;
-    }
-    pub fn mwn8_sp(&mut self) {
-        self.clock();
-        let ix = self.sp_reg();
-        self.d_mem[ix] = u32_split(self.acc).0;
-        self.reg[SP] += 1;
-    }
-    pub fn fault(&mut self, fault: u8) {
-        self.fault = fault;
-        self.int_enable = true;
-        self.int_idx = 0;
-    }
-    pub fn execute(&mut self) {
-        macro_rules! flush_debug {
-            () => {
-                if self.print_exec {
-                    println!("");
+        self.bus_write(ix as u32 * 4, 4, v);
+    }
+    /// Reads `n` bytes (1, 2 or 4) little endian from data memory, a cycle
+    /// each.
+    pub fn mem_read(&mut self, addr: u32, n: usize) -> u32 {
+        for _ in 0..n {
+            self.clock();
+        }
+        self.bus_read(addr, n)
+    }
+    /// Writes the low `n` bytes (1, 2 or 4) of `v` little endian to data
+    /// memory, a cycle each.
+    pub fn mem_write(&mut self, addr: u32, n: usize, v: u32) {
+        for _ in 0..n {
+            self.clock();
+        }
+        self.bus_write(addr, n, v);
+    }
+    /// Reads `n` bytes off the bus. Bytes that can't be read fault and
+    /// read as 0.
+    fn bus_read(&mut self, addr: u32, n: usize) -> u32 {
+        let mut v = 0;
+        for i in 0..n {
+            let a = addr.wrapping_add(i as u32);
+            match self.bus.read(a) {
+                Ok(b) => {
+                    v |= (b as u32) << (8 * i);
+                    self.note_access(a, Access::Read, b, b);
                 }
+                Err(e) => self.bus_fault(e, a),
+            }
+        }
+        v
+    }
+    fn bus_write(&mut self, addr: u32, n: usize, v: u32) {
+        for i in 0..n {
+            let a = addr.wrapping_add(i as u32);
+            let b = (v >> (8 * i)) as u8;
+            // The old value is only wanted by `change` watchpoints.
+            let watched = self
+                .watchpoints
+                .iter()
+                .any(|w| w.access == Access::Change && w.covers(a));
+            let old = match watched {
+                true => self.bus.peek(a).unwrap_or(b),
+                false => b,
+            };
+            match self.bus.write(a, b) {
+                Ok(()) => self.note_access(a, Access::Write, old, b),
+                Err(e) => self.bus_fault(e, a),
+            }
+        }
+    }
+    /// Records the access for every watchpoint it's caught by.
+    fn note_access(&mut self, addr: u32, access: Access, old: u8, new: u8) {
+        for w in &self.watchpoints {
+            let caught = match w.access {
+                Access::Change => access == Access::Write && old != new,
+                _ => w.access == access,
             };
+            if caught && w.covers(addr) {
+                self.watch_hits.push(WatchHit {
+                    watch: *w,
+                    addr,
+                    old,
+                    new,
+                });
+            }
+        }
+    }
+    fn bus_fault(&mut self, e: BusError, addr: u32) {
+        match e {
+            BusError::Unmapped => self.fault(Fault::MemoryOutOfRange(addr)),
+            BusError::ReadOnly => self.fault(Fault::WriteToRom(addr)),
+        }
+    }
+    /// Pushes the low `n` bytes of `v`. The stack is full descending: SP
+    /// moves down first, then the value is written at SP.
+    pub fn push(&mut self, v: u32, n: usize) {
+        let sp = self.reg[SP];
+        if sp < self.stack_limit + n as u32 {
+            self.fault(Fault::StackOverflow);
+            return;
         }
+        self.reg[SP] = sp - n as u32;
+        self.mem_write(self.reg[SP], n, v);
+    }
+    /// Pops `n` bytes, the reverse of `push`.
+    pub fn pop(&mut self, n: usize) -> u32 {
+        let sp = self.reg[SP];
+        if sp as u64 + n as u64 > self.stack_top as u64 {
+            self.fault(Fault::StackUnderflow);
+            return 0;
+        }
+        let v = self.mem_read(sp, n);
+        self.reg[SP] = sp + n as u32;
+        v
+    }
+    /// Pushes the address of the next instruction and jumps to `dest`.
+    pub fn call(&mut self, dest: u32) {
+        self.push(self.reg[IP], 4);
+        if self.fault.is_none() {
+            self.reg[IP] = dest;
+        }
+    }
+    pub fn print_char(&mut self, n: u32) {
+        self.clock();
+        match std::char::from_u32(n) {
+            Some(c) => self.console.write(c.encode_utf8(&mut [0; 4])),
+            None => self.fault(Fault::InvalidChar(n)),
+        }
+    }
+    /// Raises `fault`. The instruction still runs to the end, but only the
+    /// first fault it raises is reported.
+    pub fn fault(&mut self, fault: Fault) {
+        self.fault = self.fault.or(Some(fault));
+    }
+    /// Reads a register operand.
+    pub fn mrn_reg(&mut self) -> usize {
+        let r = self.mrn8_ip();
+        if r as usize >= self.reg.len() {
+            self.fault(Fault::InvalidRegister(r));
+            return 0;
+        }
+        r as usize
+    }
+    /// Raises IRQ `n`, 1-15. It stays pending until interrupts are enabled
+    /// and it isn't masked, and lower numbered IRQs are taken first.
+    pub fn raise_irq(&mut self, n: u8) {
+        assert!((1..IRQS).contains(&n), "there's no IRQ {}", n);
+        self.int_pending |= 1 << n;
+    }
+    /// Pushes `ret` and the flags, and jumps to `dest` with interrupts
+    /// disabled. `IRET` undoes it.
+    fn enter_interrupt(&mut self, ret: u32, dest: u32) {
+        let flags = (self.z as u32) | (self.c as u32) << 1 | (self.int_enable as u32) << 2;
+        self.push(ret, 4);
+        self.push(flags, 4);
+        self.int_enable = false;
+        self.reg[IP] = dest;
+    }
+    /// Hands the fault the instruction at `ip` raised to the guest's fault
+    /// handler. It stops the cpu instead if it's `HALT_AND_EXIT`, there's no
+    /// handler, or the handler is what faulted.
+    fn handle_fault(&mut self, ip: u32) -> Result<(), Halt> {
+        let fault = match self.fault.take() {
+            Some(fault) => fault,
+            None => return Ok(()),
+        };
+        let halt = Halt { fault, ip };
+        if fault == Fault::ExplicitHalt || self.fault_handler == 0 || self.in_fault {
+            return Err(halt);
+        }
+        self.in_fault = true;
+        self.fault_code = fault.code();
+        self.reg[RP] = ip;
+        self.enter_interrupt(self.reg[IP], self.fault_handler);
+        match self.fault.take() {
+            Some(_) => Err(halt), // no room on the stack to handle it
+            None => Ok(()),
+        }
+    }
+    /// Takes the highest priority pending interrupt if interrupts are
+    /// enabled, then executes one instruction.
+    pub fn step(&mut self) -> Result<(), Halt> {
+        let ready = self.int_pending & self.int_mask;
+        if self.int_enable && ready != 0 {
+            let n = ready.trailing_zeros();
+            self.int_pending &= !(1 << n);
+            let ip = self.reg[IP];
+            self.reg[RP] = ip;
+            self.enter_interrupt(ip, VECTORS + 8 * n);
+            self.handle_fault(ip)?;
+        }
+        self.execute()
+    }
+    /// Steps until the cpu faults, which includes `HALT_AND_EXIT`.
+    pub fn run(&mut self) -> Halt {
+        loop {
+            if let Err(halt) = self.step() {
+                return halt;
+            }
+        }
+    }
+    /// Executes the instruction at IP. A fault goes to the guest's fault
+    /// handler if it has one, and otherwise comes back as `Err`, after which
+    /// execution can carry on from the next instruction.
+    pub fn execute(&mut self) -> Result<(), Halt> {
+        let ip = self.reg[IP];
 
         macro_rules! set_flags {
             ($n:ident, $o:ident) => {
@@ -182,565 +556,1260 @@ impl KMM0 {
             };
         }
 
+        macro_rules! operand {
+            (reg) => {
+                self.reg[self.mrn_reg()]
+            };
+            (zp) => {{
+                let ix = self.mrn8_ip();
+                self.zpr(ix)
+            }};
+            (c8) => {
+                self.mrn8_ip() as u32
+            };
+            (c16) => {
+                self.mrn16_ip() as u32
+            };
+            (c32) => {
+                self.mrn32_ip()
+            };
+            (cptr) => {
+                self.mrn32_ip()
+            };
+            (rptr) => {
+                operand!(reg)
+            };
+        }
+
+        macro_rules! store {
+            (reg, $n:ident) => {{
+                let d = self.mrn_reg();
+                self.reg[d] = $n;
+            }};
+            (zp, $n:ident) => {{
+                let d = self.mrn8_ip();
+                self.zpw($n, d);
+            }};
+        }
+
+        // operands are read in encoding order, so the source of a write
+        // comes before its pointer
+        macro_rules! mem_op {
+            (read $p:ident $n:literal) => {{
+                let addr = operand!($p);
+                let n = self.mem_read(addr, $n);
+                store!(reg, n);
+            }};
+            (write $s:ident $p:ident $n:literal) => {{
+                let v = operand!($s);
+                let addr = operand!($p);
+                self.mem_write(addr, $n, v);
+            }};
+        }
+
+        macro_rules! stack_op {
+            (push $n:literal) => {{
+                let v = operand!(reg);
+                self.push(v, $n);
+            }};
+            (pop $n:literal) => {{
+                let r = self.mrn_reg();
+                let v = self.pop($n);
+                self.reg[r] = v;
+            }};
+        }
+
+        macro_rules! math {
+            // division, faults instead of dividing by zero
+            ($a:ident $b:ident $d:ident checked $op:ident) => {
+                let a = operand!($a);
+                let b = operand!($b);
+                match a.$op(b) {
+                    Some(n) => {
+                        store!($d, n);
+                        set_flags!(n, false);
+                    }
+                    None => {
+                        // skip the destination so IP is still at the next instruction
+                        self.mrn8_ip();
+                        self.fault(Fault::DivideByZero);
+                    }
+                }
+            };
+            ($a:ident $b:ident $d:ident $op:ident $($c:ident)?) => {
+                let a = operand!($a);
+                let b = operand!($b);
+                let (n, o) = a.$op(b, $(self.$c)?);
+                store!($d, n);
+                set_flags!(n, o);
+            };
+            (not $a:ident $d:ident) => {
+                let n = !operand!($a);
+                store!($d, n);
+                set_flags!(n, false);
+            };
+        }
+
         macro_rules! do_math {
-			(rrr $op:ident$( $c:ident)?) => {
-				let a = self.reg[self.mrn8_ip() as usize];
-				let b = self.reg[self.mrn8_ip() as usize];
-				let d = self.mrn8_ip() as usize;
-				let (n, o) = a.$op(b,$(self.$c)?);
-				self.reg[d] = n;
-				set_flags!(n, o);
-			};
-			(zrr $op:ident $($c:ident)?) => {
-				let zpix_a = self.mrn8_ip();
-				let a = self.zpr(zpix_a);
-				let b = self.reg[self.mrn8_ip() as usize];
-				let d = self.mrn8_ip() as usize;
-				let (n, o) = a.$op(b,$(self.$c)?);
-				self.reg[d] = n;
-				set_flags!(n, o);
-			};
-			(rzr $op:ident $($c:ident)?) => {
-				let a = self.reg[self.mrn8_ip() as usize];
-				let zpix_b = self.mrn8_ip();
-				let b = self.zpr(zpix_b);
-				let d = self.mrn8_ip() as usize;
-				let (n, o) = a.$op(b,$(self.$c)?);
-				self.reg[d] = n;
-				set_flags!(n, o);
-			};
-			(rrz $op:ident $($c:ident)?) => {
-				let a = self.reg[self.mrn8_ip() as usize];
-				let b = self.reg[self.mrn8_ip() as usize];
-				let d = self.mrn8_ip();
-				let (n, o) = a.$op(b,$(self.$c)?);
-				self.zpw(n, d);
-				set_flags!(n, o);
-			};
-			(rzz $op:ident $($c:ident)?) => {
-				let a = self.reg[self.mrn8_ip() as usize];
-				let zpix_b = self.mrn8_ip();
-				let b = self.zpr(zpix_b);
-				let d = self.mrn8_ip();
-				let (n, o) = a.$op(b,$(self.$c)?);
-				self.zpw(n, d);
-				set_flags!(n, o);
-			};
-			(zzz $op:ident $($c:ident)?) => {
-				let zpix_a = self.mrn8_ip();
-				let a = self.zpr(zpix_a);
-				let zpix_b = self.mrn8_ip();
-				let b = self.zpr(zpix_b);
-				let d = self.mrn8_ip();
-				let (n, o) = a.$op(b,$(self.$c)?);
-				self.zpw(n, d);
-				set_flags!(n, o);
-			};
-		}
-
-        // check for a fault
-        if self.fault != 0 {
-            println!(
-                "\n\n-------------------------\nHARDWARE FAULT- CODE 0x{:02X?}",
-                self.fault
-            );
-            panic!("hardware fault {:02X?}", self.fault);
-        }
-
-        // check for interrupts
-        if self.int_enable & (self.int_idx != 0) {
-            self.int_enable = false;
-            self.reg[RP] = self.reg[IP];
-            self.reg[IP] = 0x100 + (8 * (self.int_idx as u32));
+            (rrr $($t:tt)*) => { math!(reg reg reg $($t)*) };
+            (zrr $($t:tt)*) => { math!(zp reg reg $($t)*) };
+            (rzr $($t:tt)*) => { math!(reg zp reg $($t)*) };
+            (rrz $($t:tt)*) => { math!(reg reg zp $($t)*) };
+            (rzz $($t:tt)*) => { math!(reg zp zp $($t)*) };
+            (zzz $($t:tt)*) => { math!(zp zp zp $($t)*) };
+            (rcr $($t:tt)*) => { math!(reg c8 reg $($t)*) };
+            (zcr $($t:tt)*) => { math!(zp c8 reg $($t)*) };
+            (rcz $($t:tt)*) => { math!(reg c8 zp $($t)*) };
+            (zcz $($t:tt)*) => { math!(zp c8 zp $($t)*) };
+            (rr not) => { math!(not reg reg) };
+            (zr not) => { math!(not zp reg) };
+            (rz not) => { math!(not reg zp) };
+            (zz not) => { math!(not zp zp) };
         }
 
         // execute
         let i = self.mrn8_ip();
         match i {
-            0x00 => {}
-            0x02 => {
+            opcode!(Nop) => {}
+            JMP => {
                 // JUMP instructions
                 let v = self.mrn8_ip();
                 match &v {
-                    0x00 => self.reg[IP] = self.mrn24_ip(), // JMP const_24
-                    0x01 => self.reg[IP] = self.mrn32_ip(), // JMP const_32
-                    0x02 => self.reg[IP] = self.reg[IP] + (self.mrn8_ip() as u32), // JMP_offset +const_8
-                    0x03 => self.reg[IP] = self.reg[IP] - (self.mrn8_ip() as u32), // JMP_offset -const_8
-                    0x04 => self.reg[IP] = self.reg[IP] + (self.mrn16_ip() as u32), // JMP_offset +const_16
-                    0x05 => self.reg[IP] = self.reg[IP] - (self.mrn16_ip() as u32), // JMP_offset -const_16
-                    0x06 => self.reg[IP] = self.reg[IP] + self.mrn32_ip(), // JMP_offset +const_32
-                    0x07 => self.reg[IP] = self.reg[IP] - self.mrn32_ip(), // JMP_offset -const_32
-                    _ => self.fault(INVALID_INSTRUCTION_VARIANT),
+                    variant!(JmpConst24) => self.reg[IP] = self.mrn24_ip(), // JMP const_24
+                    variant!(JmpConst32) => self.reg[IP] = self.mrn32_ip(), // JMP const_32
+                    variant!(JmpOffsetA8) => self.jump_offset(1, false, true), // JMP_offset +const_8
+                    variant!(JmpOffsetS8) => self.jump_offset(1, true, true), // JMP_offset -const_8
+                    variant!(JmpOffsetA16) => self.jump_offset(2, false, true), // JMP_offset +const_16
+                    variant!(JmpOffsetS16) => self.jump_offset(2, true, true), // JMP_offset -const_16
+                    variant!(JmpOffsetA32) => self.jump_offset(4, false, true), // JMP_offset +const_32
+                    variant!(JmpOffsetS32) => self.jump_offset(4, true, true), // JMP_offset -const_32
+                    _ => self.fault(Fault::InvalidVariant(i, v)),
                 }
             }
-            0x03 => {
+            JMP_C => {
                 // CONDITIONAL JUMP instructions
                 let v = self.mrn8_ip();
                 match &v {
-                    0x00 => {
+                    variant!(JCConst24) => {
                         let j = self.mrn24_ip();
                         if self.c {
                             self.reg[IP] = j
                         }
                     } // JC const_24
-                    0x01 => {
+                    variant!(JCConst32) => {
                         let j = self.mrn32_ip();
                         if self.c {
                             self.reg[IP] = j
                         }
                     } // JC const_32
-                    0x02 => {
-                        let j = self.reg[IP] + (self.mrn8_ip() as u32);
-                        if self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JC_offset +const_8
-                    0x03 => {
-                        let j = self.reg[IP] - (self.mrn8_ip() as u32);
-                        if self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JC_offset -const_8
-                    0x04 => {
-                        let j = self.reg[IP] + (self.mrn16_ip() as u32);
-                        if self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JC_offset +const_16
-                    0x05 => {
-                        let j = self.reg[IP] - (self.mrn16_ip() as u32);
-                        if self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JC_offset -const_16
-                    0x06 => {
-                        let j = self.reg[IP] + self.mrn32_ip();
-                        if self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JC_offset +const_32
-                    0x07 => {
-                        let j = self.reg[IP] - self.mrn32_ip();
-                        if self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JC_offset -const_32
+                    variant!(JCOffsetA8) => self.jump_offset(1, false, self.c), // JC_offset +const_8
+                    variant!(JCOffsetS8) => self.jump_offset(1, true, self.c), // JC_offset -const_8
+                    variant!(JCOffsetA16) => self.jump_offset(2, false, self.c), // JC_offset +const_16
+                    variant!(JCOffsetS16) => self.jump_offset(2, true, self.c), // JC_offset -const_16
+                    variant!(JCOffsetA32) => self.jump_offset(4, false, self.c), // JC_offset +const_32
+                    variant!(JCOffsetS32) => self.jump_offset(4, true, self.c), // JC_offset -const_32
 
-                    0x08 => {
+                    variant!(JNCConst24) => {
                         let j = self.mrn24_ip();
                         if !self.c {
                             self.reg[IP] = j
                         }
                     } // JNC const_24
-                    0x09 => {
+                    variant!(JNCConst32) => {
                         let j = self.mrn32_ip();
                         if !self.c {
                             self.reg[IP] = j
                         }
                     } // JNC const_32
-                    0x0a => {
-                        let j = self.reg[IP] + (self.mrn8_ip() as u32);
-                        if !self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JNC_offset +const_8
-                    0x0b => {
-                        let j = self.reg[IP] - (self.mrn8_ip() as u32);
-                        if !self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JNC_offset -const_8
-                    0x0c => {
-                        let j = self.reg[IP] + (self.mrn16_ip() as u32);
-                        if !self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JNC_offset +const_16
-                    0x0d => {
-                        let j = self.reg[IP] - (self.mrn16_ip() as u32);
-                        if !self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JNC_offset -const_16
-                    0x0e => {
-                        let j = self.reg[IP] + self.mrn32_ip();
-                        if !self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JNC_offset +const_32
-                    0x0f => {
-                        let j = self.reg[IP] - self.mrn32_ip();
-                        if !self.c {
-                            self.reg[IP] = j
-                        }
-                    } // JNC_offset -const_32
+                    variant!(JNCOffsetA8) => self.jump_offset(1, false, !self.c), // JNC_offset +const_8
+                    variant!(JNCOffsetS8) => self.jump_offset(1, true, !self.c), // JNC_offset -const_8
+                    variant!(JNCOffsetA16) => self.jump_offset(2, false, !self.c), // JNC_offset +const_16
+                    variant!(JNCOffsetS16) => self.jump_offset(2, true, !self.c), // JNC_offset -const_16
+                    variant!(JNCOffsetA32) => self.jump_offset(4, false, !self.c), // JNC_offset +const_32
+                    variant!(JNCOffsetS32) => self.jump_offset(4, true, !self.c), // JNC_offset -const_32
 
-                    0x10 => {
+                    variant!(JZConst24) => {
                         let j = self.mrn24_ip();
                         if self.z {
                             self.reg[IP] = j
                         }
                     } // JZ const_24
-                    0x11 => {
+                    variant!(JZConst32) => {
                         let j = self.mrn32_ip();
                         if self.z {
                             self.reg[IP] = j
                         }
                     } // JZ const_32
-                    0x12 => {
-                        let j = self.reg[IP] + (self.mrn8_ip() as u32);
-                        if self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JZ_offset +const_8
-                    0x13 => {
-                        let j = self.reg[IP] - (self.mrn8_ip() as u32);
-                        if self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JZ_offset -const_8
-                    0x14 => {
-                        let j = self.reg[IP] + (self.mrn16_ip() as u32);
-                        if self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JZ_offset +const_16
-                    0x15 => {
-                        let j = self.reg[IP] - (self.mrn16_ip() as u32);
-                        if self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JZ_offset -const_16
-                    0x16 => {
-                        let j = self.reg[IP] + self.mrn32_ip();
-                        if self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JZ_offset +const_32
-                    0x17 => {
-                        let j = self.reg[IP] - self.mrn32_ip();
-                        if self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JZ_offset -const_32
+                    variant!(JZOffsetA8) => self.jump_offset(1, false, self.z), // JZ_offset +const_8
+                    variant!(JZOffsetS8) => self.jump_offset(1, true, self.z), // JZ_offset -const_8
+                    variant!(JZOffsetA16) => self.jump_offset(2, false, self.z), // JZ_offset +const_16
+                    variant!(JZOffsetS16) => self.jump_offset(2, true, self.z), // JZ_offset -const_16
+                    variant!(JZOffsetA32) => self.jump_offset(4, false, self.z), // JZ_offset +const_32
+                    variant!(JZOffsetS32) => self.jump_offset(4, true, self.z), // JZ_offset -const_32
 
-                    0x18 => {
+                    variant!(JNZConst24) => {
                         let j = self.mrn24_ip();
                         if !self.z {
                             self.reg[IP] = j
                         }
                     } // JNZ const_24
-                    0x19 => {
+                    variant!(JNZConst32) => {
                         let j = self.mrn32_ip();
                         if !self.z {
                             self.reg[IP] = j
                         }
                     } // JNZ const_32
-                    0x1a => {
-                        let j = self.reg[IP] + (self.mrn8_ip() as u32);
-                        if !self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JNZ_offset +const_8
-                    0x1b => {
-                        let j = self.reg[IP] - (self.mrn8_ip() as u32);
-                        if !self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JNZ_offset -const_8
-                    0x1c => {
-                        let j = self.reg[IP] + (self.mrn16_ip() as u32);
-                        if !self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JNZ_offset +const_16
-                    0x1d => {
-                        let j = self.reg[IP] - (self.mrn16_ip() as u32);
-                        if !self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JNZ_offset -const_16
-                    0x1e => {
-                        let j = self.reg[IP] + self.mrn32_ip();
-                        if !self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JNZ_offset +const_32
-                    0x1f => {
-                        let j = self.reg[IP] - self.mrn32_ip();
-                        if !self.z {
-                            self.reg[IP] = j
-                        }
-                    } // JNZ_offset -const_32
+                    variant!(JNZOffsetA8) => self.jump_offset(1, false, !self.z), // JNZ_offset +const_8
+                    variant!(JNZOffsetS8) => self.jump_offset(1, true, !self.z), // JNZ_offset -const_8
+                    variant!(JNZOffsetA16) => self.jump_offset(2, false, !self.z), // JNZ_offset +const_16
+                    variant!(JNZOffsetS16) => self.jump_offset(2, true, !self.z), // JNZ_offset -const_16
+                    variant!(JNZOffsetA32) => self.jump_offset(4, false, !self.z), // JNZ_offset +const_32
+                    variant!(JNZOffsetS32) => self.jump_offset(4, true, !self.z), // JNZ_offset -const_32
 
-                    _ => self.fault(INVALID_INSTRUCTION_VARIANT),
+                    _ => self.fault(Fault::InvalidVariant(i, v)),
                 }
             }
-            0x04 => {
+            MATH => {
                 // MATH instructions
                 let v = self.mrn8_ip();
                 match &v {
-                    0x00 => {
+                    variant!(AddRRR) => {
                         // ADD reg + reg -> reg
                         do_math!(rrr overflowing_add);
                     }
-                    0x01 => {
+                    variant!(AddZRR) => {
                         // ADD zpr + reg -> reg
                         do_math!(zrr overflowing_add);
                     }
-                    0x02 => {
+                    variant!(IncR1) => {
                         // INC reg += 1
-                        let a = self.mrn8_ip() as usize;
+                        let a = self.mrn_reg();
                         let (n, o) = self.reg[a].overflowing_add(1);
                         self.reg[a] = n;
                         set_flags!(n, o);
                     }
-                    0x03 => {
+                    variant!(AddRRZ) => {
                         // ADD reg + reg -> zpr
                         do_math!(rrz overflowing_add);
                     }
-                    0x04 => {
+                    variant!(AddRZZ) => {
                         // ADD reg + zpr -> zpr
                         do_math!(rzz overflowing_add);
                     }
-                    0x05 => {
+                    variant!(AddZZZ) => {
                         // ADD zpr + zpr -> zpr
                         do_math!(zzz overflowing_add);
                     }
 
-                    0x06 => {
+                    variant!(AddcRRR) => {
                         // ADDc reg +c reg -> reg
                         do_math!(rrr carrying_add c);
                     }
-                    0x07 => {
+                    variant!(AddcZRR) => {
                         // ADDc zpr +c reg -> reg
                         do_math!(zrr carrying_add c);
                     }
-                    0x08 => {
+                    variant!(IncR4) => {
                         // INC reg += 4
-                        let a = self.mrn8_ip() as usize;
+                        let a = self.mrn_reg();
                         let (n, o) = self.reg[a].overflowing_add(4);
                         self.reg[a] = n;
                         set_flags!(n, o);
                     }
-                    0x09 => {
+                    variant!(AddcRRZ) => {
                         // ADDc reg +c reg -> zpr
                         do_math!(rrz carrying_add c);
                     }
-                    0x0a => {
+                    variant!(AddcRZZ) => {
                         // ADDc reg +c zpr -> zpr
                         do_math!(rzz carrying_add c);
                     }
-                    0x0b => {
+                    variant!(AddcZZZ) => {
                         // ADDc zpr +c zpr -> zpr
                         do_math!(zzz carrying_add c);
                     }
-                    0x0c => {
+                    variant!(SubRRR) => {
                         // SUB reg - reg -> reg
                         do_math!(rrr overflowing_sub);
                     }
-                    0x0d => {
+                    variant!(SubZRR) => {
                         // SUB zpr - reg -> reg
                         do_math!(zrr overflowing_sub);
                     }
-                    0x0e => {
+                    variant!(SubRZR) => {
                         // SUB reg - zpr -> reg
                         do_math!(rzr overflowing_sub);
                     }
-                    0x0f => {
+                    variant!(SubRRZ) => {
                         // SUB reg - reg -> zpr
                         do_math!(rrz overflowing_sub);
                     }
-                    0x10 => {
+                    variant!(SubRZZ) => {
                         // SUB reg - zpr -> zpr
                         do_math!(rzz overflowing_sub);
                     }
-                    0x11 => {
+                    variant!(SubZZZ) => {
                         // SUB zpr - zpr -> zpr
                         do_math!(zzz overflowing_sub);
                     }
-                    0x12 => {
+                    variant!(SubbRRR) => {
                         // SUBb reg -b reg -> reg
                         do_math!(rrr borrowing_sub c);
                     }
-                    0x13 => {
+                    variant!(SubbZRR) => {
                         // SUBb zpr -b reg -> reg
                         do_math!(zrr borrowing_sub c);
                     }
-                    0x14 => {
+                    variant!(SubbRZR) => {
                         // SUBb reg -b zpr -> reg
                         do_math!(rzr borrowing_sub c);
                     }
-                    0x15 => {
+                    variant!(SubbRRZ) => {
                         // SUBb reg - reg -> zpr
                         do_math!(rrz borrowing_sub c);
                     }
-                    0x16 => {
+                    variant!(SubbRZZ) => {
                         // SUBb reg - zpr -> zpr
                         do_math!(rzz borrowing_sub c);
                     }
-                    0x17 => {
+                    variant!(SubbZZZ) => {
                         // SUBb zpr - zpr -> zpr
                         do_math!(zzz borrowing_sub c);
                     }
+                    variant!(MulRRR) => {
+                        // MUL reg * reg -> reg
+                        do_math!(rrr overflowing_mul);
+                    }
+                    variant!(MulZRR) => {
+                        // MUL zpr * reg -> reg
+                        do_math!(zrr overflowing_mul);
+                    }
+                    variant!(MulRRZ) => {
+                        // MUL reg * reg -> zpr
+                        do_math!(rrz overflowing_mul);
+                    }
+                    variant!(MulRZZ) => {
+                        // MUL reg * zpr -> zpr
+                        do_math!(rzz overflowing_mul);
+                    }
+                    variant!(MulZZZ) => {
+                        // MUL zpr * zpr -> zpr
+                        do_math!(zzz overflowing_mul);
+                    }
+                    variant!(DivRRR) => {
+                        // DIV reg / reg -> reg
+                        do_math!(rrr checked checked_div);
+                    }
+                    variant!(DivZRR) => {
+                        // DIV zpr / reg -> reg
+                        do_math!(zrr checked checked_div);
+                    }
+                    variant!(DivRZR) => {
+                        // DIV reg / zpr -> reg
+                        do_math!(rzr checked checked_div);
+                    }
+                    variant!(DivRRZ) => {
+                        // DIV reg / reg -> zpr
+                        do_math!(rrz checked checked_div);
+                    }
+                    variant!(DivRZZ) => {
+                        // DIV reg / zpr -> zpr
+                        do_math!(rzz checked checked_div);
+                    }
+                    variant!(DivZZZ) => {
+                        // DIV zpr / zpr -> zpr
+                        do_math!(zzz checked checked_div);
+                    }
+                    variant!(AndRRR) => {
+                        // AND reg & reg -> reg
+                        do_math!(rrr and_c);
+                    }
+                    variant!(AndZRR) => {
+                        // AND zpr & reg -> reg
+                        do_math!(zrr and_c);
+                    }
+                    variant!(AndRRZ) => {
+                        // AND reg & reg -> zpr
+                        do_math!(rrz and_c);
+                    }
+                    variant!(AndRZZ) => {
+                        // AND reg & zpr -> zpr
+                        do_math!(rzz and_c);
+                    }
+                    variant!(AndZZZ) => {
+                        // AND zpr & zpr -> zpr
+                        do_math!(zzz and_c);
+                    }
+                    variant!(OrRRR) => {
+                        // OR reg | reg -> reg
+                        do_math!(rrr or_c);
+                    }
+                    variant!(OrZRR) => {
+                        // OR zpr | reg -> reg
+                        do_math!(zrr or_c);
+                    }
+                    variant!(OrRRZ) => {
+                        // OR reg | reg -> zpr
+                        do_math!(rrz or_c);
+                    }
+                    variant!(OrRZZ) => {
+                        // OR reg | zpr -> zpr
+                        do_math!(rzz or_c);
+                    }
+                    variant!(OrZZZ) => {
+                        // OR zpr | zpr -> zpr
+                        do_math!(zzz or_c);
+                    }
+                    variant!(XorRRR) => {
+                        // XOR reg ^ reg -> reg
+                        do_math!(rrr xor_c);
+                    }
+                    variant!(XorZRR) => {
+                        // XOR zpr ^ reg -> reg
+                        do_math!(zrr xor_c);
+                    }
+                    variant!(XorRRZ) => {
+                        // XOR reg ^ reg -> zpr
+                        do_math!(rrz xor_c);
+                    }
+                    variant!(XorRZZ) => {
+                        // XOR reg ^ zpr -> zpr
+                        do_math!(rzz xor_c);
+                    }
+                    variant!(XorZZZ) => {
+                        // XOR zpr ^ zpr -> zpr
+                        do_math!(zzz xor_c);
+                    }
+                    variant!(NotRR) => {
+                        // NOT !reg -> reg
+                        do_math!(rr not);
+                    }
+                    variant!(NotZR) => {
+                        // NOT !zpr -> reg
+                        do_math!(zr not);
+                    }
+                    variant!(NotRZ) => {
+                        // NOT !reg -> zpr
+                        do_math!(rz not);
+                    }
+                    variant!(NotZZ) => {
+                        // NOT !zpr -> zpr
+                        do_math!(zz not);
+                    }
+                    variant!(DecR1) => {
+                        // DEC reg -= 1
+                        let a = self.mrn_reg();
+                        let (n, o) = self.reg[a].overflowing_sub(1);
+                        self.reg[a] = n;
+                        set_flags!(n, o);
+                    }
+                    variant!(DecR4) => {
+                        // DEC reg -= 4
+                        let a = self.mrn_reg();
+                        let (n, o) = self.reg[a].overflowing_sub(4);
+                        self.reg[a] = n;
+                        set_flags!(n, o);
+                    }
+                    variant!(RemRRR) => {
+                        // REM reg % reg -> reg
+                        do_math!(rrr checked checked_rem);
+                    }
+                    variant!(RemZRR) => {
+                        // REM zpr % reg -> reg
+                        do_math!(zrr checked checked_rem);
+                    }
+                    variant!(RemRZR) => {
+                        // REM reg % zpr -> reg
+                        do_math!(rzr checked checked_rem);
+                    }
+                    variant!(RemRRZ) => {
+                        // REM reg % reg -> zpr
+                        do_math!(rrz checked checked_rem);
+                    }
+                    variant!(RemRZZ) => {
+                        // REM reg % zpr -> zpr
+                        do_math!(rzz checked checked_rem);
+                    }
+                    variant!(RemZZZ) => {
+                        // REM zpr % zpr -> zpr
+                        do_math!(zzz checked checked_rem);
+                    }
+                    variant!(ShlRRR) => {
+                        // SHL reg << reg -> reg
+                        do_math!(rrr shl_c);
+                    }
+                    variant!(ShlZRR) => {
+                        // SHL zpr << reg -> reg
+                        do_math!(zrr shl_c);
+                    }
+                    variant!(ShlRCR) => {
+                        // SHL reg << const_8 -> reg
+                        do_math!(rcr shl_c);
+                    }
+                    variant!(ShlZCR) => {
+                        // SHL zpr << const_8 -> reg
+                        do_math!(zcr shl_c);
+                    }
+                    variant!(ShlRCZ) => {
+                        // SHL reg << const_8 -> zpr
+                        do_math!(rcz shl_c);
+                    }
+                    variant!(ShlZCZ) => {
+                        // SHL zpr << const_8 -> zpr
+                        do_math!(zcz shl_c);
+                    }
+                    variant!(ShrRRR) => {
+                        // SHR reg >> reg -> reg
+                        do_math!(rrr shr_c);
+                    }
+                    variant!(ShrZRR) => {
+                        // SHR zpr >> reg -> reg
+                        do_math!(zrr shr_c);
+                    }
+                    variant!(ShrRCR) => {
+                        // SHR reg >> const_8 -> reg
+                        do_math!(rcr shr_c);
+                    }
+                    variant!(ShrZCR) => {
+                        // SHR zpr >> const_8 -> reg
+                        do_math!(zcr shr_c);
+                    }
+                    variant!(ShrRCZ) => {
+                        // SHR reg >> const_8 -> zpr
+                        do_math!(rcz shr_c);
+                    }
+                    variant!(ShrZCZ) => {
+                        // SHR zpr >> const_8 -> zpr
+                        do_math!(zcz shr_c);
+                    }
 
-                    _ => self.fault(INVALID_INSTRUCTION_VARIANT),
+                    _ => self.fault(Fault::InvalidVariant(i, v)),
                 }
             }
-            0x05 => {
+            STACK => {
                 // STACK instructions
                 let v = self.mrn8_ip();
+                match &v {
+                    variant!(Push8) => stack_op!(push 1),  // PUSH_8 reg
+                    variant!(Push16) => stack_op!(push 2), // PUSH_16 reg
+                    variant!(Push32) => stack_op!(push 4), // PUSH_32 reg
+                    variant!(Pop8) => stack_op!(pop 1),    // POP_8 reg
+                    variant!(Pop16) => stack_op!(pop 2),   // POP_16 reg
+                    variant!(Pop32) => stack_op!(pop 4),   // POP_32 reg
+                    variant!(CallConst24) => {
+                        // CALL const_24
+                        let dest = self.mrn24_ip();
+                        self.call(dest);
+                    }
+                    variant!(CallConst32) => {
+                        // CALL const_32
+                        let dest = self.mrn32_ip();
+                        self.call(dest);
+                    }
+                    variant!(CallOffsetA8) => {
+                        // CALL_OFFSET +const_8
+                        self.call_offset(1, false);
+                    }
+                    variant!(CallOffsetS8) => {
+                        // CALL_OFFSET -const_8
+                        self.call_offset(1, true);
+                    }
+                    variant!(CallOffsetA16) => {
+                        // CALL_OFFSET +const_16
+                        self.call_offset(2, false);
+                    }
+                    variant!(CallOffsetS16) => {
+                        // CALL_OFFSET -const_16
+                        self.call_offset(2, true);
+                    }
+                    variant!(CallOffsetA32) => {
+                        // CALL_OFFSET +const_32
+                        self.call_offset(4, false);
+                    }
+                    variant!(CallOffsetS32) => {
+                        // CALL_OFFSET -const_32
+                        self.call_offset(4, true);
+                    }
+                    variant!(CallR) => {
+                        // CALL reg
+                        let dest = operand!(reg);
+                        self.call(dest);
+                    }
+                    variant!(Ret) => {
+                        // RET
+                        let dest = self.pop(4);
+                        if self.fault.is_none() {
+                            self.reg[IP] = dest;
+                        }
+                    }
+                    _ => self.fault(Fault::InvalidVariant(i, v)),
+                }
             }
-            0x06 => {
+            IO => {
                 // IO instructions
                 let v = self.mrn8_ip();
                 match &v {
-                    0x00 => {
-                        // PRINT_CHAR Acc
-                        self.clock();
-                        print!(
-                            "{}",
-                            std::char::from_u32(self.acc).unwrap_or_else(|| {
-                                self.fault(INVALID_CHAR);
-                                ' '
-                            })
-                        );
-                        io::stdout().flush().unwrap();
-                    }
-                    0x01 => {
+                    variant!(PrintCharR) => {
                         // PRINT_CHAR reg
+                        let n = self.reg[self.mrn_reg()];
+                        self.print_char(n);
+                    }
+                    variant!(PrintCharC8) => {
+                        // PRINT_CHAR const_8
+                        let n = self.mrn8_ip() as u32;
+                        self.print_char(n);
+                    }
+                    variant!(ReadChar) => {
+                        // READ_CHAR -> reg
+                        let d = self.mrn_reg();
                         self.clock();
-                        print!(
-                            "{}",
-                            std::char::from_u32(self.reg[self.mrn8_ip() as usize]).unwrap_or_else(
-                                || {
-                                    self.fault(INVALID_CHAR);
-                                    ' '
-                                }
-                            )
-                        );
-                        io::stdout().flush().unwrap();
-                    }
-                    _ => self.fault(INVALID_INSTRUCTION_VARIANT),
+                        let key = self.keyboard.read();
+                        self.z = key.is_none();
+                        self.reg[d] = key.unwrap_or(0) as u32;
+                    }
+                    variant!(KeyAvailable) => {
+                        // KEY_AVAILABLE -> reg
+                        let d = self.mrn_reg();
+                        let n = self.keyboard.available() as u32;
+                        self.z = n == 0;
+                        self.reg[d] = n;
+                    }
+                    _ => self.fault(Fault::InvalidVariant(i, v)),
+                }
+            }
+            MEMORY => {
+                // MEMORY instructions
+                let v = self.mrn8_ip();
+                match &v {
+                    variant!(Read8CptrR) => mem_op!(read cptr 1),          // READ_8 [const_32] -> reg
+                    variant!(Read16CptrR) => mem_op!(read cptr 2),         // READ_16 [const_32] -> reg
+                    variant!(Read32CptrR) => mem_op!(read cptr 4),         // READ_32 [const_32] -> reg
+                    variant!(Read8RptrR) => mem_op!(read rptr 1),          // READ_8 [reg] -> reg
+                    variant!(Read16RptrR) => mem_op!(read rptr 2),         // READ_16 [reg] -> reg
+                    variant!(Read32RptrR) => mem_op!(read rptr 4),         // READ_32 [reg] -> reg
+                    variant!(Write8RCptr) => mem_op!(write reg cptr 1),    // WRITE_8 reg -> [const_32]
+                    variant!(Write16RCptr) => mem_op!(write reg cptr 2),   // WRITE_16 reg -> [const_32]
+                    variant!(Write32RCptr) => mem_op!(write reg cptr 4),   // WRITE_32 reg -> [const_32]
+                    variant!(Write8RRptr) => mem_op!(write reg rptr 1),    // WRITE_8 reg -> [reg]
+                    variant!(Write16RRptr) => mem_op!(write reg rptr 2),   // WRITE_16 reg -> [reg]
+                    variant!(Write32RRptr) => mem_op!(write reg rptr 4),   // WRITE_32 reg -> [reg]
+                    variant!(Write8C8Cptr) => mem_op!(write c8 cptr 1),    // WRITE_8 const_8 -> [const_32]
+                    variant!(Write8C8Rptr) => mem_op!(write c8 rptr 1),    // WRITE_8 const_8 -> [reg]
+                    variant!(Write16C16Cptr) => mem_op!(write c16 cptr 2), // WRITE_16 const_16 -> [const_32]
+                    variant!(Write16C16Rptr) => mem_op!(write c16 rptr 2), // WRITE_16 const_16 -> [reg]
+                    variant!(Write32C32Cptr) => mem_op!(write c32 cptr 4), // WRITE_32 const_32 -> [const_32]
+                    variant!(Write32C32Rptr) => mem_op!(write c32 rptr 4), // WRITE_32 const_32 -> [reg]
+                    _ => self.fault(Fault::InvalidVariant(i, v)),
                 }
             }
-            0x10 => self.reg[self.mrn8_ip() as usize] = self.reg[self.mrn8_ip() as usize], // MOV reg <= reg
-            0x13 => {
+            opcode!(MovRR) => self.reg[self.mrn_reg()] = self.reg[self.mrn_reg()], // MOV reg <= reg
+            opcode!(MovR4R4) => {
+                // MOV reg <- 4br; reg <- 4br
+                let s = self.mrn8_ip() as usize;
+                let (a, b) = (self.reg[s & 0xf], self.reg[s >> 4]);
+                let (c, d) = (self.mrn_reg(), self.mrn_reg());
+                self.reg[c] = a;
+                self.reg[d] = b;
+            }
+            opcode!(Mov4R4R) => {
+                // MOV 4br <- reg; 4br <- reg
+                let (a, b) = (self.mrn_reg(), self.mrn_reg());
+                let (a, b) = (self.reg[a], self.reg[b]);
+                let d = self.mrn8_ip() as usize;
+                self.reg[d & 0xf] = a;
+                self.reg[d >> 4] = b;
+            }
+            opcode!(Mov44) => {
                 let r = self.mrn8_ip() as usize;
                 self.reg[r >> 4] = self.reg[r & 0b1111]
             } // MOV 4br <= 4br
-            0x14 => self.reg[self.mrn8_ip() as usize] = self.mrn8_ip() as u32, // CONST u8 -> reg
-            0x15 => self.reg[self.mrn8_ip() as usize] = self.mrn16_ip() as u32, // CONST u16 -> reg
-            0x16 => self.reg[self.mrn8_ip() as usize] = self.mrn32_ip() as u32, // CONST u32 -> reg
-
-            0x20 => self.z = true,           // SET_ZF
-            0x21 => self.z = false,          // CLR_ZF
-            0x22 => self.c = true,           // SET_CF
-            0x23 => self.c = false,          // CLR_CF
-            0x24 => self.int_enable = true,  // INT_ENABLE
-            0x45 => self.int_enable = false, // INT_DISABLE
-
-            0x2c => self.reg[0] = self.reg[1], // MOV r1 -> r0
-            0x2d => self.reg[1] = self.reg[0], // MOV r0 -> r1
-            0x2e => self.reg[0] = self.reg[1], // MOV r2 -> r0
-            0x2f => self.reg[1] = self.reg[2], // MOV r2 -> r1
-            0x30 => {
+            opcode!(ConstRegU8) => self.reg[self.mrn_reg()] = self.mrn8_ip() as u32, // CONST u8 -> reg
+            opcode!(ConstRegU16) => self.reg[self.mrn_reg()] = self.mrn16_ip() as u32, // CONST u16 -> reg
+            opcode!(ConstRegU32) => self.reg[self.mrn_reg()] = self.mrn32_ip(), // CONST u32 -> reg
+
+            opcode!(SetZF) => self.z = true,           // SET_ZF
+            opcode!(ClrZF) => self.z = false,          // CLR_ZF
+            opcode!(SetCF) => self.c = true,           // SET_CF
+            opcode!(ClrCF) => self.c = false,          // CLR_CF
+            opcode!(IntEnable) => self.int_enable = true,  // INT_ENABLE
+            opcode!(IntDisable) => self.int_enable = false, // INT_DISABLE
+            opcode!(Iret) => {
+                // IRET
+                let flags = self.pop(4);
+                let ip = self.pop(4);
+                if self.fault.is_none() {
+                    self.z = flags & 1 != 0;
+                    self.c = flags & 2 != 0;
+                    self.int_enable = flags & 4 != 0;
+                    self.reg[IP] = ip;
+                    self.in_fault = false;
+                }
+            }
+            opcode!(IntMask) => self.int_mask = self.reg[self.mrn_reg()] as u16 & 0xfffe, // INT_MASK reg
+            opcode!(IntPending) => self.reg[self.mrn_reg()] = self.int_pending as u32, // INT_PENDING -> reg
+            opcode!(FaultHandler) => self.fault_handler = self.reg[self.mrn_reg()], // FAULT_HANDLER reg
+            opcode!(FaultCode) => self.reg[self.mrn_reg()] = self.fault_code as u32, // FAULT_CODE -> reg
+
+            opcode!(MovR1R0) => self.reg[0] = self.reg[1], // MOV r1 -> r0
+            opcode!(MovR0R1) => self.reg[1] = self.reg[0], // MOV r0 -> r1
+            opcode!(MovR2R0) => self.reg[0] = self.reg[2], // MOV r2 -> r0
+            opcode!(MovR2R1) => self.reg[1] = self.reg[2], // MOV r2 -> r1
+            opcode!(AddR0R1R2) => {
                 // ADD r0 + r1 -> r2
                 let (n, o) = self.reg[0].overflowing_add(self.reg[1]);
                 self.reg[2] = n;
                 set_flags!(n, o);
             }
-            0x31 => {
+            opcode!(AddcR0R1R2) => {
                 // ADDc r0 + r1 -> r2
                 let (n, o) = self.reg[0].carrying_add(self.reg[1], self.c);
                 self.reg[2] = n;
                 set_flags!(n, o);
             }
-            0x32 => {
-                // SUB r0 + r1 -> r2
+            opcode!(SubR0R1R2) => {
+                // SUB r0 - r1 -> r2
                 let (n, o) = self.reg[0].overflowing_sub(self.reg[1]);
                 self.reg[2] = n;
                 set_flags!(n, o);
             }
-            0x33 => {
-                // SUBb r0 + r1 -> r2
+            opcode!(SubbR0R1R2) => {
+                // SUBb r0 - r1 -> r2
                 let (n, o) = self.reg[0].borrowing_sub(self.reg[1], self.c);
                 self.reg[2] = n;
                 set_flags!(n, o);
             }
-			0x34 => {
-				// MUL r0 * r1 -> r2
-				let (n, o) = self.reg[0].overflowing_mul(self.reg[1]);
-				self.reg[2] = n;
-				set_flags!(n, o);
-			}
-			0x35 => {
-				// DIV r0 / r1 -> r2
-				let (n, o) = self.reg[0].overflowing_div(self.reg[1]);
-				self.reg[2] = n;
-				set_flags!(n, o);
-			}
-			0x36 => {
-				// AND r0 & r1 -> r2
-				let n = self.reg[0] & self.reg[1];
-				self.reg[2] = n;
-				set_flags!(n, false);
-			}
-			0x37 => {
-				// OR r0 & r1 -> r2
-				let n = self.reg[0] | self.reg[1];
-				self.reg[2] = n;
-				set_flags!(n, false);
-			}
-			0x38 => {
-				// XOR r0 & r1 -> r2
-				let n = self.reg[0] ^ self.reg[1];
-				self.reg[2] = n;
-				set_flags!(n, false);
-			}
-			0x39 => {
-				// NOT !r0 -> r2
-				let n = !self.reg[0];
-				self.reg[2] = n;
-				set_flags!(n, false);
-			}
-			0x3a => {
-				// DIV r0 / r1 -> r2
-				let n = self.reg[0] % self.reg[1];
-				self.reg[2] = n;
-				set_flags!(n, false);
-			}
-			
-            0xFE => {
-                // HALT & EXIT
-                self.fault(EXPLICIT_HALT_AND_EXIT);
+            opcode!(MulR0R1R2) => {
+                // MUL r0 * r1 -> r2
+                let (n, o) = self.reg[0].overflowing_mul(self.reg[1]);
+                self.reg[2] = n;
+                set_flags!(n, o);
             }
-            0xFF => {
-                // DEBUG INSTRUCTIONS
-                let v = self.mrn8_ip();
-                if self.print_exec {
-                    println!("")
+            opcode!(DivR0R1R2) => {
+                // DIV r0 / r1 -> r2
+                match self.reg[0].checked_div(self.reg[1]) {
+                    Some(n) => {
+                        self.reg[2] = n;
+                        set_flags!(n, false);
+                    }
+                    None => self.fault(Fault::DivideByZero),
                 }
-                match &v {
-                    0x00 => {
-                        println!("\n{:08x?}", self.acc);
-                    } // PRINT ACC
-                    0x01 => {
-                        println!("\n{:08x?}", self.reg[self.mrn8_ip() as usize])
-                    } // PRINT reg
-                    0x02 => {
-                        for i in 0..16 {
-                            println!("{:x?} = {:08x?}", i, self.reg[i])
-                        }
-                        println!("cf= {}", self.c);
-                        println!("zf= {}", self.z);
-                        println!("ac= {:08x?}", self.acc)
-                    } // PRINT_ALL_REG
-                    _ => self.fault(INVALID_INSTRUCTION_VARIANT),
+            }
+            opcode!(AndR0R1R2) => {
+                // AND r0 & r1 -> r2
+                let n = self.reg[0] & self.reg[1];
+                self.reg[2] = n;
+                set_flags!(n, false);
+            }
+            opcode!(OrR0R1R2) => {
+                // OR r0 | r1 -> r2
+                let n = self.reg[0] | self.reg[1];
+                self.reg[2] = n;
+                set_flags!(n, false);
+            }
+            opcode!(XorR0R1R2) => {
+                // XOR r0 ^ r1 -> r2
+                let n = self.reg[0] ^ self.reg[1];
+                self.reg[2] = n;
+                set_flags!(n, false);
+            }
+            opcode!(NotR0R2) => {
+                // NOT !r0 -> r2
+                let n = !self.reg[0];
+                self.reg[2] = n;
+                set_flags!(n, false);
+            }
+            opcode!(RemR0R1R2) => {
+                // REM r0 % r1 -> r2
+                match self.reg[0].checked_rem(self.reg[1]) {
+                    Some(n) => {
+                        self.reg[2] = n;
+                        set_flags!(n, false);
+                    }
+                    None => self.fault(Fault::DivideByZero),
                 }
             }
-            _ => self.fault(INVALID_INSTRUCTION),
+
+            opcode!(ExplicitHaltAndExit) => {
+                // HALT & EXIT
+                self.fault(Fault::ExplicitHalt);
+            }
+            _ => self.fault(Fault::InvalidInstruction(i)),
         };
-        flush_debug!();
+        self.handle_fault(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Ver0;
+
+    /// A cpu with 4 KiB of RAM, ready to run `program`.
+    fn cpu(program: &str) -> KMM0 {
+        let code = Ver0::new().assemble_str("test.kasm", program).unwrap();
+        KMM0::new()
+            .bus(Bus::new().ram(0, 0x1000))
+            .console(Box::new(console::Buffer::new()))
+            .clock_speed_hz(0.)
+            .load_code(code)
+    }
+
+    /// Runs `body`, which starts at 0x100, until it halts.
+    fn run(body: &str) -> (KMM0, Halt) {
+        let mut cpu = cpu(&format!("\t.org 0x100\n{}\n\tHALT_AND_EXIT\n", body));
+        let halt = cpu.run();
+        (cpu, halt)
+    }
+
+    /// Runs `body` and checks that it stopped at its HALT_AND_EXIT.
+    fn halts(body: &str) -> KMM0 {
+        let (cpu, halt) = run(body);
+        assert_eq!(halt.fault, Fault::ExplicitHalt, "{}", halt);
+        cpu
+    }
+
+    /// The little endian u32 at `addr`.
+    fn word(cpu: &KMM0, addr: u32) -> u32 {
+        u32::from_le_bytes([0, 1, 2, 3].map(|i| cpu.peek(addr + i).unwrap()))
+    }
+
+    #[test]
+    fn add_and_sub_carry_and_borrow() {
+        let cpu = halts(
+            "\tCONST 0xffffffff -> r0
+             \tCONST 2 -> r1
+             \tADD r0, r1 -> r2
+             \tADDC r1, r1 -> r3
+             \tSUB r1, r0 -> r4",
+        );
+        assert_eq!((cpu.reg(2), cpu.reg(3), cpu.reg(4)), (1, 5, 3));
+        assert!(cpu.cf());
+        assert!(!cpu.zf());
+    }
+
+    #[test]
+    fn zero_page_operands_are_words_in_data_memory() {
+        let cpu = halts(
+            "\tCONST 40 -> r0
+             \tWRITE_32 r0 -> [8]
+             \tCONST 2 -> r1
+             \tMUL zp[2], r1 -> zp[3]",
+        );
+        assert_eq!(word(&cpu, 12), 80);
+    }
+
+    #[test]
+    fn mul_overflow_div_rem_and_the_bitwise_ops() {
+        let cpu = halts(
+            "\tCONST 0x10000 -> r0
+             \tMUL r0, r0 -> r1
+             \tCONST 17 -> r2
+             \tCONST 5 -> r3
+             \tDIV r2, r3 -> r4
+             \tREM r2, r3 -> r5
+             \tXOR r2, r2 -> r6",
+        );
+        assert_eq!(
+            (cpu.reg(1), cpu.reg(4), cpu.reg(5), cpu.reg(6)),
+            (0, 3, 2, 0)
+        );
+        // the overflow was in MUL, XOR cleared CF and its zero set ZF
+        assert!(!cpu.cf());
+        assert!(cpu.zf());
+        let cpu = halts("\tCONST 0x10000 -> r0\n\tMUL r0, r0 -> r1");
+        assert!(cpu.cf() && cpu.zf());
+    }
+
+    #[test]
+    fn shifts_leave_the_last_bit_out_in_carry() {
+        let cpu = halts("\tCONST 0x80000001 -> r0\n\tSHL r0, 1 -> r1");
+        assert_eq!(cpu.reg(1), 2);
+        assert!(cpu.cf());
+        let cpu = halts("\tCONST 0x80000001 -> r0\n\tSHR r0, 2 -> r1");
+        assert_eq!(cpu.reg(1), 0x20000000);
+        assert!(!cpu.cf());
+        let cpu = halts("\tCONST 6 -> r0\n\tSHR r0, 2 -> r1");
+        assert!(cpu.cf());
+    }
+
+    #[test]
+    fn dividing_by_zero_faults() {
+        for op in ["DIV", "REM"] {
+            let (cpu, halt) = run(&format!("\tCONST 7 -> r0\n\t{} r0, r1 -> r2", op));
+            assert_eq!(halt.fault, Fault::DivideByZero, "{}", op);
+            assert_eq!(halt.ip, 0x103); // after the 3 byte CONST8
+            assert_eq!(cpu.reg(2), 0);
+        }
+    }
+
+    #[test]
+    fn memory_is_little_endian_in_every_width() {
+        let cpu = halts(
+            "\tCONST 0x200 -> r1
+             \tCONST 0x12345678 -> r0
+             \tWRITE_32 r0 -> [r1]
+             \tREAD_8 [r1] -> r2
+             \tREAD_16 [0x202] -> r3
+             \tWRITE_16 0xbeef -> [0x300]
+             \tWRITE_8 0x7f -> [r1]
+             \tREAD_32 [0x200] -> r4",
+        );
+        assert_eq!((cpu.reg(2), cpu.reg(3)), (0x78, 0x1234));
+        assert_eq!(cpu.reg(4), 0x1234567f);
+        assert_eq!(word(&cpu, 0x300), 0xbeef);
+    }
+
+    #[test]
+    fn unmapped_addresses_fault_and_read_as_zero() {
+        let (cpu, halt) = run("\tCONST 5 -> r0\n\tREAD_32 [0xffe] -> r0");
+        assert_eq!(halt.fault, Fault::MemoryOutOfRange(0x1000));
+        assert_eq!(cpu.reg(0), 0);
+        let (_, halt) = run("\tWRITE_8 1 -> [0x5000]");
+        assert_eq!(
+            halt,
+            Halt {
+                fault: Fault::MemoryOutOfRange(0x5000),
+                ip: 0x100
+            }
+        );
+    }
+
+    #[test]
+    fn writes_to_rom_fault_and_leave_it_alone() {
+        let code = Ver0::new()
+            .assemble_str(
+                "test.kasm",
+                "\t.org 0x100\n\tREAD_8 [0x2001] -> r0\n\tWRITE_8 r0 -> [0x2000]\n",
+            )
+            .unwrap();
+        let mut cpu = KMM0::new()
+            .bus(Bus::new().ram(0, 0x1000).rom(0x2000, vec![1, 2]))
+            .clock_speed_hz(0.)
+            .load_code(code);
+        let halt = cpu.run();
+        assert_eq!(
+            halt,
+            Halt {
+                fault: Fault::WriteToRom(0x2000),
+                ip: 0x107
+            }
+        );
+        assert_eq!(cpu.reg(0), 2);
+        assert_eq!(cpu.peek(0x2000), Ok(1));
+    }
+
+    #[test]
+    fn every_byte_moved_takes_a_cycle() {
+        let mut one = cpu("\t.org 0x100\n\tREAD_8 [0] -> r0\n");
+        let mut four = cpu("\t.org 0x100\n\tREAD_32 [0] -> r0\n");
+        one.step().unwrap();
+        four.step().unwrap();
+        assert_eq!(four.cycles() - one.cycles(), 3);
+    }
+
+    #[test]
+    fn pushes_and_pops_are_full_descending_from_the_top_of_ram() {
+        let cpu = halts(
+            "\tCONST 0x11223344 -> r0
+             \tPUSH_32 r0
+             \tPUSH_8 r0
+             \tPUSH_16 r0
+             \tPOP_16 r1
+             \tPOP_8 r2",
+        );
+        assert_eq!((cpu.reg(1), cpu.reg(2)), (0x3344, 0x44));
+        assert_eq!(cpu.reg(SP), 0xffc);
+        assert_eq!(word(&cpu, 0xffc), 0x11223344);
+    }
+
+    #[test]
+    fn call_and_ret_go_through_the_stack() {
+        let cpu = halts(
+            "\tCALL double
+             \tCALL double
+             \tHALT_AND_EXIT
+             double:
+             \tADD r0, r0 -> r0
+             \tINC r0
+             \tRET",
+        );
+        assert_eq!(cpu.reg(0), 3);
+        assert_eq!(cpu.reg(SP), 0x1000);
+    }
+
+    #[test]
+    fn pushing_past_the_stack_size_overflows() {
+        let mut cpu =
+            cpu("\t.org 0x100\n\tPUSH_32 r0\n\tPUSH_8 r0\n\tHALT_AND_EXIT\n").stack_size(4);
+        let halt = cpu.run();
+        assert_eq!(
+            halt,
+            Halt {
+                fault: Fault::StackOverflow,
+                ip: 0x103
+            }
+        );
+        assert_eq!(cpu.reg(SP), 0xffc);
+    }
+
+    #[test]
+    fn popping_an_empty_stack_underflows() {
+        let (cpu, halt) = run("\tPUSH_8 r0\n\tPOP_16 r1");
+        assert_eq!(
+            halt,
+            Halt {
+                fault: Fault::StackUnderflow,
+                ip: 0x103
+            }
+        );
+        assert_eq!(cpu.reg(SP), 0xfff);
+        let (_, halt) = run("\tRET");
+        assert_eq!(halt.fault, Fault::StackUnderflow);
+    }
+
+    #[test]
+    fn bad_encodings_fault_with_what_was_wrong() {
+        let cases = [
+            (".byte 0xee", Fault::InvalidInstruction(0xee)),
+            (".byte 0x04, 0xff", Fault::InvalidVariant(0x04, 0xff)),
+            (".byte 0x10, 0x01, 0x10", Fault::InvalidRegister(0x10)),
+            (
+                "CONST 0xd800 -> r0\n\tPRINT_CHAR r0",
+                Fault::InvalidChar(0xd800),
+            ),
+        ];
+        for (body, fault) in cases {
+            let (_, halt) = run(&format!("\t{}", body));
+            assert_eq!(halt.fault, fault, "{}", body);
+        }
+    }
+
+    #[test]
+    fn running_off_the_end_of_the_code_faults() {
+        let mut cpu = cpu("\t.org 0x100\n\tNOP\n");
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(
+            cpu.step(),
+            Err(Halt {
+                fault: Fault::MemoryOutOfRange(0x101),
+                ip: 0x101
+            })
+        );
+    }
+
+    #[test]
+    fn relative_jumps_and_calls_dont_wrap() {
+        // both offsets count from 0x102, the operand after the two opcode bytes
+        let (cpu, halt) = run("\tJMP_OFFSET -0x200");
+        assert_eq!(halt.fault, Fault::MemoryOutOfRange(0xffffff02));
+        assert_eq!(cpu.reg(IP), 0x104); // past the jump, which wasn't taken
+        let (cpu, halt) = run("\tCALL_OFFSET -0x200");
+        assert_eq!(halt.fault, Fault::MemoryOutOfRange(0xffffff02));
+        assert_eq!(cpu.reg(SP), 0x1000);
+    }
+
+    #[test]
+    fn execution_can_carry_on_after_a_fault() {
+        let mut cpu = cpu("\t.org 0x100\n\t.byte 0xee\n\tCONST 1 -> r0\n\tHALT_AND_EXIT\n");
+        let halt = cpu.step().unwrap_err();
+        assert_eq!(
+            halt.to_string(),
+            "fault 0x10 at 0100: invalid instruction 0xee"
+        );
+        assert_eq!(cpu.run().fault, Fault::ExplicitHalt);
+        assert_eq!(cpu.reg(0), 1);
+    }
+
+    #[test]
+    fn only_the_first_fault_of_an_instruction_counts() {
+        // DIV by zero into a bad register
+        let (_, halt) = run("\t.byte 0x04, 0x1d, 0x00, 0x01, 0x20");
+        assert_eq!(halt.fault, Fault::DivideByZero);
+    }
+
+    #[test]
+    fn an_interrupt_pushes_ip_and_flags_and_iret_restores_them() {
+        let mut cpu = cpu("\t.org 0x100
+             \tJMP main
+             \t.org 0x108
+             \tINC r1
+             \tCLR_CF
+             \tIRET
+             \t.org 0x120
+             main:
+             \tSET_CF
+             \tINT_ENABLE
+             \tNOP
+             \tHALT_AND_EXIT\n");
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        let ret = cpu.reg(IP);
+        cpu.raise_irq(1);
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg(IP), 0x10b, "in the handler, past its INC");
+        assert_eq!(cpu.reg(RP), ret);
+        assert_eq!(cpu.reg(SP), 0xff8);
+        assert_eq!(word(&cpu, 0xff8), 0b110, "CF and interrupts were on");
+        assert_eq!(word(&cpu, 0xffc), ret);
+        assert!(!cpu.int_enable);
+        assert_eq!(cpu.run().fault, Fault::ExplicitHalt);
+        assert_eq!(cpu.reg(1), 1);
+        assert!(cpu.cf() && cpu.int_enable);
+        assert_eq!(cpu.reg(SP), 0x1000);
+    }
+
+    /// Runs a program that takes IRQs 1 and 2 with `mask`, and gives r3,
+    /// the IRQs in the order they were taken a nibble each, and r6, what
+    /// was still pending at the end.
+    fn take_irqs(mask: u16) -> (u32, u32) {
+        let mut cpu = cpu(&format!(
+            "\t.org 0x100
+             \tJMP main
+             \t.org 0x108
+             \tCONST 1 -> r4
+             \tJMP record
+             \t.org 0x110
+             \tCONST 2 -> r4
+             \tJMP record
+             record:
+             \tSHL r3, 4 -> r3
+             \tOR r3, r4 -> r3
+             \tIRET
+             main:
+             \tCONST {} -> r5
+             \tINT_MASK r5
+             \tINT_ENABLE
+             \tNOP
+             \tNOP
+             \tINT_PENDING -> r6
+             \tHALT_AND_EXIT\n",
+            mask
+        ));
+        cpu.raise_irq(2);
+        cpu.raise_irq(1);
+        assert_eq!(cpu.run().fault, Fault::ExplicitHalt);
+        (cpu.reg(3), cpu.reg(6))
+    }
+
+    #[test]
+    fn lower_irqs_go_first_and_masked_ones_wait() {
+        assert_eq!(take_irqs(0xfffe), (0x12, 0));
+        assert_eq!(take_irqs(0b100), (0x2, 0b10));
+        assert_eq!(take_irqs(0), (0, 0b110));
+    }
+
+    #[test]
+    fn irqs_wait_while_interrupts_are_disabled() {
+        let mut cpu = cpu("\t.org 0x100\n\tNOP\n\tINT_PENDING -> r0\n\tHALT_AND_EXIT\n");
+        cpu.raise_irq(3);
+        assert_eq!(cpu.run().fault, Fault::ExplicitHalt);
+        assert_eq!(cpu.reg(0), 1 << 3);
+    }
+
+    #[test]
+    fn the_fault_handler_gets_the_code_and_where_it_happened() {
+        let mut cpu = cpu("\t.org 0x100
+             \tCONST handler -> r0
+             \tFAULT_HANDLER r0
+             bad:
+             \t.byte 0xee
+             \tCONST bad -> r4
+             \tHALT_AND_EXIT
+             handler:
+             \tFAULT_CODE -> r1
+             \tMOV rp -> r2
+             \tIRET\n");
+        assert_eq!(cpu.run().fault, Fault::ExplicitHalt);
+        assert_eq!(cpu.reg(1), 0x10);
+        assert_eq!(cpu.reg(2), cpu.reg(4));
+    }
+
+    #[test]
+    fn a_fault_in_the_fault_handler_stops_the_cpu() {
+        let mut cpu = cpu("\t.org 0x100
+             \tCONST handler -> r0
+             \tFAULT_HANDLER r0
+             \tDIV r0, r1 -> r0
+             handler:
+             \t.byte 0xee\n");
+        let halt = cpu.run();
+        assert_eq!(halt.fault, Fault::InvalidInstruction(0xee));
+        assert_eq!(halt.ip, cpu.reg(0));
+        assert_eq!(cpu.fault_code, 0x30);
+    }
+
+    #[test]
+    fn read_char_takes_keys_and_sets_zf_once_they_run_out() {
+        let code = Ver0::new()
+            .assemble_str(
+                "test.kasm",
+                "\t.org 0x100
+                 \tKEY_AVAILABLE -> r0
+                 \tREAD_CHAR -> r1
+                 \tREAD_CHAR -> r2
+                 \tKEY_AVAILABLE -> r3
+                 \tHALT_AND_EXIT\n",
+            )
+            .unwrap();
+        let mut cpu = KMM0::new()
+            .bus(Bus::new().ram(0, 0x1000))
+            .console(Box::new(console::Buffer::new().input(b"k")))
+            .clock_speed_hz(0.)
+            .load_code(code);
+        assert_eq!(cpu.run().fault, Fault::ExplicitHalt);
+        assert_eq!((cpu.reg(0), cpu.reg(1)), (1, b'k' as u32));
+        assert_eq!((cpu.reg(2), cpu.reg(3)), (0, 0));
+        assert!(cpu.zf());
+    }
+
+    #[test]
+    fn arriving_keys_raise_the_keyboard_irq() {
+        let input = console::Buffer::new();
+        let mut cpu = KMM0::new()
+            .bus(Bus::new().ram(0, 0x1000))
+            .console(Box::new(input.clone()))
+            .keyboard_irq(2)
+            .clock_speed_hz(0.);
+        cpu.clock();
+        assert_eq!(cpu.int_pending, 0);
+        input.clone().input(b"ab");
+        cpu.clock();
+        assert_eq!(cpu.int_pending, 1 << 2);
+        cpu.int_pending = 0;
+        cpu.clock();
+        assert_eq!(cpu.int_pending, 0, "the same keys raise it once");
+    }
+
+    #[test]
+    fn watchpoints_catch_zero_page_memory_and_stack_accesses() {
+        let mut cpu = cpu("\t.org 0x100
+             \tCONST 5 -> r0
+             \tADD r0, r1 -> zp[2]
+             \tADD r0, r1 -> zp[2]
+             \tREAD_8 [0x200] -> r1
+             \tPUSH_8 r0
+             \tHALT_AND_EXIT\n");
+        cpu.watch(Watchpoint::zero_page(2, Access::Change));
+        cpu.watch(Watchpoint::new(0x200, 1, Access::Read));
+        cpu.watch(Watchpoint::new(0xfff, 1, Access::Write));
+        assert_eq!(cpu.run().fault, Fault::ExplicitHalt);
+        let hits: Vec<String> = cpu
+            .take_watch_hits()
+            .iter()
+            .map(|h| h.to_string())
+            .collect();
+        assert_eq!(
+            hits,
+            [
+                "wrote [0x8]: 0x00 -> 0x05",
+                "read [0x200] = 0x00",
+                "wrote [0xfff] = 0x05",
+            ]
+        );
+        assert!(cpu.take_watch_hits().is_empty());
+        assert!(cpu.unwatch(&Watchpoint::new(0x200, 1, Access::Read)));
+        assert!(!cpu.unwatch(&Watchpoint::new(0x200, 1, Access::Read)));
+        assert_eq!(cpu.watchpoints().len(), 2);
     }
 }
@@ -0,0 +1,255 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::IRQS;
+
+/// Something on the bus that isn't memory, like a timer or a keyboard. It
+/// sees addresses as offsets into the range it's attached at.
+pub trait Device {
+    fn read(&mut self, offset: u32) -> u8;
+    fn write(&mut self, offset: u32, value: u8);
+    /// What `read` would give, without anything else `read` does, so
+    /// debuggers and tests can look without disturbing the device.
+    fn peek(&self, offset: u32) -> u8;
+    /// Called once every clock cycle with the number of cycles the cpu has
+    /// run. Returning true raises the device's interrupt line, if it has one.
+    fn tick(&mut self, _cycles: u64) -> bool {
+        false
+    }
+}
+
+/// A shared device, so the host can still get at it once it's on the bus.
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn read(&mut self, offset: u32) -> u8 {
+        self.borrow_mut().read(offset)
+    }
+    fn write(&mut self, offset: u32, value: u8) {
+        self.borrow_mut().write(offset, value)
+    }
+    fn peek(&self, offset: u32) -> u8 {
+        self.borrow().peek(offset)
+    }
+    fn tick(&mut self, cycles: u64) -> bool {
+        self.borrow_mut().tick(cycles)
+    }
+}
+
+enum Region {
+    Ram(Vec<u8>),
+    Rom(Vec<u8>),
+    Device(Box<dyn Device>, Option<u8>), // and the IRQ it raises
+}
+
+/// Why a bus access failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    Unmapped, // nothing is attached at the address
+    ReadOnly, // the address is ROM
+}
+
+/// The data address space, made of RAM, ROM and devices at fixed ranges.
+/// Anything in between is unmapped and faults when it's used.
+#[derive(Default)]
+pub struct Bus {
+    regions: Vec<(u32, u32, Region)>, // start, length
+}
+impl Bus {
+    pub fn new() -> Self {
+        Bus { regions: vec![] }
+    }
+    /// Adds `size` bytes of zeroed RAM at `base`.
+    pub fn ram(self, base: u32, size: u32) -> Self {
+        self.map(base, size, Region::Ram(vec![0; size as usize]))
+    }
+    /// Adds ROM holding `bytes` at `base`. Writing to it faults.
+    pub fn rom(self, base: u32, bytes: Vec<u8>) -> Self {
+        let size = bytes.len() as u32;
+        self.map(base, size, Region::Rom(bytes))
+    }
+    /// Attaches `device` over `size` bytes at `base`. Its ticks raise `irq`.
+    pub fn device(self, base: u32, size: u32, irq: Option<u8>, device: Box<dyn Device>) -> Self {
+        if let Some(n) = irq {
+            assert!((1..IRQS).contains(&n), "there's no IRQ {}", n);
+        }
+        self.map(base, size, Region::Device(device, irq))
+    }
+    fn map(mut self, base: u32, size: u32, region: Region) -> Self {
+        let end = base as u64 + size as u64;
+        for (start, len, _) in &self.regions {
+            let overlaps = (base as u64) < *start as u64 + *len as u64 && end > *start as u64;
+            assert!(
+                !overlaps,
+                "{:#x}..{:#x} overlaps something already on the bus",
+                base, end
+            );
+        }
+        self.regions.push((base, size, region));
+        self
+    }
+    /// End of the first RAM region, where the stack starts.
+    pub fn ram_top(&self) -> u32 {
+        self.regions
+            .iter()
+            .find(|(_, _, r)| matches!(r, Region::Ram(_)))
+            .map_or(0, |(start, len, _)| start + len)
+    }
+    /// Index of the region `addr` is in, and how far into it.
+    fn find(&self, addr: u32) -> Option<(usize, u32)> {
+        self.regions
+            .iter()
+            .position(|(start, len, _)| addr >= *start && addr - start < *len)
+            .map(|i| (i, addr - self.regions[i].0))
+    }
+    pub fn read(&mut self, addr: u32) -> Result<u8, BusError> {
+        let (i, ofs) = self.find(addr).ok_or(BusError::Unmapped)?;
+        match &mut self.regions[i].2 {
+            Region::Ram(m) | Region::Rom(m) => Ok(m[ofs as usize]),
+            Region::Device(d, _) => Ok(d.read(ofs)),
+        }
+    }
+    /// Reads like `read` but leaves devices as they were.
+    pub fn peek(&self, addr: u32) -> Result<u8, BusError> {
+        let (i, ofs) = self.find(addr).ok_or(BusError::Unmapped)?;
+        match &self.regions[i].2 {
+            Region::Ram(m) | Region::Rom(m) => Ok(m[ofs as usize]),
+            Region::Device(d, _) => Ok(d.peek(ofs)),
+        }
+    }
+    pub fn write(&mut self, addr: u32, value: u8) -> Result<(), BusError> {
+        let (i, ofs) = self.find(addr).ok_or(BusError::Unmapped)?;
+        match &mut self.regions[i].2 {
+            Region::Ram(m) => m[ofs as usize] = value,
+            Region::Rom(_) => return Err(BusError::ReadOnly),
+            Region::Device(d, _) => d.write(ofs, value),
+        }
+        Ok(())
+    }
+    /// Ticks every device, returning the IRQs they raised as a bit mask.
+    pub fn tick(&mut self, cycles: u64) -> u16 {
+        let mut irqs = 0;
+        for (_, _, region) in &mut self.regions {
+            if let Region::Device(d, irq) = region {
+                if d.tick(cycles) {
+                    if let Some(n) = *irq {
+                        irqs |= 1 << n;
+                    }
+                }
+            }
+        }
+        irqs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device that counts how often it's been read.
+    #[derive(Default)]
+    struct Counter {
+        reads: u8,
+    }
+    impl Device for Counter {
+        fn read(&mut self, _: u32) -> u8 {
+            self.reads += 1;
+            self.reads
+        }
+        fn write(&mut self, _: u32, _: u8) {}
+        fn peek(&self, _: u32) -> u8 {
+            self.reads
+        }
+    }
+
+    #[test]
+    fn peeking_leaves_devices_alone() {
+        let counter = Rc::new(RefCell::new(Counter::default()));
+        let mut bus = Bus::new()
+            .ram(0, 0x10)
+            .device(0x10, 1, None, Box::new(counter.clone()));
+        assert_eq!(bus.peek(0x10), Ok(0));
+        assert_eq!(bus.peek(0x10), Ok(0));
+        assert_eq!(bus.read(0x10), Ok(1));
+        assert_eq!(bus.peek(0x10), Ok(1));
+        assert_eq!(counter.borrow().reads, 1);
+    }
+
+    #[test]
+    fn peek_sees_what_read_sees() {
+        let mut bus = Bus::new().ram(0, 0x10).rom(0x10, vec![7, 8]);
+        bus.write(3, 0x42).unwrap();
+        for addr in [3, 0x10, 0x11] {
+            assert_eq!(bus.peek(addr), bus.read(addr));
+        }
+        assert_eq!(bus.peek(0x12), Err(BusError::Unmapped));
+        assert_eq!(bus.write(0x10, 0), Err(BusError::ReadOnly));
+    }
+
+    /// A device that remembers the last write, and raises its line on every
+    /// `every`th cycle.
+    struct Latch {
+        last: Option<(u32, u8)>,
+        every: u64,
+    }
+    impl Device for Latch {
+        fn read(&mut self, offset: u32) -> u8 {
+            self.peek(offset)
+        }
+        fn write(&mut self, offset: u32, value: u8) {
+            self.last = Some((offset, value));
+        }
+        fn peek(&self, offset: u32) -> u8 {
+            offset as u8
+        }
+        fn tick(&mut self, cycles: u64) -> bool {
+            cycles.is_multiple_of(self.every)
+        }
+    }
+    fn latch(every: u64) -> Rc<RefCell<Latch>> {
+        Rc::new(RefCell::new(Latch { last: None, every }))
+    }
+
+    #[test]
+    fn devices_see_offsets_into_their_range() {
+        let dev = latch(1);
+        let mut bus = Bus::new().device(0x100, 0x10, None, Box::new(dev.clone()));
+        assert_eq!(bus.read(0x10f), Ok(0xf));
+        bus.write(0x104, 9).unwrap();
+        assert_eq!(dev.borrow().last, Some((4, 9)));
+        assert_eq!(bus.read(0x110), Err(BusError::Unmapped));
+        assert_eq!(bus.write(0xff, 0), Err(BusError::Unmapped));
+    }
+
+    #[test]
+    fn ticks_raise_the_irqs_devices_are_wired_to() {
+        let mut bus = Bus::new()
+            .device(0, 1, Some(3), Box::new(latch(2)))
+            .device(1, 1, Some(5), Box::new(latch(3)))
+            .device(2, 1, None, Box::new(latch(1)));
+        assert_eq!(bus.tick(1), 0);
+        assert_eq!(bus.tick(2), 1 << 3);
+        assert_eq!(bus.tick(3), 1 << 5);
+        assert_eq!(bus.tick(6), 1 << 3 | 1 << 5);
+    }
+
+    #[test]
+    fn the_stack_goes_at_the_top_of_the_first_ram() {
+        assert_eq!(Bus::new().ram_top(), 0);
+        let bus = Bus::new()
+            .rom(0, vec![0; 4])
+            .ram(0x1000, 0x800)
+            .ram(0x2000, 0x100);
+        assert_eq!(bus.ram_top(), 0x1800);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn regions_cant_overlap() {
+        let _ = Bus::new().ram(0, 0x10).rom(0xf, vec![1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "there's no IRQ 0")]
+    fn irq_0_is_where_code_starts() {
+        let _ = Bus::new().device(0, 1, Some(0), Box::new(latch(1)));
+    }
+}
@@ -0,0 +1,252 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{Command, Stdio as Pipe};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Where the cpu's text goes and its keys come from. `PRINT_CHAR` writes to
+/// it, and it's polled for input every clock cycle,
+/// which is what `READ_CHAR` ends up reading.
+pub trait Console {
+    fn write(&mut self, text: &str);
+    /// The next byte of input if one has arrived. It mustn't wait for one.
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+/// The host's stdout, and optionally its stdin or a script of input.
+#[derive(Default)]
+pub struct Stdio {
+    script: VecDeque<u8>,
+    stdin: Option<Receiver<u8>>,
+    saved_tty: Option<String>, // `stty -g` from before raw mode
+}
+impl Stdio {
+    /// Writes to stdout and never has any input.
+    pub fn new() -> Self {
+        Stdio::default()
+    }
+    /// Writes to stdout and types `bytes` as input. They all arrive at once,
+    /// as soon as the cpu starts.
+    pub fn script(bytes: Vec<u8>) -> Self {
+        let mut stdio = Stdio::new();
+        stdio.script = bytes.into();
+        stdio
+    }
+    /// Writes to stdout and reads stdin. A terminal is put in raw mode,
+    /// without echo or line buffering, until the console is dropped; piped
+    /// input is used as it is.
+    pub fn with_input() -> Self {
+        let saved_tty = match io::stdin().is_terminal() {
+            true => raw_mode(),
+            false => None,
+        };
+        let mut stdio = Stdio::new();
+        stdio.stdin = Some(read_in_background(io::stdin()));
+        stdio.saved_tty = saved_tty;
+        stdio
+    }
+}
+impl Console for Stdio {
+    fn write(&mut self, text: &str) {
+        print!("{}", text);
+        io::stdout().flush().unwrap();
+    }
+    fn read(&mut self) -> Option<u8> {
+        match self.script.pop_front() {
+            Some(b) => Some(b),
+            None => self.stdin.as_ref()?.try_recv().ok(),
+        }
+    }
+}
+impl Drop for Stdio {
+    fn drop(&mut self) {
+        if let Some(tty) = &self.saved_tty {
+            stty(&[tty]);
+        }
+    }
+}
+
+/// Output kept in memory and input given up front, for tests. Clones share
+/// the same buffers, so keep one to look at the output after handing the
+/// other to the cpu.
+#[derive(Clone, Default)]
+pub struct Buffer {
+    output: Rc<RefCell<String>>,
+    input: Rc<RefCell<VecDeque<u8>>>,
+}
+impl Buffer {
+    pub fn new() -> Self {
+        Buffer::default()
+    }
+    /// Queues `bytes` as input. They all arrive at once, as soon as the cpu
+    /// next polls.
+    pub fn input(self, bytes: &[u8]) -> Self {
+        self.input.borrow_mut().extend(bytes);
+        self
+    }
+    /// Everything written so far.
+    pub fn output(&self) -> String {
+        self.output.borrow().clone()
+    }
+}
+impl Console for Buffer {
+    fn write(&mut self, text: &str) {
+        self.output.borrow_mut().push_str(text);
+    }
+    fn read(&mut self) -> Option<u8> {
+        self.input.borrow_mut().pop_front()
+    }
+}
+
+/// Any writer for output, like a file or a socket, and optionally any reader
+/// for input, which is read on a background thread.
+pub struct Stream<W: Write> {
+    output: W,
+    input: Option<Receiver<u8>>,
+}
+impl<W: Write> Stream<W> {
+    pub fn new(output: W) -> Self {
+        Stream {
+            output,
+            input: None,
+        }
+    }
+    pub fn input<R: Read + Send + 'static>(mut self, input: R) -> Self {
+        self.input = Some(read_in_background(input));
+        self
+    }
+}
+impl<W: Write> Console for Stream<W> {
+    fn write(&mut self, text: &str) {
+        // Like stdout, output that can't be written is lost.
+        let _ = self.output.write_all(text.as_bytes());
+        let _ = self.output.flush();
+    }
+    fn read(&mut self) -> Option<u8> {
+        self.input.as_ref()?.try_recv().ok()
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(mut input: R) -> Receiver<u8> {
+    let (send, recv) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        // Each read returns as soon as anything has arrived, so keys aren't
+        // held back waiting for the buffer to fill.
+        while let Ok(n @ 1..) = input.read(&mut buf) {
+            if buf[..n].iter().any(|b| send.send(*b).is_err()) {
+                return;
+            }
+        }
+    });
+    recv
+}
+
+/// Turns off echo and line buffering on the terminal, returning its old
+/// settings. Ctrl-C still works.
+fn raw_mode() -> Option<String> {
+    let saved = stty(&["-g"])?;
+    stty(&["-icanon", "-echo", "min", "1"])?;
+    Some(saved.trim().to_string())
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let out = Command::new("stty")
+        .args(args)
+        .stdin(Pipe::inherit())
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    String::from_utf8(out.stdout).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Ver0;
+    use crate::emulator::KMM0;
+    use std::io::Cursor;
+    use std::time::{Duration, Instant};
+
+    /// A writer whose bytes can still be looked at once the cpu owns it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(console: Box<dyn Console>, code: &str) {
+        let code = Ver0::new().assemble_str("test.kasm", code).unwrap();
+        let halt = KMM0::new()
+            .console(console)
+            .clock_speed_hz(0.)
+            .load_code(code)
+            .run();
+        assert_eq!(halt.fault, crate::emulator::Fault::ExplicitHalt);
+    }
+
+    #[test]
+    fn a_stream_gets_the_bytes_print_char_writes() {
+        let out = Shared::default();
+        run(
+            Box::new(Stream::new(out.clone())),
+            "\t.org 0x100\n\
+             \tCONST 'k' -> r0\n\
+             \tPRINT_CHAR r0\n\
+             \tPRINT_CHAR 10\n\
+             \tHALT_AND_EXIT\n",
+        );
+        assert_eq!(*out.0.borrow(), b"k\n");
+    }
+
+    #[test]
+    fn a_stream_reads_its_input_as_it_arrives() {
+        let mut stream = Stream::new(io::sink()).input(Cursor::new(b"ab".to_vec()));
+        let start = Instant::now();
+        let mut read = vec![];
+        while read.len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            match stream.read() {
+                Some(b) => read.push(b),
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        assert_eq!(read, b"ab");
+        assert_eq!(stream.read(), None);
+    }
+
+    #[test]
+    fn a_stream_without_input_never_has_any() {
+        assert_eq!(Stream::new(io::sink()).read(), None);
+    }
+
+    #[test]
+    fn buffer_clones_share_output_and_input() {
+        let buffer = Buffer::new().input(b"xy");
+        let mut other = buffer.clone();
+        other.write("out");
+        assert_eq!(buffer.output(), "out");
+        assert_eq!(other.read(), Some(b'x'));
+        assert_eq!(buffer.clone().read(), Some(b'y'));
+        assert_eq!(other.read(), None);
+    }
+
+    #[test]
+    fn a_script_is_read_a_byte_at_a_time() {
+        let mut stdio = Stdio::script(b"hi".to_vec());
+        assert_eq!(stdio.read(), Some(b'h'));
+        assert_eq!(stdio.read(), Some(b'i'));
+        assert_eq!(stdio.read(), None);
+    }
+}
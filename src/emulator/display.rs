@@ -0,0 +1,275 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use super::Device;
+
+pub const COLS: u32 = 80;
+pub const ROWS: u32 = 25;
+
+// Offsets on the bus. The text cells come first, two bytes each: the
+// character, then the attribute with the foreground colour in the low nibble
+// and the background in the high one.
+const TEXT: u32 = 0x0000;
+const MODE: u32 = 0x0fa0; // 0 shows the text, 1 the pixels
+const PIXELS: u32 = 0x1000; // one colour per byte, row by row
+
+/// The 16 colours, in the order of the ANSI ones: black, red, green, yellow,
+/// blue, magenta, cyan, white, then the bright versions.
+const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0xaa, 0x00, 0x00],
+    [0x00, 0xaa, 0x00],
+    [0xaa, 0x55, 0x00],
+    [0x00, 0x00, 0xaa],
+    [0xaa, 0x00, 0xaa],
+    [0x00, 0xaa, 0xaa],
+    [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55],
+    [0xff, 0x55, 0x55],
+    [0x55, 0xff, 0x55],
+    [0xff, 0xff, 0x55],
+    [0x55, 0x55, 0xff],
+    [0xff, 0x55, 0xff],
+    [0x55, 0xff, 0xff],
+    [0xff, 0xff, 0xff],
+];
+
+const FRAME: Duration = Duration::from_millis(33);
+
+/// A memory mapped screen: 80x25 character cells with colours, and
+/// optionally a pixel framebuffer. It can draw itself to the terminal with
+/// ANSI escapes as the program runs, or be captured as text or a PPM image.
+pub struct Display {
+    text: Vec<u8>,
+    mode: u8,
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+    live: bool,
+    dirty: bool,
+    last_frame: Option<Instant>,
+}
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
+    }
+}
+impl Display {
+    /// A text screen, every cell a space in white on black.
+    pub fn new() -> Self {
+        Display {
+            text: [b' ', 0x07].repeat((COLS * ROWS) as usize),
+            mode: 0,
+            pixels: vec![],
+            width: 0,
+            height: 0,
+            live: false,
+            dirty: true,
+            last_frame: None,
+        }
+    }
+    /// Adds a `width` by `height` pixel framebuffer at offset 0x1000.
+    pub fn pixels(mut self, width: u32, height: u32) -> Self {
+        self.pixels = vec![0; (width * height) as usize];
+        self.width = width;
+        self.height = height;
+        self
+    }
+    /// Draws to the terminal while the program runs, at most 30 times a
+    /// second.
+    pub fn live(mut self) -> Self {
+        self.live = true;
+        self
+    }
+    /// Bytes the display takes up on the bus.
+    pub fn size(&self) -> u32 {
+        match self.pixels.len() {
+            0 => MODE + 1,
+            n => PIXELS + n as u32,
+        }
+    }
+    fn showing_pixels(&self) -> bool {
+        self.mode == 1 && !self.pixels.is_empty()
+    }
+    /// The characters on screen, one line per row, without trailing spaces.
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        for row in self.text.chunks(2 * COLS as usize) {
+            let line: String = row.chunks(2).map(|cell| printable(cell[0])).collect();
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+    /// The pixel framebuffer as a binary PPM image, if there is one.
+    pub fn ppm(&self) -> Option<Vec<u8>> {
+        if self.pixels.is_empty() {
+            return None;
+        }
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        for p in &self.pixels {
+            out.extend_from_slice(&PALETTE[(p & 0xf) as usize]);
+        }
+        Some(out)
+    }
+    /// The frame as ANSI escapes, starting from the top left of the terminal.
+    /// Pixels are drawn two to a character with half blocks.
+    pub fn ansi(&self) -> String {
+        let mut out = String::from("\x1b[H");
+        if self.showing_pixels() {
+            let w = self.width as usize;
+            for y in (0..self.height as usize).step_by(2) {
+                for x in 0..w {
+                    let top = self.pixels[y * w + x] & 0xf;
+                    let bottom = match self.pixels.get((y + 1) * w + x) {
+                        Some(p) => p & 0xf,
+                        None => 0,
+                    };
+                    let _ = write!(out, "\x1b[{};{}m\u{2580}", fg(top), fg(bottom) + 10);
+                }
+                out.push_str("\x1b[0m\r\n");
+            }
+        } else {
+            for row in self.text.chunks(2 * COLS as usize) {
+                let mut attr = None;
+                for cell in row.chunks(2) {
+                    if attr != Some(cell[1]) {
+                        let (f, b) = (cell[1] & 0xf, cell[1] >> 4);
+                        let _ = write!(out, "\x1b[{};{}m", fg(f), fg(b) + 10);
+                        attr = Some(cell[1]);
+                    }
+                    out.push(printable(cell[0]));
+                }
+                out.push_str("\x1b[0m\r\n");
+            }
+        }
+        out
+    }
+    /// Draws the frame to the terminal if it changed since the last one.
+    pub fn render(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        if self.last_frame.is_none() {
+            print!("\x1b[2J");
+        }
+        print!("{}", self.ansi());
+        io::stdout().flush().unwrap();
+        self.dirty = false;
+        self.last_frame = Some(Instant::now());
+    }
+}
+
+/// ANSI foreground colour code for palette entry `n`. The background code is
+/// 10 more.
+fn fg(n: u8) -> u8 {
+    match n {
+        0..=7 => 30 + n,
+        _ => 90 + n - 8,
+    }
+}
+
+fn printable(c: u8) -> char {
+    match c {
+        0x20..=0x7e => c as char,
+        _ => ' ',
+    }
+}
+
+impl Device for Display {
+    fn read(&mut self, offset: u32) -> u8 {
+        self.peek(offset)
+    }
+    fn peek(&self, offset: u32) -> u8 {
+        match offset {
+            MODE => self.mode,
+            _ if offset >= PIXELS => self.pixels[(offset - PIXELS) as usize],
+            _ => self
+                .text
+                .get((offset - TEXT) as usize)
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+    fn write(&mut self, offset: u32, value: u8) {
+        match offset {
+            MODE => self.mode = value,
+            _ if offset >= PIXELS => self.pixels[(offset - PIXELS) as usize] = value,
+            _ => match self.text.get_mut((offset - TEXT) as usize) {
+                Some(cell) => *cell = value,
+                None => return,
+            },
+        }
+        self.dirty = true;
+    }
+    fn tick(&mut self, cycles: u64) -> bool {
+        // Looking at the clock every cycle would slow the cpu down a lot.
+        if self.live && self.dirty && cycles.is_multiple_of(1024) {
+            match self.last_frame {
+                Some(t) if t.elapsed() < FRAME => {}
+                _ => self.render(),
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_show_in_the_text_without_trailing_spaces() {
+        let mut d = Display::new();
+        d.write(TEXT, b'H');
+        d.write(TEXT + 2, b'i');
+        d.write(TEXT + 2 * (COLS + 3), b'x');
+        d.write(TEXT + 2 * (COLS + 3) + 1, 0x1e);
+        d.write(TEXT + 2 * (COLS * ROWS - 1), 0x01);
+        let text = d.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), ROWS as usize);
+        assert_eq!(lines[0], "Hi");
+        assert_eq!(lines[1], "   x");
+        assert!(lines[2..].iter().all(|l| l.is_empty()));
+        assert_eq!(d.peek(TEXT + 2 * (COLS + 3) + 1), 0x1e);
+    }
+
+    #[test]
+    fn pixels_map_through_the_palette_into_the_ppm() {
+        assert_eq!(Display::new().ppm(), None);
+        let mut d = Display::new().pixels(2, 2);
+        assert_eq!(d.size(), PIXELS + 4);
+        d.write(PIXELS, 1);
+        d.write(PIXELS + 1, 0x2f); // only the low nibble picks the colour
+        d.write(PIXELS + 3, 12);
+        let mut want = b"P6\n2 2\n255\n".to_vec();
+        want.extend_from_slice(&[0xaa, 0, 0, 0xff, 0xff, 0xff, 0, 0, 0, 0x55, 0x55, 0xff]);
+        assert_eq!(d.ppm().unwrap(), want);
+        assert_eq!(d.peek(PIXELS + 1), 0x2f);
+    }
+
+    #[test]
+    fn mode_picks_what_is_drawn() {
+        let mut d = Display::new().pixels(1, 1);
+        d.write(TEXT, b'A');
+        assert!(d.ansi().contains('A'));
+        d.write(MODE, 1);
+        assert_eq!(d.peek(MODE), 1);
+        assert!(!d.ansi().contains('A'));
+        assert!(d.ansi().contains('\u{2580}'));
+    }
+
+    #[test]
+    fn the_gap_before_the_pixels_reads_zero_and_ticks_never_interrupt() {
+        let mut d = Display::new().pixels(1, 1);
+        d.write(MODE + 1, b'z');
+        assert_eq!(d.peek(MODE + 1), 0);
+        assert!(!d.text().contains('z'));
+        // Not live, so ticking doesn't draw, and the display has no IRQ.
+        assert!(!d.tick(0));
+        assert!(!d.tick(1024));
+        assert!(d.dirty);
+    }
+}
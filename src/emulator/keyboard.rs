@@ -0,0 +1,64 @@
+use std::collections::VecDeque;
+
+use super::Console;
+
+/// Keys waiting for `READ_CHAR` and `KEY_AVAILABLE`. Where they come from is
+/// up to the console; the cpu polls it into the keyboard every clock cycle.
+#[derive(Default)]
+pub struct Keyboard {
+    buffer: VecDeque<u8>, // typed, waiting for READ_CHAR
+}
+impl Keyboard {
+    pub fn new() -> Self {
+        Keyboard::default()
+    }
+    /// Moves the keys that arrived at `console` since the last poll into the
+    /// buffer. Returns true if there were any, which is when the keyboard
+    /// raises its IRQ, so keys that arrive together raise it once.
+    pub fn poll(&mut self, console: &mut dyn Console) -> bool {
+        let before = self.buffer.len();
+        while let Some(key) = console.read() {
+            self.buffer.push_back(key);
+        }
+        self.buffer.len() != before
+    }
+    /// Number of keys waiting to be read.
+    pub fn available(&self) -> usize {
+        self.buffer.len()
+    }
+    /// Takes the oldest key, if there is one.
+    pub fn read(&mut self) -> Option<u8> {
+        self.buffer.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::console::Buffer;
+
+    #[test]
+    fn keys_are_read_oldest_first() {
+        let mut keys = Keyboard::new();
+        assert!(keys.poll(&mut Buffer::new().input(b"ab")));
+        assert!(keys.poll(&mut Buffer::new().input(b"c")));
+        assert_eq!(keys.available(), 3);
+        assert_eq!(keys.read(), Some(b'a'));
+        assert_eq!(keys.read(), Some(b'b'));
+        assert_eq!(keys.read(), Some(b'c'));
+        assert_eq!(keys.read(), None);
+        assert_eq!(keys.available(), 0);
+    }
+
+    #[test]
+    fn a_poll_only_raises_the_irq_when_keys_arrive() {
+        let mut keys = Keyboard::new();
+        let mut console = Buffer::new().input(b"xyz");
+        assert!(keys.poll(&mut console));
+        assert!(!keys.poll(&mut console));
+        // keys already waiting don't raise it again
+        keys.read();
+        assert!(!keys.poll(&mut console));
+        assert_eq!(keys.available(), 2);
+    }
+}
@@ -0,0 +1,165 @@
+use super::Device;
+
+/// Size of the timer's register block on the bus.
+pub const TIMER_SIZE: u32 = 0x10;
+
+// Register offsets. Every register is little endian and can be read and
+// written a byte at a time.
+const RELOAD: u32 = 0x0; // u32, what COUNT starts from
+const COUNT: u32 = 0x4; // u32, cycles left until it fires
+const CONTROL: u32 = 0x8; // u8, see below
+const UPTIME: u32 = 0xc; // u32, cycles since reset, read only
+
+// CONTROL bits.
+const TIMER_ENABLE: u8 = 1 << 0;
+const TIMER_PERIODIC: u8 = 1 << 1;
+const TIMER_FIRED: u8 = 1 << 2; // set when it fires, writing a 1 clears it
+
+/// A countdown timer driven by the cpu clock. Once enabled COUNT goes down by
+/// one every cycle, and when it reaches zero the timer raises its IRQ. A
+/// periodic timer then starts again from RELOAD; a one-shot one turns itself
+/// off.
+#[derive(Default)]
+pub struct Timer {
+    reload: u32,
+    count: u32,
+    control: u8,
+    uptime: u64, // the cpu's cycle count as of the last tick
+}
+impl Timer {
+    pub fn new() -> Self {
+        Timer::default()
+    }
+}
+
+fn byte_of(v: u32, n: u32) -> u8 {
+    (v >> (8 * n)) as u8
+}
+
+fn set_byte(v: &mut u32, n: u32, b: u8) {
+    *v = (*v & !(0xff << (8 * n))) | (b as u32) << (8 * n);
+}
+
+impl Device for Timer {
+    fn read(&mut self, offset: u32) -> u8 {
+        self.peek(offset)
+    }
+    fn peek(&self, offset: u32) -> u8 {
+        match offset {
+            RELOAD..=0x3 => byte_of(self.reload, offset - RELOAD),
+            COUNT..=0x7 => byte_of(self.count, offset - COUNT),
+            CONTROL => self.control,
+            UPTIME..=0xf => byte_of(self.uptime as u32, offset - UPTIME),
+            _ => 0,
+        }
+    }
+    fn write(&mut self, offset: u32, value: u8) {
+        match offset {
+            RELOAD..=0x3 => set_byte(&mut self.reload, offset - RELOAD, value),
+            COUNT..=0x7 => set_byte(&mut self.count, offset - COUNT, value),
+            CONTROL => {
+                // Turning it on starts a fresh countdown from RELOAD.
+                if value & TIMER_ENABLE != 0 && self.control & TIMER_ENABLE == 0 {
+                    self.count = self.reload;
+                }
+                let fired = self.control & TIMER_FIRED & !value;
+                self.control = value & (TIMER_ENABLE | TIMER_PERIODIC) | fired;
+            }
+            _ => {}
+        }
+    }
+    fn tick(&mut self, cycles: u64) -> bool {
+        self.uptime = cycles;
+        if self.control & TIMER_ENABLE == 0 || self.count == 0 {
+            return false;
+        }
+        self.count -= 1;
+        if self.count != 0 {
+            return false;
+        }
+        self.control |= TIMER_FIRED;
+        if self.control & TIMER_PERIODIC != 0 {
+            self.count = self.reload;
+        } else {
+            self.control &= !TIMER_ENABLE;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write32(t: &mut Timer, offset: u32, v: u32) {
+        for (i, b) in v.to_le_bytes().into_iter().enumerate() {
+            t.write(offset + i as u32, b);
+        }
+    }
+
+    fn read32(t: &Timer, offset: u32) -> u32 {
+        u32::from_le_bytes([0, 1, 2, 3].map(|i| t.peek(offset + i)))
+    }
+
+    /// The cycles in `1..=n` the timer fired on.
+    fn fires(t: &mut Timer, n: u64) -> Vec<u64> {
+        (1..=n).filter(|c| t.tick(*c)).collect()
+    }
+
+    #[test]
+    fn a_periodic_timer_reloads_every_time_it_fires() {
+        let mut t = Timer::new();
+        write32(&mut t, RELOAD, 3);
+        t.write(CONTROL, TIMER_ENABLE | TIMER_PERIODIC);
+        assert_eq!(read32(&t, COUNT), 3);
+        assert_eq!(fires(&mut t, 10), [3, 6, 9]);
+        assert_eq!(read32(&t, COUNT), 2);
+        assert_eq!(t.peek(CONTROL), TIMER_ENABLE | TIMER_PERIODIC | TIMER_FIRED);
+    }
+
+    #[test]
+    fn a_one_shot_timer_turns_itself_off() {
+        let mut t = Timer::new();
+        write32(&mut t, RELOAD, 2);
+        t.write(CONTROL, TIMER_ENABLE);
+        assert_eq!(fires(&mut t, 10), [2]);
+        assert_eq!(t.peek(CONTROL), TIMER_FIRED);
+        // turning it back on starts from RELOAD again
+        t.write(CONTROL, TIMER_ENABLE);
+        assert_eq!(fires(&mut t, 3), [2]);
+    }
+
+    #[test]
+    fn writing_a_one_clears_fired() {
+        let mut t = Timer::new();
+        write32(&mut t, RELOAD, 1);
+        t.write(CONTROL, TIMER_ENABLE | TIMER_PERIODIC);
+        assert!(t.tick(1));
+        t.write(CONTROL, TIMER_ENABLE | TIMER_PERIODIC);
+        assert_ne!(t.peek(CONTROL) & TIMER_FIRED, 0);
+        t.write(CONTROL, TIMER_ENABLE | TIMER_PERIODIC | TIMER_FIRED);
+        assert_eq!(t.peek(CONTROL), TIMER_ENABLE | TIMER_PERIODIC);
+    }
+
+    #[test]
+    fn count_can_be_set_and_a_stopped_timer_doesnt_count() {
+        let mut t = Timer::new();
+        write32(&mut t, RELOAD, 100);
+        t.write(CONTROL, TIMER_ENABLE | TIMER_PERIODIC);
+        write32(&mut t, COUNT, 2);
+        assert_eq!(fires(&mut t, 2), [2]);
+        assert_eq!(read32(&t, COUNT), 100);
+        t.write(CONTROL, 0);
+        assert_eq!(fires(&mut t, 200), Vec::<u64>::new());
+        assert_eq!(read32(&t, COUNT), 100);
+    }
+
+    #[test]
+    fn uptime_is_the_cycle_count_and_read_only() {
+        let mut t = Timer::new();
+        t.tick(0x12345678);
+        write32(&mut t, UPTIME, 0);
+        assert_eq!(read32(&t, UPTIME), 0x12345678);
+        assert_eq!(t.peek(TIMER_SIZE), 0);
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+
+/// What a watchpoint looks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Change, // a write of a value that's different from the one there
+}
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Change => "change",
+        })
+    }
+}
+
+/// A range of data memory to keep an eye on. The cpu notes every access to it
+/// that the watchpoint is for, whether it comes from a zero page instruction,
+/// a memory instruction or the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u32,
+    pub len: u32,
+    pub access: Access,
+}
+impl Watchpoint {
+    pub fn new(addr: u32, len: u32, access: Access) -> Self {
+        Watchpoint {
+            addr,
+            len: len.max(1),
+            access,
+        }
+    }
+    /// Zero page slot `n`, all 4 bytes of it.
+    pub fn zero_page(n: u8, access: Access) -> Self {
+        Watchpoint::new(n as u32 * 4, 4, access)
+    }
+    pub fn covers(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.addr) < self.len
+    }
+}
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.access)?;
+        if self.addr < 0x400 && self.addr.is_multiple_of(4) && self.len == 4 {
+            write!(f, "zp[{}] ", self.addr / 4)?;
+        }
+        match self.len {
+            1 => write!(f, "[{:#x}]", self.addr),
+            n => write!(f, "[{:#x}..{:#x}]", self.addr, self.addr.wrapping_add(n)),
+        }
+    }
+}
+
+/// One access a watchpoint caught, a byte at a time. `old` is only known for
+/// `Change`; for the others it's the same as `new`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watch: Watchpoint,
+    pub addr: u32,
+    pub old: u8,
+    pub new: u8,
+}
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.watch.access {
+            Access::Read => write!(f, "read [{:#x}] = {:#04x}", self.addr, self.new),
+            Access::Write => write!(f, "wrote [{:#x}] = {:#04x}", self.addr, self.new),
+            Access::Change => write!(
+                f,
+                "wrote [{:#x}]: {:#04x} -> {:#04x}",
+                self.addr, self.old, self.new
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_watchpoint_covers_its_range_even_at_the_top_of_memory() {
+        let w = Watchpoint::new(0x200, 4, Access::Write);
+        assert!(!w.covers(0x1ff) && w.covers(0x200) && w.covers(0x203) && !w.covers(0x204));
+        let top = Watchpoint::new(0xfffffffe, 2, Access::Read);
+        assert!(top.covers(0xffffffff) && !top.covers(0));
+        assert_eq!(Watchpoint::new(0x10, 0, Access::Read).len, 1);
+        assert_eq!(
+            Watchpoint::zero_page(3, Access::Change),
+            Watchpoint::new(12, 4, Access::Change)
+        );
+    }
+
+    #[test]
+    fn watchpoints_and_hits_print_what_they_are() {
+        assert_eq!(
+            Watchpoint::zero_page(2, Access::Change).to_string(),
+            "change zp[2] [0x8..0xc]"
+        );
+        assert_eq!(
+            Watchpoint::new(0x400, 4, Access::Write).to_string(),
+            "write [0x400..0x404]"
+        );
+        assert_eq!(
+            Watchpoint::new(0x200, 1, Access::Read).to_string(),
+            "read [0x200]"
+        );
+        let hit = |access, old| WatchHit {
+            watch: Watchpoint::new(0x200, 1, access),
+            addr: 0x200,
+            old,
+            new: 7,
+        };
+        assert_eq!(hit(Access::Read, 7).to_string(), "read [0x200] = 0x07");
+        assert_eq!(hit(Access::Write, 7).to_string(), "wrote [0x200] = 0x07");
+        assert_eq!(
+            hit(Access::Change, 1).to_string(),
+            "wrote [0x200]: 0x01 -> 0x07"
+        );
+    }
+}
@@ -0,0 +1,517 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::assembler::{self, Lexeme, Ver0};
+use crate::emulator::{console::Buffer, Bus, Display, Fault, Timer, KMM0, TIMER_SIZE};
+
+/// How many cycles a program gets to halt in if it doesn't say.
+pub const DEFAULT_CYCLES: u64 = 1_000_000;
+
+// Where programs find the timer, the display and a `--rom` file, and the
+// keyboard's IRQ.
+pub const TIMER_BASE: u32 = 0x10000;
+pub const TIMER_IRQ: u8 = 1;
+pub const DISPLAY_BASE: u32 = 0x20000;
+pub const ROM_BASE: u32 = 0x30000;
+pub const KEYBOARD_IRQ: u8 = 2;
+
+/// The data bus `-r` and `test` run programs with: 64 KiB of RAM, the timer
+/// and `display`.
+pub fn machine_bus(display: &Rc<RefCell<Display>>) -> Bus {
+    Bus::new()
+        .ram(0, 0x10000)
+        .device(
+            TIMER_BASE,
+            TIMER_SIZE,
+            Some(TIMER_IRQ),
+            Box::new(Timer::new()),
+        )
+        .device(
+            DISPLAY_BASE,
+            display.borrow().size(),
+            None,
+            Box::new(display.clone()),
+        )
+}
+
+/// One thing a finished program has to have done.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    Output(Vec<u8>),    // everything printed, all `output` lines together
+    Reg(u8, u32),       // register
+    Zf(bool),           // zero flag
+    Cf(bool),           // carry flag
+    Mem(u32, Vec<u8>),  // data memory from the address on
+    Code(u32, Vec<u8>), // assembled bytes from the address on
+    Fault(u8),          // code of the fault that stopped it, 0x01 unless given
+}
+
+/// What a program should do, and what it's run with. Programs give these in
+/// comment lines like `; expect: r0 == 42`, `; input: "keys"`,
+/// `; cycles: 5000` and `; stack: 0x100`, and sidecar files in lines without
+/// the `;`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectations {
+    pub checks: Vec<(usize, Check)>, // line each came from
+    pub input: Vec<u8>,
+    pub cycles: u64,
+    pub stack: Option<u32>, // bytes the stack can grow to, all of RAM if not given
+}
+impl Default for Expectations {
+    fn default() -> Self {
+        Expectations {
+            checks: vec![],
+            input: vec![],
+            cycles: DEFAULT_CYCLES,
+            stack: None,
+        }
+    }
+}
+impl Expectations {
+    /// Reads expectations from the comment lines of a program's source.
+    pub fn from_source(source: &str) -> Result<Self, String> {
+        let mut exp = Expectations::default();
+        for (i, line) in source.lines().enumerate() {
+            if let Some(comment) = line.trim_start().strip_prefix(';') {
+                exp.directive(i + 1, comment.trim())?;
+            }
+        }
+        Ok(exp)
+    }
+    /// Adds the expectations of a sidecar file, where every line that isn't
+    /// blank or a comment is one.
+    pub fn sidecar(&mut self, text: &str) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            if !self.directive(i + 1, line)? {
+                return Err(format!(
+                    "line {}: expected `expect:`, `input:`, `cycles:` or `stack:`",
+                    i + 1
+                ));
+            }
+        }
+        Ok(())
+    }
+    /// Reads `expect: ...`, `input: ...`, `cycles: ...` or `stack: ...`.
+    /// Returns false if `text` isn't any of them, which in a program is just a
+    /// comment.
+    fn directive(&mut self, line: usize, text: &str) -> Result<bool, String> {
+        let (name, rest) = match text.split_once(':') {
+            Some((name, rest)) => (name.trim(), rest),
+            None => return Ok(false),
+        };
+        if !matches!(name, "expect" | "input" | "cycles" | "stack") {
+            return Ok(false);
+        }
+        let bad = |what: &str| format!("line {}: {} in `{}`", line, what, text);
+        let toks = assembler::tokens(rest).ok_or_else(|| bad("invalid syntax"))?;
+        match (name, toks.as_slice()) {
+            ("input", [Lexeme::Str(s)]) => self.input.extend(s),
+            ("cycles", [Lexeme::Int(n)]) => self.cycles = *n,
+            ("stack", [Lexeme::Int(n)]) => match u32::try_from(*n) {
+                Ok(n) => self.stack = Some(n),
+                Err(_) => return Err(bad("a stack bigger than the address space")),
+            },
+            ("expect", _) => {
+                let check = parse_check(&toks).ok_or_else(|| bad("unknown expectation"))?;
+                self.checks.push((line, check));
+            }
+            _ => return Err(bad("expected a string or a number")),
+        }
+        Ok(true)
+    }
+}
+
+/// `output == "text"`, `r0 == 5`, `zf == 1`, `fault == 0x30`,
+/// `[addr] == 1, 2` or `code[addr] == 1, 2`.
+fn parse_check(toks: &[Lexeme]) -> Option<Check> {
+    use Lexeme::*;
+    let (lhs, rhs) = match toks.iter().position(|t| *t == EqEq) {
+        Some(i) => (&toks[..i], &toks[i + 1..]),
+        None => return None,
+    };
+    Some(match lhs {
+        [Ident(o)] if o == "output" => match rhs {
+            [Str(s)] => Check::Output(s.clone()),
+            _ => return None,
+        },
+        [Ident(f)] if f == "zf" => Check::Zf(number(rhs)? != 0),
+        [Ident(f)] if f == "cf" => Check::Cf(number(rhs)? != 0),
+        [Ident(f)] if f == "fault" => Check::Fault(u8::try_from(number(rhs)?).ok()?),
+        [Ident(r)] => Check::Reg(assembler::register(r)?, sized(number(rhs)?, 32)?),
+        [LBracket, Int(a), RBracket] => Check::Mem(u32::try_from(*a).ok()?, bytes(rhs)?),
+        [Ident(c), LBracket, Int(a), RBracket] if c == "code" => {
+            Check::Code(u32::try_from(*a).ok()?, bytes(rhs)?)
+        }
+        _ => return None,
+    })
+}
+
+/// A number, optionally negative.
+fn number(toks: &[Lexeme]) -> Option<i64> {
+    match toks {
+        [Lexeme::Int(n)] => Some(*n as i64),
+        [Lexeme::Minus, Lexeme::Int(n)] => Some(-(*n as i64)),
+        _ => None,
+    }
+}
+
+/// `n` as a `bits` wide value, with negative numbers in two's complement.
+/// `None` if it doesn't fit.
+fn sized(n: i64, bits: u32) -> Option<u32> {
+    if n >= 1 << bits || n < -(1 << (bits - 1)) {
+        return None;
+    }
+    Some((n as u64 & ((1 << bits) - 1)) as u32)
+}
+
+/// A string, or bytes separated by commas.
+fn bytes(toks: &[Lexeme]) -> Option<Vec<u8>> {
+    if let [Lexeme::Str(s)] = toks {
+        return Some(s.clone());
+    }
+    let mut out = vec![];
+    for part in toks.split(|t| *t == Lexeme::Comma) {
+        out.push(sized(number(part)?, 8)? as u8);
+    }
+    Some(out)
+}
+
+/// How one program did. It passed if there are no failures.
+pub struct Outcome {
+    pub path: PathBuf,
+    pub failures: Vec<String>,
+}
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.passed() { "ok  " } else { "FAIL" };
+        write!(f, "{} {}", status, self.path.display())?;
+        for failure in &self.failures {
+            write!(f, "\n     {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+/// Assembles and runs the program at `path`, and checks it against its
+/// expectations.
+pub fn run_file(ver0: &Ver0, path: &Path) -> Outcome {
+    let failures = match check_file(ver0, path) {
+        Ok(failures) => failures,
+        Err(e) => vec![e],
+    };
+    Outcome {
+        path: path.to_path_buf(),
+        failures,
+    }
+}
+
+/// Runs every `.kasm` file in `dir`, in name order.
+pub fn run_dir(ver0: &Ver0, dir: &Path) -> std::io::Result<Vec<Outcome>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "kasm") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths.iter().map(|p| run_file(ver0, p)).collect())
+}
+
+fn check_file(ver0: &Ver0, path: &Path) -> Result<Vec<String>, String> {
+    let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut exp = Expectations::from_source(&source)?;
+    if let Ok(text) = fs::read_to_string(path.with_extension("expect")) {
+        exp.sidecar(&text)
+            .map_err(|e| format!("{}: {}", path.with_extension("expect").display(), e))?;
+    }
+    let golden = fs::read(path.with_extension("out")).ok();
+    let screen = fs::read_to_string(path.with_extension("screen")).ok();
+    let code = ver0
+        .assemble_str(&path.to_string_lossy(), &source)
+        .map_err(|e| format!("doesn't assemble:\n{}", e))?;
+    Ok(run(code, &exp, golden, screen))
+}
+
+/// Runs `code` and lists the ways it didn't do what `exp` and `golden`, the
+/// whole expected output if there is one, say it should have. `screen` is
+/// the expected text on the display, where blank rows at the end don't count.
+pub fn run(
+    code: Vec<u8>,
+    exp: &Expectations,
+    golden: Option<Vec<u8>>,
+    screen: Option<String>,
+) -> Vec<String> {
+    let console = Buffer::new().input(&exp.input);
+    let display = Rc::new(RefCell::new(Display::new().pixels(160, 100)));
+    let mut cpu = KMM0::new()
+        .bus(machine_bus(&display))
+        .console(Box::new(console.clone()))
+        .keyboard_irq(KEYBOARD_IRQ)
+        .clock_speed_hz(0.)
+        .load_code(code);
+    if let Some(size) = exp.stack {
+        cpu = cpu.stack_size(size);
+    }
+    let halt = loop {
+        if cpu.cycles() >= exp.cycles {
+            break None;
+        }
+        if let Err(halt) = cpu.step() {
+            break Some(halt);
+        }
+    };
+
+    let mut failures = vec![];
+    let mut expected_fault = Fault::ExplicitHalt.code();
+    let mut output: Option<Vec<u8>> = golden;
+    for (line, check) in &exp.checks {
+        let mut fail = |what: String| failures.push(format!("line {}: {}", line, what));
+        match check {
+            Check::Output(s) => output.get_or_insert_with(Vec::new).extend(s),
+            Check::Fault(code) => expected_fault = *code,
+            Check::Reg(r, v) if cpu.reg(*r as usize) != *v => fail(format!(
+                "{} is {:#x}, expected {:#x}",
+                crate::isa::reg(*r),
+                cpu.reg(*r as usize),
+                v
+            )),
+            Check::Zf(z) if cpu.zf() != *z => {
+                fail(format!("zf is {}, expected {}", cpu.zf() as u8, *z as u8))
+            }
+            Check::Cf(c) if cpu.cf() != *c => {
+                fail(format!("cf is {}, expected {}", cpu.cf() as u8, *c as u8))
+            }
+            Check::Mem(addr, want) => {
+                let got: Vec<_> = (0..want.len() as u32)
+                    .map(|i| cpu.peek(addr + i).ok())
+                    .collect();
+                if got.iter().zip(want).any(|(g, w)| *g != Some(*w)) {
+                    fail(format!(
+                        "[{:#x}] is {}, expected {:02x?}",
+                        addr,
+                        show_bytes(&got),
+                        want
+                    ));
+                }
+            }
+            Check::Code(addr, want) => {
+                let got: Vec<_> = (0..want.len() as u32).map(|i| cpu.code(addr + i)).collect();
+                if got.iter().zip(want).any(|(g, w)| *g != Some(*w)) {
+                    fail(format!(
+                        "code[{:#x}] is {}, expected {:02x?}",
+                        addr,
+                        show_bytes(&got),
+                        want
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    match halt {
+        None => failures.push(format!("still running after {} cycles", exp.cycles)),
+        Some(halt) if halt.fault.code() != expected_fault => failures.push(format!(
+            "stopped with {}, expected fault {:#04x}",
+            halt, expected_fault
+        )),
+        Some(_) => {}
+    }
+    if let Some(want) = output {
+        let got = console.output();
+        if got.as_bytes() != want {
+            failures.push(format!(
+                "printed {:?}, expected {:?}",
+                got,
+                String::from_utf8_lossy(&want)
+            ));
+        }
+    }
+    if let Some(want) = screen {
+        let got = display.borrow().text();
+        if got.trim_end_matches('\n') != want.trim_end_matches('\n') {
+            failures.push(format!(
+                "the screen shows {:?}, expected {:?}",
+                got.trim_end_matches('\n'),
+                want.trim_end_matches('\n')
+            ));
+        }
+    }
+    failures
+}
+
+fn show_bytes(bytes: &[Option<u8>]) -> String {
+    let shown: Vec<String> = bytes
+        .iter()
+        .map(|b| match b {
+            Some(b) => format!("{:02x}", b),
+            None => "unmapped".to_string(),
+        })
+        .collect();
+    format!("[{}]", shown.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checks(source: &str) -> Vec<Check> {
+        let exp = Expectations::from_source(source).unwrap();
+        exp.checks.into_iter().map(|(_, c)| c).collect()
+    }
+    fn error(source: &str) -> String {
+        match Expectations::from_source(source) {
+            Ok(exp) => panic!("`{}` gave {:?}", source, exp),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn every_kind_of_check_parses() {
+        let source = "\
+            ; expect: output == \"hi\"\n\
+            ; expect: r3 == 0x2a\n\
+            ; expect: sp == -1\n\
+            ; expect: zf == 1\n\
+            ; expect: cf == 0\n\
+            ; expect: fault == 0x30\n\
+            ; expect: [0x200] == 1, -1, 3\n\
+            ; expect: [0x10] == \"ab\"\n\
+            ; expect: code[0x100] == 0x14\n";
+        assert_eq!(
+            checks(source),
+            [
+                Check::Output(b"hi".to_vec()),
+                Check::Reg(3, 0x2a),
+                Check::Reg(9, 0xffffffff),
+                Check::Zf(true),
+                Check::Cf(false),
+                Check::Fault(0x30),
+                Check::Mem(0x200, vec![1, 0xff, 3]),
+                Check::Mem(0x10, b"ab".to_vec()),
+                Check::Code(0x100, vec![0x14]),
+            ]
+        );
+    }
+
+    #[test]
+    fn input_cycles_and_stack_are_read_and_other_comments_ignored() {
+        let exp = Expectations::from_source(
+            "; just a comment: with a colon\n\
+             \tNOP ; input: \"not at the start of a comment\"\n\
+             ; input: \"ab\"\n\
+             ; input: \"c\"\n\
+             ; cycles: 500\n\
+             ; stack: 0x40\n",
+        )
+        .unwrap();
+        assert_eq!(exp.input, b"abc");
+        assert_eq!(exp.cycles, 500);
+        assert_eq!(exp.stack, Some(0x40));
+        assert!(exp.checks.is_empty());
+    }
+
+    #[test]
+    fn bad_directives_say_which_line() {
+        assert!(error("\n; expect: r16 == 1").starts_with("line 2: unknown expectation"));
+        assert!(error("; expect: r0 = 1").contains("invalid syntax"));
+        assert!(error("; expect: pc == 1").contains("unknown expectation"));
+        assert!(error("; expect: output == 1").contains("unknown expectation"));
+        assert!(error("; expect: r0 == 0x100000000").contains("unknown expectation"));
+        assert!(error("; expect: [0] == 256").contains("unknown expectation"));
+        assert!(error("; expect: fault == 0x100").contains("unknown expectation"));
+        assert!(error("; cycles: \"many\"").contains("expected a string or a number"));
+        assert!(error("; input: 5").contains("expected a string or a number"));
+        assert!(error("; stack: 0x100000000").contains("bigger than the address space"));
+        assert!(error("; expect: \"open").contains("invalid syntax"));
+    }
+
+    #[test]
+    fn sidecars_skip_blanks_and_comments_and_reject_anything_else() {
+        let mut exp = Expectations::default();
+        exp.sidecar("; a comment\n\nexpect: r0 == 1\ncycles: 10\n")
+            .unwrap();
+        assert_eq!(exp.checks, [(3, Check::Reg(0, 1))]);
+        assert_eq!(exp.cycles, 10);
+        let e = exp.sidecar("expect: r0 == 1\nr1 == 2\n").unwrap_err();
+        assert!(e.starts_with("line 2: expected `expect:`"), "{}", e);
+        let e = exp.sidecar("expect: zf == yes\n").unwrap_err();
+        assert!(e.contains("unknown expectation"), "{}", e);
+    }
+
+    fn assemble(code: &str) -> Vec<u8> {
+        Ver0::new().assemble_str("test.kasm", code).unwrap()
+    }
+
+    #[test]
+    fn a_program_gets_exactly_its_cycle_budget() {
+        let code = assemble("\t.org 0x100\n\tNOP\n\tNOP\n\tHALT_AND_EXIT\n");
+        let mut cpu = KMM0::new().clock_speed_hz(0.).load_code(code.clone());
+        cpu.run();
+        let needed = cpu.cycles();
+        let exp = |cycles| Expectations {
+            cycles,
+            ..Expectations::default()
+        };
+        assert_eq!(
+            run(code.clone(), &exp(needed), None, None),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            run(code, &exp(needed - 1), None, None),
+            [format!("still running after {} cycles", needed - 1)]
+        );
+    }
+
+    #[test]
+    fn failures_name_the_line_and_what_was_there() {
+        let code = assemble("\t.org 0x100\n\tCONST 7 -> r0\n\tPRINT_CHAR 'x'\n\tHALT_AND_EXIT\n");
+        let exp = Expectations::from_source(
+            "; expect: r0 == 7\n\
+             ; expect: r1 == 1\n\
+             ; expect: zf == 1\n\
+             ; expect: [0] == 1, 2\n\
+             ; expect: code[0x100] == 0xff\n\
+             ; expect: fault == 0x30\n",
+        )
+        .unwrap();
+        let failures = run(code, &exp, Some(b"y".to_vec()), None);
+        assert_eq!(failures.len(), 6, "{:#?}", failures);
+        assert!(failures[0].starts_with("line 2: r1 is 0x0"));
+        assert!(failures[1].starts_with("line 3: zf is 0"));
+        assert_eq!(failures[2], "line 4: [0x0] is [00, 00], expected [01, 02]");
+        assert!(failures[3].starts_with("line 5: code[0x100] is"));
+        assert!(failures[4].contains("expected fault 0x30"));
+        assert_eq!(failures[5], "printed \"x\", expected \"y\"");
+    }
+
+    #[test]
+    fn the_screen_is_compared_without_its_blank_rows() {
+        let code = assemble(
+            "\t.org 0x100\n\
+             \tCONST 0x0741 -> r0\n\
+             \tWRITE_16 r0 -> [0x200a2]\n\
+             \tHALT_AND_EXIT\n",
+        );
+        let exp = Expectations::default();
+        let screen = |s: &str| Some(s.to_string());
+        assert!(run(code.clone(), &exp, None, screen("\n A\n")).is_empty());
+        assert!(run(code.clone(), &exp, None, screen("\n A")).is_empty());
+        assert_eq!(
+            run(code, &exp, None, screen("A\n")),
+            ["the screen shows \"\\n A\", expected \"A\""]
+        );
+    }
+}
@@ -0,0 +1,711 @@
+use std::fmt;
+
+// First bytes of the instruction groups. Every instruction in a group is
+// followed by a variant byte.
+pub const JMP: u8 = 0x02;
+pub const JMP_C: u8 = 0x03;
+pub const MATH: u8 = 0x04;
+pub const STACK: u8 = 0x05;
+pub const IO: u8 = 0x06;
+pub const MEMORY: u8 = 0x07;
+
+/// What an operand is, which decides how many bytes it takes and how it's
+/// written in assembly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Reg,
+    Zp,
+    U8,
+    U16,
+    U24,
+    U32,
+    Char,    // a u8 that's shown as a character literal when it can be
+    CPtr,    // [const_32]
+    RPtr,    // [reg]
+    Fwd8,    // +const_8
+    Back8,   // -const_8
+    Fwd16,   // +const_16
+    Back16,  // -const_16
+    Fwd32,   // +const_32
+    Back32,  // -const_32
+    Regs44,  // two registers packed as dst << 4 | src
+    Regs4x2, // two registers packed as second << 4 | first
+    RegPair, // two registers, a byte each
+}
+impl Kind {
+    /// Encoded length in bytes.
+    pub fn width(self) -> usize {
+        match self {
+            Kind::U16 | Kind::Fwd16 | Kind::Back16 | Kind::RegPair => 2,
+            Kind::U24 => 3,
+            Kind::U32 | Kind::CPtr | Kind::Fwd32 | Kind::Back32 => 4,
+            _ => 1,
+        }
+    }
+    /// How the operand is spelled in the reference.
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Reg => "reg",
+            Kind::Zp => "zpr",
+            Kind::U8 | Kind::Char => "const_8",
+            Kind::U16 => "const_16",
+            Kind::U24 => "const_24",
+            Kind::U32 => "const_32",
+            Kind::CPtr => "[const_32]",
+            Kind::RPtr => "[reg]",
+            Kind::Fwd8 => "+const_8",
+            Kind::Back8 => "-const_8",
+            Kind::Fwd16 => "+const_16",
+            Kind::Back16 => "-const_16",
+            Kind::Fwd32 => "+const_32",
+            Kind::Back32 => "-const_32",
+            Kind::Regs44 => "4br -> 4br",
+            Kind::Regs4x2 => "4br, 4br",
+            Kind::RegPair => "reg, reg",
+        }
+    }
+    fn write(self, n: u32, out: &mut Vec<u8>) {
+        out.extend(&n.to_le_bytes()[..self.width()]);
+    }
+    /// The operand in assembly syntax.
+    pub fn show(self, n: u32) -> String {
+        match self {
+            Kind::Reg => reg(n as u8),
+            Kind::Zp => format!("zp[{}]", n),
+            Kind::Char if ((n as u8).is_ascii_graphic() && n as u8 != b'\'') || n as u8 == b' ' => {
+                format!("'{}'", n as u8 as char)
+            }
+            Kind::U8 | Kind::U16 | Kind::U24 | Kind::U32 | Kind::Char => format!("{:#x}", n),
+            Kind::CPtr => format!("[{:#x}]", n),
+            Kind::RPtr => format!("[{}]", reg(n as u8)),
+            Kind::Fwd8 | Kind::Fwd16 | Kind::Fwd32 => format!("+{:#x}", n),
+            Kind::Back8 | Kind::Back16 | Kind::Back32 => format!("-{:#x}", n),
+            Kind::Regs44 => format!("{} -> {}", reg(n as u8 & 0xf), reg(n as u8 >> 4)),
+            Kind::Regs4x2 => format!("{}, {}", reg(n as u8 & 0xf), reg(n as u8 >> 4)),
+            Kind::RegPair => format!("{}, {}", reg(n as u8), reg((n >> 8) as u8)),
+        }
+    }
+}
+
+/// Register name as the assembler spells it.
+pub fn reg(r: u8) -> String {
+    match r {
+        0x8 => "ip".to_string(),
+        0x9 => "sp".to_string(),
+        0xa => "rp".to_string(),
+        0xb => "tr".to_string(),
+        0xc => "ix".to_string(),
+        r => format!("r{}", r),
+    }
+}
+
+/// Reads little endian operands, giving `None` once the code runs out.
+struct Reader<'a> {
+    code: &'a [u8],
+    pos: usize,
+}
+impl Reader<'_> {
+    fn read(&mut self, kind: Kind) -> Option<u32> {
+        let bytes = self.code.get(self.pos..self.pos + kind.width())?;
+        self.pos += kind.width();
+        let mut n = [0; 4];
+        n[..bytes.len()].copy_from_slice(bytes);
+        Some(u32::from_le_bytes(n))
+    }
+}
+
+/// One line of the instruction table.
+pub struct Encoding {
+    pub group: &'static str,
+    pub mnemonic: &'static str,
+    pub opcode: &'static [u8],
+    pub srcs: &'static [Kind],
+    pub dst: Option<Kind>,
+}
+impl Encoding {
+    /// Encoded length in bytes.
+    pub fn size(&self) -> usize {
+        self.opcode.len()
+            + self.srcs.iter().map(|k| k.width()).sum::<usize>()
+            + self.dst.map_or(0, |k| k.width())
+    }
+}
+
+macro_rules! isa_type {
+    (Reg) => {
+        u8
+    };
+    (Zp) => {
+        u8
+    };
+    (U8) => {
+        u8
+    };
+    (U16) => {
+        u16
+    };
+    (U24) => {
+        u32
+    };
+    (U32) => {
+        u32
+    };
+    (Char) => {
+        u8
+    };
+    (CPtr) => {
+        u32
+    };
+    (RPtr) => {
+        u8
+    };
+    (Fwd8) => {
+        u8
+    };
+    (Back8) => {
+        u8
+    };
+    (Fwd16) => {
+        u16
+    };
+    (Back16) => {
+        u16
+    };
+    (Fwd32) => {
+        u32
+    };
+    (Back32) => {
+        u32
+    };
+    (Regs44) => {
+        u8
+    };
+    (Regs4x2) => {
+        u8
+    };
+    (RegPair) => {
+        u16
+    };
+}
+
+macro_rules! isa_dst {
+    () => {
+        None
+    };
+    ($k:ident) => {
+        Some(Kind::$k)
+    };
+}
+
+/// Builds everything that depends on the instruction encodings from one
+/// table: the `Instruction` enum, its encoder, decoder and assembly syntax,
+/// the `opcode!`/`variant!` macros the emulator matches on and the `TABLE`
+/// the reference is printed from.
+///
+/// Each row is `Variant(operands) = "MNEMONIC" [opcode bytes];`, with
+/// operands in encoding order and the destination, if any, after `->`.
+macro_rules! isa {
+    ($(
+        $group:literal {
+            $(
+                $name:ident $( ( $($f:ident : $k:ident),* $(-> $df:ident : $dk:ident)? ) )?
+                    = $mn:literal [$op:tt $(, $var:tt)?];
+            )*
+        }
+    )*) => {
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Instruction {
+            Data(Vec<u8>), // raw bytes, not an instruction at all
+            $($(
+                $name $( ( $(isa_type!($k),)* $(isa_type!($dk))? ) )?,
+            )*)*
+        }
+
+        impl Instruction {
+            /// Encoded length in bytes.
+            pub fn size(&self) -> u32 {
+                match self {
+                    Instruction::Data(d) => d.len() as u32,
+                    $($(
+                        Instruction::$name { .. } => {
+                            [$op $(, $var)?].len() as u32
+                                $( $( + Kind::$k.width() as u32 )* $( + Kind::$dk.width() as u32 )? )?
+                        }
+                    )*)*
+                }
+            }
+
+            pub fn encode(&self, out: &mut Vec<u8>) {
+                match self {
+                    Instruction::Data(d) => out.extend(d),
+                    $($(
+                        Instruction::$name $( ( $($f,)* $($df)? ) )? => {
+                            out.extend([$op $(, $var)?]);
+                            $(
+                                $( Kind::$k.write(*$f as u32, out); )*
+                                $( Kind::$dk.write(*$df as u32, out); )?
+                            )?
+                        }
+                    )*)*
+                }
+            }
+
+            /// Decodes the instruction at the start of `code`, returning it
+            /// along with its length. `None` if the bytes aren't a known
+            /// instruction or it's cut short.
+            pub fn decode(code: &[u8]) -> Option<(Instruction, usize)> {
+                $($(
+                    let opcode = [$op $(, $var)?];
+                    if code.starts_with(&opcode) {
+                        #[allow(unused_mut)]
+                        let mut r = Reader { code, pos: opcode.len() };
+                        let i = Instruction::$name $( (
+                            $( r.read(Kind::$k)? as isa_type!($k), )*
+                            $( r.read(Kind::$dk)? as isa_type!($dk) )?
+                        ) )?;
+                        return Some((i, r.pos));
+                    }
+                )*)*
+                None
+            }
+        }
+
+        /// Prints an instruction in the syntax the assembler reads, e.g.
+        /// `ADD r0, zp[2] -> r1`. `Data` is printed as a `.byte` line.
+        impl fmt::Display for Instruction {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let (mnemonic, srcs, dst): (&str, Vec<String>, Option<String>) = match self {
+                    Instruction::Data(d) => {
+                        let bytes: Vec<String> = d.iter().map(|b| format!("{:#04x}", b)).collect();
+                        (".byte", vec![bytes.join(", ")], None)
+                    }
+                    $($(
+                        Instruction::$name $( ( $($f,)* $($df)? ) )? => (
+                            $mn,
+                            vec![$( $( Kind::$k.show(*$f as u32) ),* )?],
+                            None $( $( .or(Some(Kind::$dk.show(*$df as u32))) )? )?,
+                        ),
+                    )*)*
+                };
+                write!(f, "{}", mnemonic)?;
+                if !srcs.is_empty() {
+                    write!(f, " {}", srcs.join(", "))?;
+                }
+                if let Some(dst) = dst {
+                    write!(f, " -> {}", dst)?;
+                }
+                Ok(())
+            }
+        }
+
+        /// First byte of an instruction, usable as a pattern.
+        macro_rules! opcode {
+            $($( ($name) => { $op }; )*)*
+        }
+        /// Variant byte of an instruction in a group, usable as a pattern.
+        macro_rules! variant {
+            $($( $( ($name) => { $var }; )? )*)*
+        }
+
+        pub const TABLE: &[Encoding] = &[
+            $($(
+                Encoding {
+                    group: $group,
+                    mnemonic: $mn,
+                    opcode: &[$op $(, $var)?],
+                    srcs: &[$( $(Kind::$k),* )?],
+                    dst: isa_dst!($( $($dk)? )?),
+                },
+            )*)*
+        ];
+    };
+}
+
+isa! {
+    "control" {
+        Nop = "NOP" [0x00];
+        SetZF = "SET_ZF" [0x20];
+        ClrZF = "CLR_ZF" [0x21];
+        SetCF = "SET_CF" [0x22];
+        ClrCF = "CLR_CF" [0x23];
+        IntEnable = "INT_ENABLE" [0x24];
+        IntDisable = "INT_DISABLE" [0x25];
+        ExplicitHaltAndExit = "HALT_AND_EXIT" [0xfe];
+    }
+    "interrupts, IRQ n (1-15, lowest first) jumps to 0x100 + 8n" {
+        Iret = "IRET" [0x26];
+        IntMask(r: Reg) = "INT_MASK" [0x27];
+        IntPending(-> d: Reg) = "INT_PENDING" [0x28];
+        FaultHandler(r: Reg) = "FAULT_HANDLER" [0x29];
+        FaultCode(-> d: Reg) = "FAULT_CODE" [0x2a];
+    }
+    "jumps, offsets count from the first operand byte (instruction start + 2)" {
+        JmpConst24(n: U24) = "JMP" [JMP, 0x00];
+        JmpConst32(n: U32) = "JMP" [JMP, 0x01];
+        JmpOffsetA8(n: Fwd8) = "JMP_OFFSET" [JMP, 0x02];
+        JmpOffsetS8(n: Back8) = "JMP_OFFSET" [JMP, 0x03];
+        JmpOffsetA16(n: Fwd16) = "JMP_OFFSET" [JMP, 0x04];
+        JmpOffsetS16(n: Back16) = "JMP_OFFSET" [JMP, 0x05];
+        JmpOffsetA32(n: Fwd32) = "JMP_OFFSET" [JMP, 0x06];
+        JmpOffsetS32(n: Back32) = "JMP_OFFSET" [JMP, 0x07];
+
+        JCConst24(n: U24) = "JC" [JMP_C, 0x00];
+        JCConst32(n: U32) = "JC" [JMP_C, 0x01];
+        JCOffsetA8(n: Fwd8) = "JC_OFFSET" [JMP_C, 0x02];
+        JCOffsetS8(n: Back8) = "JC_OFFSET" [JMP_C, 0x03];
+        JCOffsetA16(n: Fwd16) = "JC_OFFSET" [JMP_C, 0x04];
+        JCOffsetS16(n: Back16) = "JC_OFFSET" [JMP_C, 0x05];
+        JCOffsetA32(n: Fwd32) = "JC_OFFSET" [JMP_C, 0x06];
+        JCOffsetS32(n: Back32) = "JC_OFFSET" [JMP_C, 0x07];
+
+        JNCConst24(n: U24) = "JNC" [JMP_C, 0x08];
+        JNCConst32(n: U32) = "JNC" [JMP_C, 0x09];
+        JNCOffsetA8(n: Fwd8) = "JNC_OFFSET" [JMP_C, 0x0a];
+        JNCOffsetS8(n: Back8) = "JNC_OFFSET" [JMP_C, 0x0b];
+        JNCOffsetA16(n: Fwd16) = "JNC_OFFSET" [JMP_C, 0x0c];
+        JNCOffsetS16(n: Back16) = "JNC_OFFSET" [JMP_C, 0x0d];
+        JNCOffsetA32(n: Fwd32) = "JNC_OFFSET" [JMP_C, 0x0e];
+        JNCOffsetS32(n: Back32) = "JNC_OFFSET" [JMP_C, 0x0f];
+
+        JZConst24(n: U24) = "JZ" [JMP_C, 0x10];
+        JZConst32(n: U32) = "JZ" [JMP_C, 0x11];
+        JZOffsetA8(n: Fwd8) = "JZ_OFFSET" [JMP_C, 0x12];
+        JZOffsetS8(n: Back8) = "JZ_OFFSET" [JMP_C, 0x13];
+        JZOffsetA16(n: Fwd16) = "JZ_OFFSET" [JMP_C, 0x14];
+        JZOffsetS16(n: Back16) = "JZ_OFFSET" [JMP_C, 0x15];
+        JZOffsetA32(n: Fwd32) = "JZ_OFFSET" [JMP_C, 0x16];
+        JZOffsetS32(n: Back32) = "JZ_OFFSET" [JMP_C, 0x17];
+
+        JNZConst24(n: U24) = "JNZ" [JMP_C, 0x18];
+        JNZConst32(n: U32) = "JNZ" [JMP_C, 0x19];
+        JNZOffsetA8(n: Fwd8) = "JNZ_OFFSET" [JMP_C, 0x1a];
+        JNZOffsetS8(n: Back8) = "JNZ_OFFSET" [JMP_C, 0x1b];
+        JNZOffsetA16(n: Fwd16) = "JNZ_OFFSET" [JMP_C, 0x1c];
+        JNZOffsetS16(n: Back16) = "JNZ_OFFSET" [JMP_C, 0x1d];
+        JNZOffsetA32(n: Fwd32) = "JNZ_OFFSET" [JMP_C, 0x1e];
+        JNZOffsetS32(n: Back32) = "JNZ_OFFSET" [JMP_C, 0x1f];
+    }
+    "math" {
+        AddRRR(a: Reg, b: Reg -> d: Reg) = "ADD" [MATH, 0x00];
+        AddZRR(a: Zp, b: Reg -> d: Reg) = "ADD" [MATH, 0x01];
+        IncR1(r: Reg) = "INC" [MATH, 0x02];
+        AddRRZ(a: Reg, b: Reg -> d: Zp) = "ADD" [MATH, 0x03];
+        AddRZZ(a: Reg, b: Zp -> d: Zp) = "ADD" [MATH, 0x04];
+        AddZZZ(a: Zp, b: Zp -> d: Zp) = "ADD" [MATH, 0x05];
+
+        AddcRRR(a: Reg, b: Reg -> d: Reg) = "ADDC" [MATH, 0x06];
+        AddcZRR(a: Zp, b: Reg -> d: Reg) = "ADDC" [MATH, 0x07];
+        IncR4(r: Reg) = "INC4" [MATH, 0x08];
+        AddcRRZ(a: Reg, b: Reg -> d: Zp) = "ADDC" [MATH, 0x09];
+        AddcRZZ(a: Reg, b: Zp -> d: Zp) = "ADDC" [MATH, 0x0a];
+        AddcZZZ(a: Zp, b: Zp -> d: Zp) = "ADDC" [MATH, 0x0b];
+
+        SubRRR(a: Reg, b: Reg -> d: Reg) = "SUB" [MATH, 0x0c];
+        SubZRR(a: Zp, b: Reg -> d: Reg) = "SUB" [MATH, 0x0d];
+        SubRZR(a: Reg, b: Zp -> d: Reg) = "SUB" [MATH, 0x0e];
+        SubRRZ(a: Reg, b: Reg -> d: Zp) = "SUB" [MATH, 0x0f];
+        SubRZZ(a: Reg, b: Zp -> d: Zp) = "SUB" [MATH, 0x10];
+        SubZZZ(a: Zp, b: Zp -> d: Zp) = "SUB" [MATH, 0x11];
+
+        SubbRRR(a: Reg, b: Reg -> d: Reg) = "SUBB" [MATH, 0x12];
+        SubbZRR(a: Zp, b: Reg -> d: Reg) = "SUBB" [MATH, 0x13];
+        SubbRZR(a: Reg, b: Zp -> d: Reg) = "SUBB" [MATH, 0x14];
+        SubbRRZ(a: Reg, b: Reg -> d: Zp) = "SUBB" [MATH, 0x15];
+        SubbRZZ(a: Reg, b: Zp -> d: Zp) = "SUBB" [MATH, 0x16];
+        SubbZZZ(a: Zp, b: Zp -> d: Zp) = "SUBB" [MATH, 0x17];
+
+        MulRRR(a: Reg, b: Reg -> d: Reg) = "MUL" [MATH, 0x18];
+        MulZRR(a: Zp, b: Reg -> d: Reg) = "MUL" [MATH, 0x19];
+        MulRRZ(a: Reg, b: Reg -> d: Zp) = "MUL" [MATH, 0x1a];
+        MulRZZ(a: Reg, b: Zp -> d: Zp) = "MUL" [MATH, 0x1b];
+        MulZZZ(a: Zp, b: Zp -> d: Zp) = "MUL" [MATH, 0x1c];
+
+        DivRRR(a: Reg, b: Reg -> d: Reg) = "DIV" [MATH, 0x1d];
+        DivZRR(a: Zp, b: Reg -> d: Reg) = "DIV" [MATH, 0x1e];
+        DivRZR(a: Reg, b: Zp -> d: Reg) = "DIV" [MATH, 0x1f];
+        DivRRZ(a: Reg, b: Reg -> d: Zp) = "DIV" [MATH, 0x20];
+        DivRZZ(a: Reg, b: Zp -> d: Zp) = "DIV" [MATH, 0x21];
+        DivZZZ(a: Zp, b: Zp -> d: Zp) = "DIV" [MATH, 0x22];
+
+        AndRRR(a: Reg, b: Reg -> d: Reg) = "AND" [MATH, 0x23];
+        AndZRR(a: Zp, b: Reg -> d: Reg) = "AND" [MATH, 0x24];
+        AndRRZ(a: Reg, b: Reg -> d: Zp) = "AND" [MATH, 0x25];
+        AndRZZ(a: Reg, b: Zp -> d: Zp) = "AND" [MATH, 0x26];
+        AndZZZ(a: Zp, b: Zp -> d: Zp) = "AND" [MATH, 0x27];
+
+        OrRRR(a: Reg, b: Reg -> d: Reg) = "OR" [MATH, 0x28];
+        OrZRR(a: Zp, b: Reg -> d: Reg) = "OR" [MATH, 0x29];
+        OrRRZ(a: Reg, b: Reg -> d: Zp) = "OR" [MATH, 0x2a];
+        OrRZZ(a: Reg, b: Zp -> d: Zp) = "OR" [MATH, 0x2b];
+        OrZZZ(a: Zp, b: Zp -> d: Zp) = "OR" [MATH, 0x2c];
+
+        XorRRR(a: Reg, b: Reg -> d: Reg) = "XOR" [MATH, 0x2d];
+        XorZRR(a: Zp, b: Reg -> d: Reg) = "XOR" [MATH, 0x2e];
+        XorRRZ(a: Reg, b: Reg -> d: Zp) = "XOR" [MATH, 0x2f];
+        XorRZZ(a: Reg, b: Zp -> d: Zp) = "XOR" [MATH, 0x30];
+        XorZZZ(a: Zp, b: Zp -> d: Zp) = "XOR" [MATH, 0x31];
+
+        NotRR(a: Reg -> d: Reg) = "NOT" [MATH, 0x32];
+        NotZR(a: Zp -> d: Reg) = "NOT" [MATH, 0x33];
+        NotRZ(a: Reg -> d: Zp) = "NOT" [MATH, 0x34];
+        NotZZ(a: Zp -> d: Zp) = "NOT" [MATH, 0x35];
+
+        DecR1(r: Reg) = "DEC" [MATH, 0x36];
+        DecR4(r: Reg) = "DEC4" [MATH, 0x37];
+
+        RemRRR(a: Reg, b: Reg -> d: Reg) = "REM" [MATH, 0x38];
+        RemZRR(a: Zp, b: Reg -> d: Reg) = "REM" [MATH, 0x39];
+        RemRZR(a: Reg, b: Zp -> d: Reg) = "REM" [MATH, 0x3a];
+        RemRRZ(a: Reg, b: Reg -> d: Zp) = "REM" [MATH, 0x3b];
+        RemRZZ(a: Reg, b: Zp -> d: Zp) = "REM" [MATH, 0x3c];
+        RemZZZ(a: Zp, b: Zp -> d: Zp) = "REM" [MATH, 0x3d];
+
+        ShlRRR(a: Reg, b: Reg -> d: Reg) = "SHL" [MATH, 0x3e];
+        ShlZRR(a: Zp, b: Reg -> d: Reg) = "SHL" [MATH, 0x3f];
+        ShlRCR(a: Reg, b: U8 -> d: Reg) = "SHL" [MATH, 0x40];
+        ShlZCR(a: Zp, b: U8 -> d: Reg) = "SHL" [MATH, 0x41];
+        ShlRCZ(a: Reg, b: U8 -> d: Zp) = "SHL" [MATH, 0x42];
+        ShlZCZ(a: Zp, b: U8 -> d: Zp) = "SHL" [MATH, 0x43];
+
+        ShrRRR(a: Reg, b: Reg -> d: Reg) = "SHR" [MATH, 0x44];
+        ShrZRR(a: Zp, b: Reg -> d: Reg) = "SHR" [MATH, 0x45];
+        ShrRCR(a: Reg, b: U8 -> d: Reg) = "SHR" [MATH, 0x46];
+        ShrZCR(a: Zp, b: U8 -> d: Reg) = "SHR" [MATH, 0x47];
+        ShrRCZ(a: Reg, b: U8 -> d: Zp) = "SHR" [MATH, 0x48];
+        ShrZCZ(a: Zp, b: U8 -> d: Zp) = "SHR" [MATH, 0x49];
+    }
+    "stack, grows down from the top of data memory; overflow and underflow raise faults 0x50 and 0x51" {
+        Push8(r: Reg) = "PUSH_8" [STACK, 0x00];
+        Push16(r: Reg) = "PUSH_16" [STACK, 0x01];
+        Push32(r: Reg) = "PUSH_32" [STACK, 0x02];
+        Pop8(r: Reg) = "POP_8" [STACK, 0x03];
+        Pop16(r: Reg) = "POP_16" [STACK, 0x04];
+        Pop32(r: Reg) = "POP_32" [STACK, 0x05];
+        CallConst24(n: U24) = "CALL" [STACK, 0x06];
+        CallConst32(n: U32) = "CALL" [STACK, 0x07];
+        CallOffsetA8(n: Fwd8) = "CALL_OFFSET" [STACK, 0x08];
+        CallOffsetS8(n: Back8) = "CALL_OFFSET" [STACK, 0x09];
+        CallOffsetA16(n: Fwd16) = "CALL_OFFSET" [STACK, 0x0a];
+        CallOffsetS16(n: Back16) = "CALL_OFFSET" [STACK, 0x0b];
+        CallOffsetA32(n: Fwd32) = "CALL_OFFSET" [STACK, 0x0c];
+        CallOffsetS32(n: Back32) = "CALL_OFFSET" [STACK, 0x0d];
+        CallR(r: Reg) = "CALL" [STACK, 0x0e];
+        Ret = "RET" [STACK, 0x0f];
+    }
+    "io, READ_CHAR sets ZF and gives 0 when no key is waiting" {
+        PrintCharR(r: Reg) = "PRINT_CHAR" [IO, 0x00];
+        PrintCharC8(c: Char) = "PRINT_CHAR" [IO, 0x01];
+        ReadChar(-> d: Reg) = "READ_CHAR" [IO, 0x02];
+        KeyAvailable(-> d: Reg) = "KEY_AVAILABLE" [IO, 0x03];
+    }
+    "memory, little endian; unmapped addresses raise fault 0x40, writes to ROM 0x41" {
+        Read8CptrR(p: CPtr -> d: Reg) = "READ_8" [MEMORY, 0x00];
+        Read16CptrR(p: CPtr -> d: Reg) = "READ_16" [MEMORY, 0x01];
+        Read32CptrR(p: CPtr -> d: Reg) = "READ_32" [MEMORY, 0x02];
+        Read8RptrR(p: RPtr -> d: Reg) = "READ_8" [MEMORY, 0x03];
+        Read16RptrR(p: RPtr -> d: Reg) = "READ_16" [MEMORY, 0x04];
+        Read32RptrR(p: RPtr -> d: Reg) = "READ_32" [MEMORY, 0x05];
+        Write8RCptr(r: Reg -> p: CPtr) = "WRITE_8" [MEMORY, 0x06];
+        Write16RCptr(r: Reg -> p: CPtr) = "WRITE_16" [MEMORY, 0x07];
+        Write32RCptr(r: Reg -> p: CPtr) = "WRITE_32" [MEMORY, 0x08];
+        Write8RRptr(r: Reg -> p: RPtr) = "WRITE_8" [MEMORY, 0x09];
+        Write16RRptr(r: Reg -> p: RPtr) = "WRITE_16" [MEMORY, 0x0a];
+        Write32RRptr(r: Reg -> p: RPtr) = "WRITE_32" [MEMORY, 0x0b];
+        Write8C8Cptr(c: U8 -> p: CPtr) = "WRITE_8" [MEMORY, 0x0c];
+        Write8C8Rptr(c: U8 -> p: RPtr) = "WRITE_8" [MEMORY, 0x0d];
+        Write16C16Cptr(c: U16 -> p: CPtr) = "WRITE_16" [MEMORY, 0x0e];
+        Write16C16Rptr(c: U16 -> p: RPtr) = "WRITE_16" [MEMORY, 0x0f];
+        Write32C32Cptr(c: U32 -> p: CPtr) = "WRITE_32" [MEMORY, 0x10];
+        Write32C32Rptr(c: U32 -> p: RPtr) = "WRITE_32" [MEMORY, 0x11];
+    }
+    "data transfer, MOVR4R4 and MOV4R4R read both sources before writing either" {
+        MovRR(a: Reg -> d: Reg) = "MOV" [0x10];
+        MovR4R4(s: Regs4x2 -> d: RegPair) = "MOVR4R4" [0x11];
+        Mov4R4R(s: RegPair -> d: Regs4x2) = "MOV4R4R" [0x12];
+        Mov44(r: Regs44) = "MOV44" [0x13];
+        ConstRegU8(c: U8 -> d: Reg) = "CONST8" [0x14];
+        ConstRegU16(c: U16 -> d: Reg) = "CONST16" [0x15];
+        ConstRegU32(c: U32 -> d: Reg) = "CONST32" [0x16];
+    }
+    "compact, fixed registers" {
+        MovR1R0 = "MOV_R1_R0" [0x2c];
+        MovR0R1 = "MOV_R0_R1" [0x2d];
+        MovR2R0 = "MOV_R2_R0" [0x2e];
+        MovR2R1 = "MOV_R2_R1" [0x2f];
+        AddR0R1R2 = "ADD_R0_R1_R2" [0x30];
+        AddcR0R1R2 = "ADDC_R0_R1_R2" [0x31];
+        SubR0R1R2 = "SUB_R0_R1_R2" [0x32];
+        SubbR0R1R2 = "SUBB_R0_R1_R2" [0x33];
+        MulR0R1R2 = "MUL_R0_R1_R2" [0x34];
+        DivR0R1R2 = "DIV_R0_R1_R2" [0x35];
+        AndR0R1R2 = "AND_R0_R1_R2" [0x36];
+        OrR0R1R2 = "OR_R0_R1_R2" [0x37];
+        XorR0R1R2 = "XOR_R0_R1_R2" [0x38];
+        NotR0R2 = "NOT_R0_R2" [0x39];
+        RemR0R1R2 = "REM_R0_R1_R2" [0x3a];
+    }
+}
+
+// lets the emulator import the macros by path
+#[allow(clippy::single_component_path_imports)]
+pub(crate) use {opcode, variant};
+
+impl Instruction {
+    /// The instruction written as `mnemonic` with no operands, if there is one.
+    pub fn bare(mnemonic: &str) -> Option<Instruction> {
+        let e = TABLE
+            .iter()
+            .find(|e| e.mnemonic == mnemonic && e.srcs.is_empty() && e.dst.is_none())?;
+        Some(Instruction::decode(e.opcode)?.0)
+    }
+}
+
+/// The instruction set reference, as kept in `instruction_set.txt`.
+pub fn reference() -> String {
+    let mut out = String::new();
+    out += "KMM0 instruction set\n\n";
+    out += "Generated from the table in src/isa.rs by `cargo run -- --isa`.\n";
+    out += "Operands are stored little endian, in the order they're written.\n\n";
+    out += "operands:\n";
+    let kinds = [
+        (Kind::Reg, "register r0-r15, 1 byte"),
+        (
+            Kind::Zp,
+            "zero page slot, 1 byte, the u32 at 4*n in data memory",
+        ),
+        (Kind::U8, "1 byte constant"),
+        (Kind::U16, "2 byte constant"),
+        (Kind::U24, "3 byte constant"),
+        (Kind::U32, "4 byte constant"),
+        (Kind::CPtr, "4 byte address"),
+        (Kind::RPtr, "1 byte register holding an address"),
+        (Kind::Fwd8, "forward offset, 1, 2 or 4 bytes"),
+        (Kind::Back8, "backward offset, 1, 2 or 4 bytes"),
+        (Kind::Regs44, "1 byte, dst << 4 | src"),
+        (Kind::Regs4x2, "1 byte, second << 4 | first"),
+        (Kind::RegPair, "2 bytes, first then second"),
+    ];
+    for (kind, text) in kinds {
+        out += &format!("  {:<12}{}\n", kind.name(), text);
+    }
+    out += "\nflags, set by every math instruction:\n";
+    out += "  ZF  the result is zero\n";
+    out += "  CF  carry out of ADD/ADDC/INC, borrow out of SUB/SUBB/DEC, MUL overflowed,\n";
+    out += "      or the last bit shifted out by SHL/SHR. Cleared by DIV, REM, AND, OR,\n";
+    out += "      XOR and NOT. DIV and REM by zero raise fault 0x30 instead.\n";
+    let mut group = "";
+    for e in TABLE {
+        if e.group != group {
+            group = e.group;
+            out += &format!("\n\t{}\n", group);
+        }
+        let opcode: Vec<String> = e.opcode.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut syntax = e.mnemonic.to_string();
+        let srcs: Vec<&str> = e.srcs.iter().map(|k| k.name()).collect();
+        if !srcs.is_empty() {
+            syntax += &format!(" {}", srcs.join(", "));
+        }
+        if let Some(dst) = e.dst {
+            syntax += &format!(" -> {}", dst.name());
+        }
+        let size = match e.size() {
+            1 => "1 byte".to_string(),
+            n => format!("{} bytes", n),
+        };
+        out += &format!("{:<7}{:<34}{}\n", opcode.join(" "), syntax, size);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_encoding_round_trips_its_operands() {
+        for e in TABLE {
+            // operand bytes that differ from each other, so a swapped or
+            // dropped one shows
+            let mut bytes = e.opcode.to_vec();
+            bytes.extend((1..).take(e.size() - e.opcode.len()));
+            let (i, len) = Instruction::decode(&bytes).unwrap();
+            assert_eq!(len, e.size(), "{}", i);
+            assert_eq!(i.size() as usize, e.size(), "{}", i);
+            let mut again = vec![];
+            i.encode(&mut again);
+            assert_eq!(again, bytes, "{}", i);
+            assert!(i.to_string().starts_with(e.mnemonic), "{}", i);
+            // one byte short doesn't decode to this instruction
+            if let Some((short, _)) = Instruction::decode(&bytes[..bytes.len() - 1]) {
+                assert!(short.size() < i.size(), "{} decodes short as {}", i, short);
+            }
+        }
+    }
+
+    #[test]
+    fn opcodes_are_unique_and_never_the_start_of_another() {
+        for (n, a) in TABLE.iter().enumerate() {
+            for b in &TABLE[n + 1..] {
+                assert!(
+                    !a.opcode.starts_with(b.opcode) && !b.opcode.starts_with(a.opcode),
+                    "{} {:02x?} and {} {:02x?}",
+                    a.mnemonic,
+                    a.opcode,
+                    b.mnemonic,
+                    b.opcode
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn operands_show_in_assembler_syntax() {
+        let shown = [
+            Instruction::AddZRR(2, 0, 9),
+            Instruction::Mov44(0x21),
+            Instruction::MovR4R4(0x10, 0x0001),
+            Instruction::Mov4R4R(0x0203, 0x32),
+            Instruction::PrintCharC8(b'A'),
+            Instruction::PrintCharC8(b'\''),
+            Instruction::Read8RptrR(3, 0),
+            Instruction::Write16RCptr(1, 0x200),
+            Instruction::JmpOffsetS8(4),
+            Instruction::Data(vec![1, 0xff]),
+        ]
+        .map(|i| i.to_string());
+        assert_eq!(
+            shown,
+            [
+                "ADD zp[2], r0 -> sp",
+                "MOV44 r1 -> r2",
+                "MOVR4R4 r0, r1 -> r1, r0",
+                "MOV4R4R r3, r2 -> r2, r3",
+                "PRINT_CHAR 'A'",
+                "PRINT_CHAR 0x27",
+                "READ_8 [r3] -> r0",
+                "WRITE_16 r1 -> [0x200]",
+                "JMP_OFFSET -0x4",
+                ".byte 0x01, 0xff",
+            ]
+        );
+    }
+
+    #[test]
+    fn bare_finds_only_instructions_without_operands() {
+        assert_eq!(Instruction::bare("NOP"), Some(Instruction::Nop));
+        assert_eq!(Instruction::bare("RET"), Some(Instruction::Ret));
+        assert_eq!(Instruction::bare("ADD"), None);
+        assert_eq!(Instruction::bare("nop"), None);
+    }
+
+    #[test]
+    fn the_reference_lists_every_encoding() {
+        let text = reference();
+        assert!(text.contains("\n11     MOVR4R4 4br, 4br -> reg, reg      4 bytes\n"));
+        // every encoding line starts with its opcode, two hex digits then a space
+        let lines = text.lines().filter(|l| {
+            l.len() > 3 && l[..2].bytes().all(|b| b.is_ascii_hexdigit()) && &l[2..3] == " "
+        });
+        assert_eq!(lines.count(), TABLE.len());
+    }
+}
@@ -1,143 +1,314 @@
-#![feature(bigint_helper_methods)]
-#![feature(mixed_integer_ops)]
 
 mod emulator;
 mod assembler;
+mod debugger;
+mod harness;
+mod isa;
+
+use std::cell::RefCell;
+use std::rc::Rc;
 
 fn block_print(width: usize, nums: Vec<u8>) {
-	for i in 0..nums.len() {
+	for (i, n) in nums.iter().enumerate() {
 		if i % width == 0 {
 			println!();
 		}
-		print!("{:02x?} ", nums[i]);
+		print!("{:02x?} ", n);
 	}
 	println!();
 }
 
-macro_rules! skip256bytes {
-	() => {
-		vec![
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
-		0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,]
+/// Writes the screen to `file`, as a PPM image of the pixels if the name ends
+/// in `.ppm` and as text otherwise.
+fn dump_screen(display: &emulator::Display, file: &str) -> std::io::Result<()> {
+	if file.ends_with(".ppm") {
+		return std::fs::write(file, display.ppm().unwrap_or_default());
+	}
+	std::fs::write(file, display.text())
+}
+
+/// Stdout, or the `--console` file or `host:port` connection, with the keys
+/// from the `--keys` file if there is one, or else from the connection, or
+/// from stdin if `stdin` is set.
+fn console(keys: &Option<String>, output: &Option<String>, stdin: bool) -> Box<dyn emulator::Console> {
+	use emulator::console::{Stdio, Stream};
+	let keys = keys.as_ref().map(|file| match std::fs::read(file) {
+		Ok(bytes) => bytes,
+		Err(e) => {
+			eprintln!("error: couldn't read `{}`: {}", file, e);
+			std::process::exit(1);
+		}
+	});
+	let output = match output {
+		Some(output) => output,
+		None => {
+			return match keys {
+				Some(bytes) => Box::new(Stdio::script(bytes)),
+				None if stdin => Box::new(Stdio::with_input()),
+				None => Box::new(Stdio::new()),
+			}
+		}
 	};
+	if let Ok(addr) = output.parse::<std::net::SocketAddr>() {
+		let socket = match std::net::TcpStream::connect(addr) {
+			Ok(socket) => socket,
+			Err(e) => {
+				eprintln!("error: couldn't connect to {}: {}", addr, e);
+				std::process::exit(1);
+			}
+		};
+		let stream = match socket.try_clone() {
+			Ok(writer) => Stream::new(writer),
+			Err(e) => {
+				eprintln!("error: couldn't use the connection to {}: {}", addr, e);
+				std::process::exit(1);
+			}
+		};
+		return match keys {
+			Some(bytes) => Box::new(stream.input(std::io::Cursor::new(bytes))),
+			None => Box::new(stream.input(socket)),
+		};
+	}
+	let stream = match std::fs::File::create(output) {
+		Ok(file) => Stream::new(file),
+		Err(e) => {
+			eprintln!("error: couldn't write `{}`: {}", output, e);
+			std::process::exit(1);
+		}
+	};
+	match keys {
+		Some(bytes) => Box::new(stream.input(std::io::Cursor::new(bytes))),
+		None if stdin => Box::new(stream.input(std::io::stdin())),
+		None => Box::new(stream),
+	}
 }
 
-const R0: u8 = 0x0;
-const R1: u8 = 0x1;
-const R2: u8 = 0x2;
-const R3: u8 = 0x3;
-const R4: u8 = 0x4;
-const R5: u8 = 0x5;
-const R6: u8 = 0x6;
-const R7: u8 = 0x7;
-const IP: u8 = 0x8; // instruction pointer
-const SP: u8 = 0x9; // stack pointer
-const RP: u8 = 0xa; // interrupt return pointer
-const TR: u8 = 0xb; // temp register
-const IX: u8 = 0xc; // index register
+/// `bus` with the `--rom` file, if there is one, read only at `ROM_BASE`.
+fn with_rom(bus: emulator::Bus, rom: &Option<Vec<u8>>) -> emulator::Bus {
+	match rom {
+		Some(bytes) => bus.rom(ROM_BASE, bytes.clone()),
+		None => bus,
+	}
+}
 
-const NOP: u8 = 0x00;
-const JMP: u8 = 0x02;
-	const JMP_24: u8 = 0x00;
-	const JMP_32: u8 = 0x01;
-	const JMP_P_OFFSET_8: u8 = 0x02;
-	const JMP_S_OFFSET_8: u8 = 0x03;
-	const JMP_P_OFFSET_16: u8 = 0x04;
-	const JMP_S_OFFSET_16: u8 = 0x05;
-	const JMP_P_OFFSET_32: u8 = 0x06;
-	const JMP_S_OFFSET_32: u8 = 0x07;
-const JMPC: u8 = 0x03;
-	const  JC_24: u8 = 0x00;
-	const  JC_32: u8 = 0x01;
-	const  JC_P_OFFSET_8: u8 = 0x02;
-	const  JC_S_OFFSET_8: u8 = 0x03;
-	const  JC_P_OFFSET_16: u8 = 0x04;
-	const  JC_S_OFFSET_16: u8 = 0x05;
-	const  JC_P_OFFSET_32: u8 = 0x06;
-	const  JC_S_OFFSET_32: u8 = 0x07;
-	const  JNC_24: u8 = 0x08;
-	const  JNC_32: u8 = 0x09;
-	const  JNC_P_OFFSET_8: u8 = 0x0a;
-	const  JNC_S_OFFSET_8: u8 = 0x0b;
-	const  JNC_P_OFFSET_16: u8 = 0x0c;
-	const  JNC_S_OFFSET_16: u8 = 0x0d;
-	const  JNC_P_OFFSET_32: u8 = 0x0e;
-	const  JNC_S_OFFSET_32: u8 = 0x0f;
-	const  JZ_24: u8 = 0x10;
-	const  JZ_32: u8 = 0x11;
-	const  JZ_P_OFFSET_8: u8 = 0x12;
-	const  JZ_S_OFFSET_8: u8 = 0x13;
-	const  JZ_P_OFFSET_16: u8 = 0x14;
-	const  JZ_S_OFFSET_16: u8 = 0x15;
-	const  JZ_P_OFFSET_32: u8 = 0x16;
-	const  JZ_S_OFFSET_32: u8 = 0x17;
-	const  JNZ_24: u8 = 0x18;
-	const  JNZ_32: u8 = 0x19;
-	const  JNZ_P_OFFSET_8: u8 = 0x1a;
-	const  JNZ_S_OFFSET_8: u8 = 0x1b;
-	const  JNZ_P_OFFSET_16: u8 = 0x1c;
-	const  JNZ_S_OFFSET_16: u8 = 0x1d;
-	const  JNZ_P_OFFSET_32: u8 = 0x1e;
-	const  JNZ_S_OFFSET_32: u8 = 0x1f;
-const MATH: u8 = 0x04;
-	const ADD_RRR: u8 = 0x00;
-	const ADD_ZRR: u8 = 0x01;
-	const INC_1: u8 = 0x02;
-	const ADD_RRZ: u8 = 0x03;
-	const ADD_RZZ: u8 = 0x04;
-	const ADD_ZZZ: u8 = 0x05;
-	const ADDC_RRR: u8 = 0x06;
-	const ADDC_ZRR: u8 = 0x07;
-	const INC_4: u8 = 0x08;
-	const ADDC_RRZ: u8 = 0x09;
-	const ADDC_RZZ: u8 = 0x0a;
-	const ADDC_ZZZ: u8 = 0x0b;
-const CONST8: u8 = 0x14;
-const EXPLICIT_HALT_AND_EXIT: u8 = 0xFE;
-const DEBUG: u8 = 0xFF;
-	const PRINT_ACC: u8 = 0x00;
-	const PRINT_REG: u8 = 0x01;
-	const PRINT_ALL_REG: u8 = 0x02;
+/// Splits a `-D NAME=VALUE` define. The value is 1 if it's left out.
+fn parse_define(define: &str) -> Option<(&str, i64)> {
+	let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+	if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+		return None;
+	}
+	let (neg, value) = match value.strip_prefix('-') {
+		Some(v) => (true, v),
+		None => (false, value),
+	};
+	let value = value.replace('_', "");
+	let (digits, radix) = match value.get(..2) {
+		Some("0x") => (&value[2..], 16),
+		Some("0b") => (&value[2..], 2),
+		Some("0o") => (&value[2..], 8),
+		_ => (&value[..], 10),
+	};
+	let n = i64::from_str_radix(digits, radix).ok()?;
+	Some((name, if neg { -n } else { n }))
+}
+
+/// What the command line takes, printed when there's no file to work on.
+const USAGE: &str = "\
+usage: KMM-0 [options] file.kasm
+       KMM-0 test [dir]
+       KMM-0 --isa
 
-use assembler::Instruction as I;
-use assembler::{Assembler, Ver0};
+  -d                  print a disassembly instead of the bytes
+  -r                  run the program
+  -g                  run the program in the debugger
+  -I dir              search dir for included files
+  -D NAME[=VALUE]     define a constant, 1 if there's no value
+  --keys file         take keys from the file instead of stdin
+  --console target    send output to a file, or to and from host:port
+  --rom file          map the file read only at 0x30000
+  --screen            draw the display in the terminal as it runs
+  --screen-dump file  save the last frame, as a PPM if the name ends in .ppm
+";
+
+use assembler::{disassembler, Assembler, Ver0};
+use harness::{machine_bus, KEYBOARD_IRQ, ROM_BASE};
 
 fn main() {
-	let mut skip: Vec<u8> = skip256bytes!();
-	// let mut code: Vec<u8> = vec![
-	// 	CONST8, 0xFF, R0,             // CONST 0xFF => r0
-	// 	CONST8, 0x07, R1,             // CONST r1 = 0x01
-	// 	MATH, ADD_RRR, R0, R1, R0,          // ADD r0 = r0 + r1
-	// 	DEBUG, PRINT_REG, R0,		        // DEBUG_PRINT_REG r0
-	// 	DEBUG, PRINT_REG, R1,		        // DEBUG_PRINT_REG r1
-	// 	JMPC, JNC_S_OFFSET_8, 0x0d,		    // JNC-offset 9
-	// 	DEBUG, PRINT_REG, R0,		        // DEBUG_PRINT_ALL_REG
-	// 	EXPLICIT_HALT_AND_EXIT,			    // EXPLICIT HALT AND EXIT
-	// ];
-	let instruction_tokens: Vec<I> = vec![
-		I::ConstRegU8(R0, 0xFF),
-		I::ConstRegU8(R1, 0x07),
-		I::AddRRR(R0, R1, R0),
-		I::DebugPrintReg(R0),
-		I::DebugPrintReg(R1),
-		I::JNCOffsetS8(0x0d),
-		I::DebugPrintReg(R0),
-		I::ExplicitHaltAndExit,
-	];
-	let code = Ver0::assemble_to_bytes(instruction_tokens);
-	block_print(16, code);
-}
\ No newline at end of file
+	let mut ver0 = Ver0::new();
+	let mut path = None;
+	let mut listing = false;
+	let mut run = false;
+	let mut debug = false;
+	let mut test = false;
+	let mut keys = None;
+	let mut rom = None;
+	let mut console_path = None;
+	let mut screen = false;
+	let mut screen_dump = None;
+	let mut args = std::env::args().skip(1);
+	while let Some(arg) = args.next() {
+		if arg == "--isa" {
+			print!("{}", isa::reference());
+			return;
+		} else if arg == "-d" {
+			listing = true;
+		} else if arg == "test" && !test && path.is_none() {
+			test = true;
+		} else if arg == "-g" {
+			debug = true;
+		} else if arg == "-r" {
+			run = true;
+		} else if arg == "--keys" {
+			match args.next() {
+				Some(file) => keys = Some(file),
+				None => {
+					eprintln!("error: --keys needs a file");
+					std::process::exit(1);
+				}
+			}
+		} else if arg == "--console" {
+			match args.next() {
+				Some(output) => console_path = Some(output),
+				None => {
+					eprintln!("error: --console needs a file or host:port");
+					std::process::exit(1);
+				}
+			}
+		} else if arg == "--rom" {
+			let file = match args.next() {
+				Some(file) => file,
+				None => {
+					eprintln!("error: --rom needs a file");
+					std::process::exit(1);
+				}
+			};
+			match std::fs::read(&file) {
+				Ok(bytes) => rom = Some(bytes),
+				Err(e) => {
+					eprintln!("error: couldn't read `{}`: {}", file, e);
+					std::process::exit(1);
+				}
+			}
+		} else if arg == "--screen" {
+			screen = true;
+		} else if arg == "--screen-dump" {
+			match args.next() {
+				Some(file) => screen_dump = Some(file),
+				None => {
+					eprintln!("error: --screen-dump needs a file");
+					std::process::exit(1);
+				}
+			}
+		} else if arg == "-I" {
+			match args.next() {
+				Some(dir) => ver0 = ver0.include_path(&dir),
+				None => {
+					eprintln!("error: -I needs a directory");
+					std::process::exit(1);
+				}
+			}
+		} else if let Some(dir) = arg.strip_prefix("-I") {
+			ver0 = ver0.include_path(dir);
+		} else if let Some(define) = arg.strip_prefix("-D") {
+			let define = match define {
+				"" => args.next().unwrap_or_default(),
+				_ => define.to_string(),
+			};
+			match parse_define(&define) {
+				Some((name, value)) => ver0 = ver0.define(name, value),
+				None => {
+					eprintln!("error: -D needs NAME or NAME=VALUE, found `{}`", define);
+					std::process::exit(1);
+				}
+			}
+		} else {
+			path = Some(arg);
+		}
+	}
+	if test {
+		let dir = path.unwrap_or_else(|| ".".to_string());
+		let outcomes = match harness::run_dir(&ver0, std::path::Path::new(&dir)) {
+			Ok(outcomes) => outcomes,
+			Err(e) => {
+				eprintln!("error: couldn't read `{}`: {}", dir, e);
+				std::process::exit(1);
+			}
+		};
+		let failed = outcomes.iter().filter(|o| !o.passed()).count();
+		for outcome in &outcomes {
+			println!("{}", outcome);
+		}
+		println!("{} passed, {} failed", outcomes.len() - failed, failed);
+		if failed > 0 {
+			std::process::exit(1);
+		}
+		return;
+	}
+	if let (Some(path), true) = (&path, debug) {
+		let (code, labels) = match ver0.assemble_file_with_labels(path) {
+			Ok(assembled) => assembled,
+			Err(e) => {
+				eprintln!("{}", e);
+				std::process::exit(1);
+			}
+		};
+		// stdin is the debugger's, so the program only gets scripted keys
+		let console = console(&keys, &console_path, false);
+		let display = Rc::new(RefCell::new(emulator::Display::new().pixels(160, 100)));
+		let cpu = emulator::KMM0::new()
+			.bus(with_rom(machine_bus(&display), &rom))
+			.console(console)
+			.keyboard_irq(KEYBOARD_IRQ)
+			.clock_speed_hz(0.)
+			.load_code(code.clone());
+		let mut debugger = debugger::Debugger::new(cpu, code, labels);
+		if let Err(e) = debugger.repl(std::io::stdin().lock(), std::io::stdout()) {
+			eprintln!("error: {}", e);
+			std::process::exit(1);
+		}
+		return;
+	}
+	if let Some(path) = path {
+		match ver0.assemble_file(&path) {
+			Ok(code) if listing => print!("{}", disassembler::disassemble(&code, 0)),
+			Ok(code) if run => {
+				let display = match screen {
+					true => emulator::Display::new().pixels(160, 100).live(),
+					false => emulator::Display::new().pixels(160, 100),
+				};
+				let display = Rc::new(RefCell::new(display));
+				let console = console(&keys, &console_path, true);
+				let mut cpu = emulator::KMM0::new()
+					.bus(with_rom(machine_bus(&display), &rom))
+					.console(console)
+					.keyboard_irq(KEYBOARD_IRQ)
+					.clock_speed_hz(0.)
+					.load_code(code);
+				let halt = cpu.run();
+				drop(cpu); // puts the terminal back
+				if screen {
+					display.borrow_mut().render();
+				}
+				if let Some(file) = &screen_dump {
+					if let Err(e) = dump_screen(&display.borrow(), file) {
+						eprintln!("error: couldn't write `{}`: {}", file, e);
+						std::process::exit(1);
+					}
+				}
+				if halt.fault != emulator::Fault::ExplicitHalt {
+					eprintln!("error: {}", halt);
+					std::process::exit(1);
+				}
+			}
+			Ok(code) => block_print(16, code),
+			Err(e) => {
+				eprintln!("{}", e);
+				std::process::exit(1);
+			}
+		}
+		return;
+	}
+
+	eprint!("{}", USAGE);
+	std::process::exit(1);
+}
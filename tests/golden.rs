@@ -0,0 +1,14 @@
+use std::process::Command;
+
+/// Runs every program in `tests/kasm` through `KMM-0 test`, which checks each
+/// against the expectations written in it.
+#[test]
+fn golden_programs() {
+    let out = Command::new(env!("CARGO_BIN_EXE_KMM-0"))
+        .args(["test", "tests/kasm"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    let report = String::from_utf8_lossy(&out.stdout);
+    assert!(out.status.success(), "{}", report);
+}